@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate xolotl;
+
+fuzz_target!(|data: &[u8]| {
+    xolotl::fuzz::router_process(data);
+});
@@ -0,0 +1,80 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Xolotl command line tool
+//!
+//! `xolotl check-ratchet <key-file> <export-file> [--repair <output-file>]`
+//! checks ratchet state exported by `export_state` or `export_client`
+//! under the 32 byte key found in `key-file`, and writes an export
+//! of the repaired state to `output-file` if asked.  We exit with
+//! status 1 if we found problems, and 2 upon errors.
+
+extern crate xolotl;
+
+use std::env;
+use std::fs::File;
+use std::io::{self,Read,Write};
+use std::process;
+
+use xolotl::{ExportKey,check_export};
+
+
+const USAGE: &'static str =
+    "Usage: xolotl check-ratchet <key-file> <export-file> [--repair <output-file>]";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut v = Vec::new();
+    File::open(path)?.read_to_end(&mut v) ?;
+    Ok(v)
+}
+
+/// Returns whether the ratchet state was consistent.
+fn check_ratchet(args: &[String]) -> Result<bool,String> {
+    let (key_path,export_path,output) = match args.len() {
+        2 => (&args[0], &args[1], None),
+        4 if args[2] == "--repair" => (&args[0], &args[1], Some(&args[3])),
+        _ => usage(),
+    };
+
+    let k = read_file(key_path).map_err( |e| format!("{}: {}", key_path, e) ) ?;
+    if k.len() != 32 {
+        return Err( format!("{}: Expected a 32 byte key.", key_path) );
+    }
+    let mut key = ExportKey([0u8; 32]);
+    key.0.copy_from_slice(&k);
+
+    let export = read_file(export_path).map_err( |e| format!("{}: {}", export_path, e) ) ?;
+    let (report,repaired) = check_export(&key, &export, output.is_some())
+      .map_err( |e| format!("{}: {}", export_path, e) ) ?;
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    println!("Found {} problems.", report.problems.len());
+
+    if let (Some(path),Some(repaired)) = (output,repaired) {
+        File::create(path).and_then( |mut f| f.write_all(&repaired) )
+          .map_err( |e| format!("{}: {}", path, e) ) ?;
+        println!("Pruned {} entries and restored {} parent links.", report.pruned, report.restored);
+    }
+    Ok( report.problems.is_empty() )
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let r = match args.first().map( |s| s.as_str() ) {
+        Some("check-ratchet") => check_ratchet(&args[1..]),
+        _ => usage(),
+    };
+    match r {
+        Ok(true) => { },
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        },
+    }
+}
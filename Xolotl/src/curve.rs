@@ -11,6 +11,7 @@ use rand::{Rng, Rand};
 use curve25519_dalek::scalar;
 use curve25519_dalek::edwards;
 use curve25519_dalek::constants;
+use curve25519_dalek::field::FieldElement;
 // ::{CompressedEdwardsY,ExtendedPoint,ScalarMult,BasepointMult};
 
 use sphinx::SphinxSecret;
@@ -165,6 +166,61 @@ impl Point {
                 .compress_edwards().to_bytes()
         )
     }
+
+    /// Perform many key exchanges, as in `Point::key_exchange`, but
+    /// compress all resulting points with a single field inversion.
+    ///
+    /// Results are bit-identical to calling `key_exchange` on each
+    /// pair because compression is canonical.
+    ///
+    /// We share only the inversion.  Each key exchange multiplies a
+    /// fresh point, so no table precomputed from a routing key could
+    /// help, and decompression takes an inverse square root of its
+    /// own, which we cannot share between points.
+    pub fn key_exchange_batch(pairs: &[(&Point,&Scalar)]) -> Vec<SphinxSecret> {
+        let points: Vec<edwards::ExtendedPoint> = pairs.iter()
+            .map( |&(p,s)| &p.0 * &s.0 ).collect();
+        compress_batch(&points).into_iter().map(SphinxSecret).collect()
+    }
+
+    /// Blind many points, as in `Point::blind`, and compress them all
+    /// with a single field inversion.
+    pub fn blind_batch(pairs: &[(&Point,&Scalar)]) -> Vec<AlphaBytes> {
+        let points: Vec<edwards::ExtendedPoint> = pairs.iter()
+            .map( |&(p,s)| &p.0 * &s.0 ).collect();
+        compress_batch(&points)
+    }
+}
+
+/// Compress many points into compressed Edwards Y form using
+/// Montgomery's trick, so that we invert only one field element.
+///
+/// Extended coordinates never have `Z = 0`, so our product of all
+/// `Z` coordinates never vanishes.
+fn compress_batch(points: &[edwards::ExtendedPoint]) -> Vec<[u8; 32]> {
+    if points.len() == 0 { return Vec::new(); }
+
+    // Accumulate products of all prior `Z` coordinates.
+    let mut prefix = Vec::with_capacity(points.len());
+    let mut acc = FieldElement::one();
+    for p in points.iter() {
+        prefix.push(acc);
+        acc = &acc * &p.Z;
+    }
+
+    // Invert once and peel off each `1/Z` in reverse.
+    let mut inv = acc.invert();
+    let mut r = vec![[0u8; 32]; points.len()];
+    for i in (0..points.len()).rev() {
+        let recip = &inv * &prefix[i];
+        inv = &inv * &points[i].Z;
+        let x = &points[i].X * &recip;
+        let y = &points[i].Y * &recip;
+        let mut s = y.to_bytes();
+        s[31] ^= x.is_negative_ed25519() << 7;
+        r[i] = s;
+    }
+    r
 }
 
 impl fmt::Debug for Point {
@@ -186,6 +242,7 @@ mod tests {
     use crypto::curve25519 as rc_curve25519;
 
     use rand::{OsRng, Rng, Rand};
+    use test::Bencher;
     use super::*;
     // use hex::ToHex;
 
@@ -305,11 +362,49 @@ rc_curve25519::double_scalarmult_vartime
 */
 
 
-/*
     #[test]
-    fn need_test_() {
+    fn batch_matches_single() {
+        let mut r = os_rng();
+        let secrets: Vec<Scalar> = (0..3).map(|_| Scalar::rand(&mut r)).collect();
+        let points: Vec<Point> = (0..16).map( |_| {
+            let alpha = Point::from_secret(&Scalar::rand(&mut r)).compress();
+            Point::decompress(&alpha).unwrap()
+        } ).collect();
+        let pairs: Vec<(&Point,&Scalar)> = points.iter().enumerate()
+            .map( |(i,p)| (p, &secrets[i % secrets.len()]) ).collect();
+
+        let batch = Point::key_exchange_batch(&pairs);
+        for (&(p,s),ss) in pairs.iter().zip(batch.iter()) {
+            assert_eq!(p.key_exchange(s).0, ss.0);
+        }
+        let batch = Point::blind_batch(&pairs);
+        for (&(p,s),a) in pairs.iter().zip(batch.iter()) {
+            assert_eq!(p.blind(s).compress(), *a);
+        }
+        assert!( Point::key_exchange_batch(&[]).is_empty() );
+    }
+
+    fn bench_pairs(n: usize) -> (Vec<Point>,Scalar) {
+        let mut r = os_rng();
+        let points = (0..n).map( |_| {
+            let alpha = Point::from_secret(&Scalar::rand(&mut r)).compress();
+            Point::decompress(&alpha).unwrap()
+        } ).collect();
+        (points, Scalar::rand(&mut r))
+    }
+
+    #[bench]
+    fn bench_key_exchange_single(b: &mut Bencher) {
+        let (points,s) = bench_pairs(64);
+        b.iter( || points.iter().map(|p| p.key_exchange(&s).0[0]).fold(0u8, |x,y| x^y) );
+    }
+
+    #[bench]
+    fn bench_key_exchange_batch(b: &mut Bencher) {
+        let (points,s) = bench_pairs(64);
+        let pairs: Vec<(&Point,&Scalar)> = points.iter().map(|p| (p,&s)).collect();
+        b.iter( || Point::key_exchange_batch(&pairs).iter().map(|ss| ss.0[0]).fold(0u8, |x,y| x^y) );
     }
-*/
 }
 
 
@@ -10,13 +10,42 @@ use ed25519_dalek as ed25519;
 use sha2::Sha512 as Ed25519Hash;
 
 use curve;
-// use super::error::*;
+use super::error::*;
 // use super::super::*;
 
 use super::RoutingName;
+use super::kem::{KemPublic,MAX_KEM_PUBLIC_LENGTH};
 use super::time::*;
 
 
+/// Version of our certificate signing format, included as the first
+/// byte of every message we sign with an issuer key.
+pub const SIGNING_VERSION: u8 = 1;
+
+pub const SIGNING_CONTEXT_LENGTH: usize = 16;
+
+/// Domain separation prefix for ed25519 signatures by issuer keys.
+///
+/// We prefix every signed message with a version byte followed by a
+/// label identifying the certificate type, so that no signature on
+/// one certificate type can be reinterpreted as a signature on
+/// another type, or on anything else signed by the same key.
+pub type SigningContext = [u8; SIGNING_CONTEXT_LENGTH];
+
+const ROUTING_LABEL: &'static [u8; SIGNING_CONTEXT_LENGTH-1] = b"Xolotl routing\0";
+const ISSUER_LABEL:  &'static [u8; SIGNING_CONTEXT_LENGTH-1] = b"Xolotl issuer\0\0";
+
+fn signing_context(label: &[u8; SIGNING_CONTEXT_LENGTH-1]) -> SigningContext {
+    let mut c = [0u8; SIGNING_CONTEXT_LENGTH];
+    {
+        let (v,l) = mut_array_refs![&mut c,1,SIGNING_CONTEXT_LENGTH-1];
+        v[0] = SIGNING_VERSION;
+        *l = *label;
+    }
+    c
+}
+
+
 /// Routing public key certificate
 #[derive(Clone, Debug)]  // Copy
 pub struct RoutingPublic {
@@ -26,43 +55,94 @@ pub struct RoutingPublic {
     pub validity: ValidityPeriod,
     /// Issuing mix nodes long term issuing key
     pub issuer: IssuerPublicKey,
+    /// Optional KEM public key for hybrid sub-hops.
+    pub kem: Option<KemPublic>,
     /// Signature over preceeding fields by issuer.
     pub signature: ed25519::Signature,
 }
 
+/// Length of an encoded `RoutingPublic` without any `KemPublic`,
+/// which we append when present.
 pub const ROUTING_PUBLIC_LENGTH: usize = 32+16+32+64;
 
+/// Length of the message signed by the issuer in a `RoutingPublic`,
+/// consisting of our `SigningContext` and all fields but the signature,
+/// with the KEM public key replaced by its digest, or zeros if absent.
+pub const ROUTING_SIGNABLE_LENGTH: usize = SIGNING_CONTEXT_LENGTH+32+16+32+32;
+
 impl RoutingPublic {
-    pub fn valid(&self) -> ValidityResult { self.validity.valid() }
+    pub fn valid<K: Clock+?Sized>(&self, clock: &K) -> ValidityResult { self.validity.valid(clock) }
+
+    /// Message signed by the issuer, excluding the signature itself.
+    pub fn signable(&self) -> [u8; ROUTING_SIGNABLE_LENGTH] {
+        let mut b = [0u8; ROUTING_SIGNABLE_LENGTH];
+        {
+        let (context,public,validity,issuer,kem)
+          = mut_array_refs![&mut b,SIGNING_CONTEXT_LENGTH,32,16,32,32];
+        *context = signing_context(ROUTING_LABEL);
+        *public = self.public;
+        *validity = self.validity.to_bytes();
+        *issuer = self.issuer.0;
+        if let Some(ref k) = self.kem { *kem = k.digest(); }
+        }
+        b
+    }
 
     pub fn verify(&self) -> bool {
-        let b = self.to_bytes();
         ed25519::PublicKey::from_bytes(&self.issuer.0)
-          .verify::<Ed25519Hash>(&b[..ROUTING_PUBLIC_LENGTH-64],&self.signature)
+          .verify::<Ed25519Hash>(&self.signable(),&self.signature)
     }
 
-    pub fn to_bytes(&self) -> [u8; ROUTING_PUBLIC_LENGTH] {
-        let mut r = [0u8; ROUTING_PUBLIC_LENGTH];
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let kem = self.kem.as_ref().map_or(&[][..], |k| &k.0);
+        let mut r = vec![0u8; ROUTING_PUBLIC_LENGTH + kem.len()];
         {
         let (public,validity,issuer,signature)
-          = mut_array_refs![&mut r,32,16,32,64];
+          = mut_array_refs![array_mut_ref![r,0,ROUTING_PUBLIC_LENGTH],32,16,32,64];
         *public = self.public;
         *validity = self.validity.to_bytes();
         *issuer = self.issuer.0;
         *signature = self.signature.to_bytes();
         }
+        r[ROUTING_PUBLIC_LENGTH..].copy_from_slice(kem);
         r
     }
-    pub fn from_bytes(b: &[u8; ROUTING_PUBLIC_LENGTH]) -> RoutingPublic {
+    pub fn from_bytes(b: &[u8]) -> KeysResult<RoutingPublic> {
         // use curve25519_dalek::edwards::CompressedEdwardsY;
+        if b.len() < ROUTING_PUBLIC_LENGTH || b.len() > ROUTING_PUBLIC_LENGTH + MAX_KEM_PUBLIC_LENGTH {
+            return Err( KeysError::BadLength("RoutingPublic has wrong length", b.len()) );
+        }
         let (public,validity,issuer,signature)
-          = array_refs![b,32,16,32,64];
-        RoutingPublic {
+          = array_refs![array_ref![b,0,ROUTING_PUBLIC_LENGTH],32,16,32,64];
+        let kem = &b[ROUTING_PUBLIC_LENGTH..];
+        Ok( RoutingPublic {
             public: *public,
             validity: ValidityPeriod::from_bytes(validity),
             issuer: IssuerPublicKey(*issuer),
+            kem: if kem.len() > 0 { Some(KemPublic(kem.to_vec().into_boxed_slice())) } else { None },
             signature: ed25519::Signature(*signature),
-        }
+        } )
+    }
+}
+
+/// We serialize `RoutingPublic`s with any `KemPublic` appended,
+/// so their length varies.
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for RoutingPublic {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok,S::Error>
+      where S: ::serde::Serializer {
+        serializer.serialize_bytes(& self.to_bytes())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for RoutingPublic {
+    fn deserialize<D>(deserializer: D) -> Result<RoutingPublic,D::Error>
+      where D: ::serde::Deserializer<'de> {
+        let v = deserializer.deserialize_bytes(::macros::BytesVisitor("RoutingPublic")) ?;
+        RoutingPublic::from_bytes(&v).map_err( |_|
+            ::serde::de::Error::invalid_length(v.len(), &"at least ROUTING_PUBLIC_LENGTH")
+        )
     }
 }
 
@@ -103,10 +183,30 @@ impl RoutingSecret {
 }
 
 
+/// Explicit opt-in wrapper for serializing secret key material.
+///
+/// We never implement Serde's traits for secret key types themselves,
+/// so that secrets cannot leak merely by being included in some
+/// larger serialized structure.  Wrap them in `ExposeSecret` when you
+/// actually intend to write them out, like when saving a node's keys.
+#[derive(Clone, Debug)]
+pub struct ExposeSecret<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl_serde_via_bytes!(ExposeSecret<RoutingSecret>, ROUTING_SECRET_LENGTH,
+    |x: &ExposeSecret<RoutingSecret>| x.0.to_bytes(),
+    |b| ExposeSecret(RoutingSecret::from_bytes(b)) );
+
+
 /// Identifies a particular node without specifying a routing key.
 #[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
 pub struct IssuerPublicKey(pub [u8; 32]);
 
+#[cfg(feature = "serde")]
+impl_serde_via_bytes!(IssuerPublicKey, 32,
+    |x: &IssuerPublicKey| x.0,
+    |b: &[u8; 32]| IssuerPublicKey(*b) );
+
 /// 
 #[derive(Clone, Debug)]  // Copy
 pub struct IssuerPublicKeyInfo {
@@ -119,23 +219,49 @@ pub struct IssuerPublicKeyInfo {
     // TODO: Signatures with older issuer keys?
 }
 
-fn issuer_signable(pk: &IssuerPublicKey, validity: &ValidityPeriod) -> [u8; 16+32] {
-    let mut b = [0u8; 16+32];
+/// Length of the message signed by the issuer in an `IssuerPublicKeyInfo`.
+pub const ISSUER_SIGNABLE_LENGTH: usize = SIGNING_CONTEXT_LENGTH+16+32;
+
+fn issuer_signable(pk: &IssuerPublicKey, validity: &ValidityPeriod) -> [u8; ISSUER_SIGNABLE_LENGTH] {
+    let mut b = [0u8; ISSUER_SIGNABLE_LENGTH];
     {
-        let (v,p) = mut_array_refs![&mut b,16,32];
+        let (c,v,p) = mut_array_refs![&mut b,SIGNING_CONTEXT_LENGTH,16,32];
+        *c = signing_context(ISSUER_LABEL);
         *v = validity.to_bytes();
         *p = pk.0;
     }
     b
 }
 
+pub const ISSUER_PUBLIC_INFO_LENGTH: usize = 16+64;
+
 impl IssuerPublicKeyInfo {
+    pub fn to_bytes(&self) -> [u8; ISSUER_PUBLIC_INFO_LENGTH] {
+        let mut r = [0u8; ISSUER_PUBLIC_INFO_LENGTH];
+        {
+        let (validity,signature) = mut_array_refs![&mut r,16,64];
+        *validity = self.validity.to_bytes();
+        *signature = self.signature.to_bytes();
+        }
+        r
+    }
+    pub fn from_bytes(b: &[u8; ISSUER_PUBLIC_INFO_LENGTH]) -> IssuerPublicKeyInfo {
+        let (validity,signature) = array_refs![b,16,64];
+        IssuerPublicKeyInfo {
+            validity: ValidityPeriod::from_bytes(validity),
+            signature: ed25519::Signature(*signature),
+        }
+    }
+
     pub fn verify(&self, pk: &IssuerPublicKey) -> bool {
         ed25519::PublicKey::from_bytes(&pk.0)
           .verify::<Ed25519Hash>(& issuer_signable(pk,&self.validity),&self.signature)
     }
 }
 
+#[cfg(feature = "serde")]
+impl_serde_via_bytes!(IssuerPublicKeyInfo, ISSUER_PUBLIC_INFO_LENGTH);
+
 /// 
 #[derive(Debug)]  // Clone, Copy
 pub struct IssuerSecret {
@@ -161,6 +287,12 @@ impl IssuerSecret {
     }
 
     pub fn issue<R: Rng>(&self, rng: &mut R, validity: ValidityPeriod)
+      -> (RoutingName,RoutingPublic,RoutingSecret) {
+        self.issue_hybrid(rng, validity, None)
+    }
+
+    /// Issue a routing key that advertises `kem` for hybrid sub-hops.
+    pub fn issue_hybrid<R: Rng>(&self, rng: &mut R, validity: ValidityPeriod, kem: Option<KemPublic>)
       -> (RoutingName,RoutingPublic,RoutingSecret) {
         let mut s = RoutingSecret {
             name: RoutingName([0u8; 16]),
@@ -171,12 +303,129 @@ impl IssuerSecret {
             public: curve::Point::from_secret(&s.secret).compress(),
             validity: validity,
             issuer: IssuerPublicKey(self.keys.public.to_bytes()),
+            kem,
             signature: ed25519::Signature([0u8; 64]),
         };
-        let b = p.to_bytes();
-        p.signature = self.keys.sign::<Ed25519Hash>(&b[..ROUTING_SECRET_LENGTH-64]);
+        p.signature = self.keys.sign::<Ed25519Hash>(&p.signable());
         s.name = p.name();
         (s.name,p,s)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use rand::OsRng;
+    use std::time::{Duration,UNIX_EPOCH};
+    use super::*;
+
+    fn os_rng() -> OsRng {
+        OsRng::new().expect("failed to create an OS RNG")
+    }
+
+    fn validity(start: u64) -> ValidityPeriod {
+        ValidityPeriod::new(UNIX_EPOCH + Duration::from_secs(start), Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn issued_certificates_verify() {
+        let mut r = os_rng();
+        let issuer = IssuerSecret::new(&mut r, validity(1000));
+        let (ipk,info) = issuer.public();
+        assert!(info.verify(&ipk));
+
+        let (name,p,s) = issuer.issue(&mut r, validity(2000));
+        assert!(p.verify());
+        assert_eq!(name, s.name);
+        assert_eq!(name, p.name());
+
+        let p = RoutingPublic::from_bytes(&p.to_bytes()).unwrap();
+        assert!(p.verify());
+        assert!( RoutingPublic::from_bytes(&p.to_bytes()[1..]).is_err() );
+
+        let mut q = p.clone();
+        q.validity += Duration::from_secs(1);
+        assert!(! q.verify());
+        let mut q = p.clone();
+        q.public[0] ^= 1;
+        assert!(! q.verify());
+    }
+
+    #[test]
+    fn kem_public_signed() {
+        let mut r = os_rng();
+        let issuer = IssuerSecret::new(&mut r, validity(1000));
+        let kem = KemPublic(vec![7u8; 800].into_boxed_slice());
+        let (name,p,_) = issuer.issue_hybrid(&mut r, validity(2000), Some(kem.clone()));
+        assert!(p.verify());
+
+        let q = RoutingPublic::from_bytes(&p.to_bytes()).unwrap();
+        assert_eq!(q.kem, Some(kem));
+        assert_eq!(q.name(), name);
+        assert!(q.verify());
+
+        // Stripping or replacing the KEM key invalidates the signature.
+        let mut q = p.clone();
+        q.kem = None;
+        assert!(! q.verify());
+        let mut q = p.clone();
+        q.kem = Some(KemPublic(vec![8u8; 800].into_boxed_slice()));
+        assert!(! q.verify());
+    }
+
+    #[test]
+    fn signature_type_confusion() {
+        let mut r = os_rng();
+        let issuer = IssuerSecret::new(&mut r, validity(1000));
+        let (ipk,info) = issuer.public();
+        let (_,p,_) = issuer.issue(&mut r, validity(1000));
+
+        // A routing key signature cannot vouch for the issuer key.
+        let forged = IssuerPublicKeyInfo {
+            validity: p.validity.clone(),
+            signature: p.signature,
+        };
+        assert!(! forged.verify(&ipk));
+
+        // An issuer key signature cannot vouch for a routing key.
+        let mut forged = p.clone();
+        forged.signature = info.signature;
+        assert!(! forged.verify());
+
+        // Nor can a raw signature over the undecorated fields.
+        let b = p.to_bytes();
+        let mut forged = p.clone();
+        forged.signature = issuer.keys.sign::<Ed25519Hash>(&b[..ROUTING_PUBLIC_LENGTH-64]);
+        assert!(! forged.verify());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        use serde_json::{to_string,from_str};
+        let mut r = os_rng();
+        let issuer = IssuerSecret::new(&mut r, validity(1000));
+        let (ipk,info) = issuer.public();
+        let kem = KemPublic(vec![7u8; 80].into_boxed_slice());
+        let (_,p,s) = issuer.issue_hybrid(&mut r, validity(2000), Some(kem));
+
+        let q: RoutingPublic = from_str(&to_string(&p).unwrap()).unwrap();
+        assert_eq!(&q.to_bytes()[..], &p.to_bytes()[..]);
+        assert!(q.verify());
+
+        let t: ExposeSecret<RoutingSecret> = from_str(&to_string(&ExposeSecret(s.clone())).unwrap()).unwrap();
+        assert_eq!(&t.0.to_bytes()[..], &s.to_bytes()[..]);
+
+        let k: IssuerPublicKey = from_str(&to_string(&ipk).unwrap()).unwrap();
+        assert_eq!(k, ipk);
+        let i: IssuerPublicKeyInfo = from_str(&to_string(&info).unwrap()).unwrap();
+        assert!(i.verify(&ipk));
+
+        // Fixed length encodings reject any other length.
+        assert!( from_str::<IssuerPublicKey>("[1,2,3]").is_err() );
+        let mut v = to_string(&info).unwrap();
+        v.insert_str(1, "0,");
+        assert!( from_str::<IssuerPublicKeyInfo>(&v).is_err() );
+        assert!( from_str::<RoutingPublic>("[]").is_err() );
+    }
+}
@@ -8,6 +8,7 @@ use std::time::SystemTime;
 use rand::{Rng};  // Rand
 
 use super::RoutingName;
+use super::time::Clock;
 use super::certs::*;
 use super::error::*;
 
@@ -18,16 +19,19 @@ pub type VPnRN = (super::time::ValidityPeriod,RoutingName);
 /// TODO: Make private once we get associated type constructors
 pub type RpI = [VPnRN; super::MAX_ROUTING_PER_ISSUER];
 
-/// 
-pub fn rpi_before(r: &VPnRN, before: SystemTime) -> bool {
-    r.0.end() > before && r.0.start() < r.0.end() 
+/// Routing key record already valid at `now` and remaining valid
+/// until `before`.
+pub fn rpi_valid(r: &VPnRN, now: SystemTime, before: SystemTime) -> bool {
+    r.0.start() <= now && r.0.end() > before && r.0.start() < r.0.end() 
 }
 
 /// Access records for issuer and routing keys.
 /// 
 /// TODO: Algorithms and data structures suck ass here.  
 ///       Rewrite everything using well designed data structures.
-/// TODO: Worry about leakage through validity period, especially with SURBs.
+/// We coarsen validity periods according to `sphinx::Params::VALIDITY_POLICY`
+/// when building headers, especially SURBs, so that their validity
+/// periods leak little about which routing keys they employ.
 pub trait Concensus {
     /// Returns the routing public key record associated to a given
     /// routing name.
@@ -37,8 +41,11 @@ pub trait Concensus {
     fn routing_named(&self, routing_name: &RoutingName)
       -> KeysResult<&RoutingPublic>;
 
-    /// Fetch a routing key for some particular issuer
-    fn routing_by_issuer<R: Rng>(&self, rng: &mut R,
+    /// Fetch a routing key for some particular issuer, which is
+    /// currently valid according to `clock` and remains valid until
+    /// `before`.
+    fn routing_by_issuer<R: Rng,K: Clock+?Sized>(&self, rng: &mut R,
+          clock: &K,
           issuer: &IssuerPublicKey, 
           before: SystemTime
       ) -> KeysResult<(RoutingName,&RoutingPublic)>;
@@ -46,13 +53,14 @@ pub trait Concensus {
     /// Fetch a routing key with substancial validity period remaining.
     ///
     /// Used for building a header to connect with a SURB.
-    fn routing_by_routing<R: Rng>(&self, rng: &mut R,
+    fn routing_by_routing<R: Rng,K: Clock+?Sized>(&self, rng: &mut R,
+          clock: &K,
           routing_name: &RoutingName, 
           before: SystemTime
       ) -> KeysResult<(RoutingName,&RoutingPublic)> 
     {
         let r = self.routing_named(routing_name) ?;
-        self.routing_by_issuer(rng, &r.issuer, before)
+        self.routing_by_issuer(rng, clock, &r.issuer, before)
     }
 
     /// Randomly select a valid `RoutingName` from an array of exactly
@@ -62,12 +70,12 @@ pub trait Concensus {
     /// higher-kinded types, or `-> impl Trait` in traits allow us to write
     /// generalize `RoutePicker` to a trait and write `router_picker` without
     /// a trait object.
-    fn rpi_picker<'a,R: Rng>(&'a self, rng: &mut R, rpi: &'a RpI, before: SystemTime) 
+    fn rpi_picker<'a,R: Rng>(&'a self, rng: &mut R, rpi: &'a RpI, now: SystemTime, before: SystemTime) 
       -> KeysResult<(RoutingName,&'a RoutingPublic)> 
     {
         use arrayvec::ArrayVec;
         let v: ArrayVec<[&VPnRN; super::MAX_ROUTING_PER_ISSUER]>
-          = rpi.iter().filter(|r| rpi_before(r,before)).collect();
+          = rpi.iter().filter(|r| rpi_valid(r,now,before)).collect();
         if v.len() == 0 {
             // let issuer = self.routing_named(&rpi[0])
             //   .map(|rp| rp.issuer)
@@ -81,12 +89,14 @@ pub trait Concensus {
 
     /// TODO: As in `rand::Rand::gen_iter()`, an iterator might work
     /// here but only if we give up our random number generator.
-    fn route_picker<'s>(&'s self, before: SystemTime)
+    fn route_picker<'s,K: Clock+?Sized>(&'s self, clock: &K, before: SystemTime)
       -> KeysResult<RoutePicker<'s,Self>>;
 }
 
 pub struct RoutePicker<'a,C> where C: Concensus + 'a + ?Sized  {
     pub concensus: &'a C,
+    /// Time according to our `Clock` when we created this `RoutePicker`.
+    pub now: SystemTime,
     pub before: SystemTime,
     pub issuers: Vec<&'a RpI>,
 }
@@ -94,7 +104,7 @@ pub struct RoutePicker<'a,C> where C: Concensus + 'a + ?Sized  {
 impl<'a,C> RoutePicker<'a,C> where C: Concensus+'a {
     pub fn pick<R: Rng>(&self, rng: &mut R) -> KeysResult<(RoutingName,&RoutingPublic)> {
         let i = rng.gen_range(0, self.issuers.len());
-        self.concensus.rpi_picker(rng,self.issuers[i],self.before)
+        self.concensus.rpi_picker(rng,self.issuers[i],self.now,self.before)
     }
 }
 
@@ -11,6 +11,7 @@ use crypto::digest::Digest;
 use crypto::sha3::Sha3;
 
 use super::RoutingName;
+use super::time::Clock;
 use super::certs::*;
 use super::concensus::*;
 use super::error::*;
@@ -51,7 +52,8 @@ impl Concensus for Directory {
           .ok_or( KeysError::Routing(*routing_name,"No RoutingPublic for given RoutingName.") )
     }
 
-    fn routing_by_issuer<R: Rng>(&self, rng: &mut R,
+    fn routing_by_issuer<R: Rng,K: Clock+?Sized>(&self, rng: &mut R,
+          clock: &K,
           issuer: &IssuerPublicKey, 
           before: SystemTime
       ) -> KeysResult<(RoutingName,&RoutingPublic)>
@@ -62,15 +64,16 @@ impl Concensus for Directory {
         if rpi.len() == 0 {
             return Err( KeysError::Issuer(*issuer,"No routing keys for given issuer.") ) 
         }
-        self.rpi_picker(rng,rpi,before)
+        self.rpi_picker(rng,rpi,clock.now(),before)
     }
 
-    fn route_picker<'s>(&'s self, before: SystemTime)
+    fn route_picker<'s,K: Clock+?Sized>(&'s self, clock: &K, before: SystemTime)
       -> KeysResult<RoutePicker<'s,Directory>> {
+        let now = clock.now();
         let issuers: Vec<_> 
           = self.issuers.values().map(|t| &t.1)
-          .filter(|rpi| rpi.iter().any(|r| rpi_before(r,before))).collect();
-        Ok( RoutePicker { concensus: self, before, issuers } ) 
+          .filter(|rpi| rpi.iter().any(|r| rpi_valid(r,now,before))).collect();
+        Ok( RoutePicker { concensus: self, now, before, issuers } ) 
     }
 }
 
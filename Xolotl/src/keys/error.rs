@@ -10,6 +10,7 @@ use std::fmt;
 #[derive(Debug, Clone)]
 pub enum KeysError {
     InternalError(&'static str),
+    BadLength(&'static str, usize),
     Routing(super::RoutingName,&'static str),
     Issuer(super::certs::IssuerPublicKey,&'static str),
 }
@@ -23,6 +24,8 @@ impl fmt::Display for KeysError {
         match *self {
             InternalError(s)
                 => write!(f, "Internal error: {}", s),
+            BadLength(s,l)
+                => write!(f, "Length error: {} ({})", s, l),
             Routing(r,t)
                 => write!(f, "Routing key error: {} ({})", t, r.0.to_hex()),
             Issuer(i,t)
@@ -40,6 +43,7 @@ impl Error for KeysError {
         use self::KeysError::*;
         match *self {
             InternalError(_) => None,
+            BadLength(_,_) => None,
             Routing(_,_) => None,
             Issuer(_,_) => None,
         }
@@ -0,0 +1,90 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Post-quantum key encapsulation for hybrid Sphinx sub-hops
+//!
+//! Our ratchet provides post-quantum protection only after a client
+//! establishes a ratchet branch with a node, so any first contact
+//! relies upon curve25519 alone.  Nodes may therefore advertise a
+//! public key for some lattice-based key encapsulation mechanism
+//! (KEM) in their `RoutingPublic`, which clients use to add a hybrid
+//! sub-hop whose key mixes the KEM shared secret into the key from
+//! the curve25519 key exchange.
+//!
+//! We abstract over the KEM with the `Kem` trait because candidate
+//! lattice KEMs remain in flux, so all nodes and clients must agree
+//! upon the `Kem` used with any given mix network.
+//!
+//! We ship no lattice KEM ourselves, so hybrid sub-hops provide no
+//! post-quantum protection until a mix network integrates one, which
+//! requires:
+//!
+//! - implementing `Kem` for the KEM, deriving all encapsulation
+//!   randomness from `seed`, as ML-KEM's deterministic internal
+//!   encapsulation does with its 32 byte message,
+//! - issuing routing keys with `IssuerSecret::issue_hybrid` and
+//!   registering the KEM secrets with `Router::add_kem_secret`, and
+//! - building headers with `sphinx::Params` whose
+//!   `MAX_BETA_TAIL_LENGTH` covers `Command::Hybrid` with the KEM's
+//!   ciphertexts, like `sphinx::params::HybridChatParams` does for
+//!   ML-KEM-768.  Ciphertexts travel in beta, so the 64 byte tails of
+//!   our other presets fit no lattice KEM.
+
+use std::fmt;
+
+use super::error::*;
+
+
+pub const KEM_SHARED_SECRET_LENGTH: usize = 32;
+
+/// Length of ML-KEM-768 ciphertexts, for which we size
+/// `sphinx::params::HybridChatParams`.
+pub const ML_KEM_768_CIPHERTEXT_LENGTH: usize = 1088;
+
+/// Shared secret produced by a `Kem`.
+pub type KemSharedSecret = [u8; KEM_SHARED_SECRET_LENGTH];
+
+/// Maximum length of a `KemPublic` we accept when parsing.
+pub const MAX_KEM_PUBLIC_LENGTH: usize = 0x4000;
+
+/// Encoded KEM public key advertised in a `RoutingPublic`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KemPublic(pub Box<[u8]>);
+
+/// Encoded KEM secret key held by the node alongside its `RoutingSecret`.
+#[derive(Clone)]
+pub struct KemSecret(pub Box<[u8]>);
+
+impl fmt::Debug for KemSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KemSecret(..)")
+    }
+}
+
+impl KemPublic {
+    /// Hash of our public key that the issuer signs in `RoutingPublic`.
+    pub fn digest(&self) -> [u8; 32] {
+        use crypto::digest::Digest;
+        use crypto::sha3::Sha3;
+
+        let mut r = [0u8; 32];
+        let mut sha = Sha3::sha3_256();
+        sha.input_str( "Xolotl KEM public" );
+        sha.input(&self.0);
+        sha.result(&mut r);
+        sha.reset();
+        r
+    }
+}
+
+/// Key encapsulation mechanism used by hybrid sub-hops.
+pub trait Kem : Send+Sync {
+    /// Encapsulate a fresh shared secret to `public`, returning the
+    /// ciphertext and shared secret.  We supply all randomness in
+    /// `seed` so that headers remain reproducible from our seed.
+    fn encapsulate(&self, public: &KemPublic, seed: &[u8; 32])
+      -> KeysResult<(Box<[u8]>,KemSharedSecret)>;
+
+    /// Recover the shared secret from `ciphertext`.
+    fn decapsulate(&self, secret: &KemSecret, ciphertext: &[u8])
+      -> KeysResult<KemSharedSecret>;
+}
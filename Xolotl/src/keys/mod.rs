@@ -40,4 +40,6 @@ pub struct RoutingName(pub RoutingNameBytes);
 pub mod certs;
 pub use self::certs::*;
 
+pub mod kem;
+
 
@@ -2,14 +2,102 @@
 
 //! Validity periods for key material
 //!
-//! TODO: Add serialization options using Serde.
+//! Enable the `serde` feature for serialization using Serde.
 
 use std::ops::{Range,AddAssign,Add,SubAssign,Sub};
+use std::sync::RwLock;
 use std::time::{Duration,SystemTime,UNIX_EPOCH};
 
+use rand::Rng;
+
+
+/// Source of the current time for validity checks and packet
+/// scheduling, so that tests may simulate the passage of time.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// `Clock` that reports the actual system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime { SystemTime::now() }
+}
+
+/// `Clock` that reports a simulated time, which only changes when
+/// explicitly set or advanced.
+#[derive(Debug)]
+pub struct MockClock(RwLock<SystemTime>);
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> MockClock { MockClock(RwLock::new(now)) }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.0.write().unwrap_or_else(|x| x.into_inner()) = now;
+    }
+
+    pub fn advance(&self, d: Duration) {
+        *self.0.write().unwrap_or_else(|x| x.into_inner()) += d;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.read().unwrap_or_else(|x| x.into_inner())
+    }
+}
+
 #[derive(Clone, Debug)] // Copy
 pub struct ValidityPeriod(pub Range<u64>);
 
+/// Policy for coarsening the validity period of headers and SURBs.
+///
+/// A header's validity period is the intersection of the validity
+/// periods of the routing keys it uses, so an exact validity period
+/// tells anyone holding a SURB roughly which routing keys it traverses.
+/// We therefore shrink each hop's validity period to a coarse grid
+/// before intersecting, and shave a random amount off the end of
+/// the result.  We only ever shrink validity periods, so a header
+/// never claims validity beyond that of its routing keys.
+#[derive(Clone, Copy, Debug)]
+pub enum ValidityPolicy {
+    /// Report the exact intersection of hop validity periods.
+    /// Only suitable for testing.
+    Exact,
+
+    /// Shrink every hop's validity period to multiples of
+    /// `granularity` seconds, and then shave a uniformly random
+    /// number of at most `fuzz` further multiples off the end.
+    Coarsen { granularity: u64, fuzz: u64 },
+}
+
+impl ValidityPolicy {
+    /// Coarsen the validity period of a single hop, returning `None`
+    /// if nothing remains.
+    pub fn coarsen(&self, v: &ValidityPeriod) -> Option<ValidityPeriod> {
+        match *self {
+            ValidityPolicy::Exact => Some(v.clone()),
+            ValidityPolicy::Coarsen { granularity, .. } => v.quantize(granularity),
+        }
+    }
+
+    /// Randomly shorten the final validity period of a header, returning
+    /// `None` if nothing remains.
+    pub fn fuzz<R: Rng>(&self, rng: &mut R, v: &ValidityPeriod) -> Option<ValidityPeriod> {
+        let (granularity, fuzz) = match *self {
+            ValidityPolicy::Exact => return Some(v.clone()),
+            ValidityPolicy::Coarsen { granularity, fuzz } => (granularity, fuzz),
+        };
+        let v = if let Some(v) = v.quantize(granularity) { v } else { return None; };
+        if granularity == 0 || fuzz == 0 { return Some(v); }
+        // Never shave off the last multiple of granularity.
+        let steps = (v.0.end - v.0.start) / granularity;
+        let shave = rng.gen_range(0, ::std::cmp::min(fuzz,steps-1) + 1);
+        Some( ValidityPeriod(v.0.start .. v.0.end - shave*granularity) )
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum ValidityResult {
     Pending(Duration),
@@ -19,7 +107,8 @@ pub enum ValidityResult {
 
 impl ValidityPeriod {
     pub fn new(start: SystemTime, duration: Duration) -> ValidityPeriod {
-        let start = start.duration_since(start).unwrap();
+        let start = start.duration_since(UNIX_EPOCH)
+          .expect("validity period starts before the Unix epoch");
         ValidityPeriod( start.as_secs() .. (start+duration).as_secs() )
     }
 
@@ -30,6 +119,18 @@ impl ValidityPeriod {
         if start < end { Some(ValidityPeriod(start..end)) } else { None }
     }
 
+    /// Shrink our validity period to start and end on multiples of
+    /// `granularity` seconds, returning `None` if nothing remains.
+    pub fn quantize(&self, granularity: u64) -> Option<ValidityPeriod> {
+        if granularity == 0 { return Some(self.clone()); }
+        let start = match self.0.start.checked_add(granularity-1) {
+            Some(s) => s / granularity * granularity,
+            None => return None,
+        };
+        let end = self.0.end / granularity * granularity;
+        if start < end { Some(ValidityPeriod(start..end)) } else { None }
+    }
+
     // pub fn intersect_assign(&mut self, other: &ValidityPeriod) {
     //     *self = self.intersect(other);
     // }
@@ -41,15 +142,20 @@ impl ValidityPeriod {
         UNIX_EPOCH + Duration::from_secs(self.0.end)
     }
 
-    pub fn valid(&self) -> ValidityResult {
+    /// Check our validity at the time given by `clock`.
+    pub fn valid<K: Clock+?Sized>(&self, clock: &K) -> ValidityResult {
+        self.valid_at(clock.now())
+    }
+
+    /// Check our validity at the specified time `now`.
+    pub fn valid_at(&self, now: SystemTime) -> ValidityResult {
         use self::ValidityResult::*;
         let start = Duration::from_secs(self.0.start);
         let end = Duration::from_secs(self.0.end);
-        let len = end-start;
         if start > end {
             return Expired( Duration::from_secs(0) ); 
         }
-        let now = SystemTime::now();
+        let len = end-start;
         /*
         match now.duration_since(UNIX_EPOCH + end) {
             Ok(d) => Expired(d),
@@ -85,6 +191,30 @@ impl ValidityPeriod {
     }
 }
 
+/// We serialize `ValidityPeriod` as a pair `(start,end)` of seconds
+/// since the Unix epoch, not using `to_bytes`, so that human readable
+/// formats remain human readable.
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for ValidityPeriod {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok,S::Error>
+      where S: ::serde::Serializer {
+        ::serde::Serialize::serialize(&(self.0.start, self.0.end), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for ValidityPeriod {
+    fn deserialize<D>(deserializer: D) -> Result<ValidityPeriod,D::Error>
+      where D: ::serde::Deserializer<'de> {
+        use ::serde::de::Error;
+        let (start,end) = <(u64,u64) as ::serde::Deserialize>::deserialize(deserializer) ?;
+        if start > end {
+            return Err( D::Error::custom("validity period ends before it starts") );
+        }
+        Ok( ValidityPeriod(start..end) )
+    }
+}
+
 impl<'a> Add<Duration> for &'a ValidityPeriod {
     type Output = ValidityPeriod;
 
@@ -136,3 +266,66 @@ impl SubAssign<Duration> for ValidityPeriod {
 }
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use rand::OsRng;
+    use super::*;
+
+    #[test]
+    fn validity_policy_shrinks() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let v = ValidityPeriod(1234..98765);
+        let q = v.quantize(1000).unwrap();
+        assert_eq!(q.0, 2000..98000);
+        assert!(v.quantize(100000).is_none());
+
+        let p = ValidityPolicy::Coarsen { granularity: 1000, fuzz: 10 };
+        for _ in 0..20 {
+            let f = p.fuzz(&mut r, &v).unwrap();
+            assert_eq!(f.0.start, 2000);
+            assert!(f.0.end <= 98000 && f.0.end >= 88000);
+            assert_eq!(f.0.end % 1000, 0);
+            assert_eq!(f.intersect(&v).unwrap().0, f.0);
+        }
+        let short = ValidityPeriod(1000..2000);
+        assert_eq!(p.fuzz(&mut r, &short).unwrap().0, 1000..2000);
+    }
+
+    #[test]
+    fn validity_with_mock_clock() {
+        let epoch = UNIX_EPOCH + Duration::from_secs(1000);
+        let v = ValidityPeriod::new(epoch, Duration::from_secs(100));
+        assert_eq!(v.0, 1000..1100);
+
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(990));
+        match v.valid(&clock) {
+            ValidityResult::Pending(d) => assert_eq!(d, Duration::from_secs(10)),
+            r => panic!("Expected Pending, found {:?}", r),
+        }
+        clock.advance(Duration::from_secs(40));
+        match v.valid(&clock) {
+            ValidityResult::Valid(d) => assert_eq!(d, Duration::from_secs(70)),
+            r => panic!("Expected Valid, found {:?}", r),
+        }
+        clock.advance(Duration::from_secs(100));
+        match v.valid(&clock) {
+            ValidityResult::Expired(d) => assert_eq!(d, Duration::from_secs(30)),
+            r => panic!("Expected Expired, found {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn validity_serde_round_trip() {
+        use serde_json::{to_string,from_str};
+        let v = ValidityPeriod(1000..1100);
+        let j = to_string(&v).unwrap();
+        assert_eq!(j, "[1000,1100]");
+        let w: ValidityPeriod = from_str(&j).unwrap();
+        assert_eq!(w.0, v.0);
+        assert!( from_str::<ValidityPeriod>("[1000]").is_err() );
+        assert!( from_str::<ValidityPeriod>("[1100,1000]").is_err() );
+    }
+}
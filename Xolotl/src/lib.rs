@@ -16,6 +16,8 @@
 #![feature(box_syntax)]
 #![feature(exclusive_range_pattern)]
 #![feature(conservative_impl_trait)]
+#![feature(const_fn)]
+#![cfg_attr(test, feature(test))]
 
 // #![doc(html_root_url="...")]
 
@@ -45,6 +47,15 @@ extern crate lioness;
 
 extern crate crypto;  //  SHA3, Poly1305, checking curve25519_dalek
 
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(test)]
+extern crate test;
+
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
 
 #[macro_use]
 mod macros;
@@ -55,6 +66,12 @@ mod keys;
 mod ratchet;
 mod sphinx;
 
+#[cfg(feature = "fuzzing")]
+pub use sphinx::fuzz;
+
+// Used by the `xolotl` command line tool.
+pub use ratchet::{ExportKey,CheckReport,check_export};
+
 
 // pub use self::...;
 // use self::...;
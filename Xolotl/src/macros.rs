@@ -14,6 +14,65 @@ macro_rules! impl_Display_as_hex_for_WrapperStruct {
 */
 
 
+/// Implement Serde's `Serialize` and `Deserialize` for a type with
+/// a fixed length byte encoding, by default its `to_bytes` and
+/// `from_bytes` methods.
+#[cfg(feature = "serde")]
+macro_rules! impl_serde_via_bytes {
+    ($t:ty, $len:expr) => {
+        impl_serde_via_bytes!($t, $len, |x: &$t| x.to_bytes(), |b| <$t>::from_bytes(b));
+    };
+    ($t:ty, $len:expr, $to:expr, $from:expr) => {
+        impl ::serde::Serialize for $t {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok,S::Error>
+              where S: ::serde::Serializer {
+                serializer.serialize_bytes(& ($to)(self))
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $t {
+            fn deserialize<D>(deserializer: D) -> Result<$t,D::Error>
+              where D: ::serde::Deserializer<'de> {
+                let v = deserializer.deserialize_bytes(::macros::BytesVisitor(stringify!($t))) ?;
+                if v.len() != $len {
+                    return Err( ::serde::de::Error::invalid_length(v.len(), &stringify!($len)) );
+                }
+                Ok( ($from)(array_ref![v,0,$len]) )
+            }
+        }
+    };
+}
+
+/// Serde visitor that accepts either a byte string or a sequence of
+/// bytes, as self-describing formats like JSON produce the latter.
+#[cfg(feature = "serde")]
+pub struct BytesVisitor(pub &'static str);
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::de::Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "the byte encoding of {}", self.0)
+    }
+
+    fn visit_bytes<E: ::serde::de::Error>(self, v: &[u8]) -> Result<Vec<u8>,E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: ::serde::de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>,E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Vec<u8>,A::Error>
+      where A: ::serde::de::SeqAccess<'de> {
+        let mut v = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element() ? { v.push(b); }
+        Ok(v)
+    }
+}
+
+
 #[derive(Debug, Default)]
 pub struct Secret<T>(pub T) where T: Copy;
 
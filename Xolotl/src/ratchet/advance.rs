@@ -4,6 +4,7 @@
 
 use std::collections::HashMap; // HashSet
 use std::ops::Deref; // DerefMut
+use std::time::SystemTime;
 
 use super::MessageKey;
 use ::sphinx::SphinxSecret;
@@ -52,6 +53,13 @@ pub struct Advance<'a> {
 
     /// Keys to insert upon confirmation
     inserts: Vec<TwigIS>,
+
+    /// Branch from which we roll over to this branch, which we
+    /// record as rolled over upon confirmation.
+    rollover: Option<BranchId>,
+
+    /// Branch needs insertion as an initial branch, without parent.
+    initial: bool,
 }
 
 impl<'a> Transaction for Advance<'a> {
@@ -61,34 +69,7 @@ impl<'a> Transaction for Advance<'a> {
     }
 
     fn confirm(&mut self) -> RatchetResult<()> {
-        let mut berry: Option<TwigId> = None;
-
-        if self.inserts.len() == 0 { return Ok(()); }
-        if self.insert_branch.is_none() {
-            // Add branch data
-            let mut branches = self.state().branches.write() ?; //  PoisonError
-            branches.insert(*self.branch_id.id(), self.branch.clone());
-
-            // Add parents link from children's family name to our branch_id
-            let mut parents = self.state().parents.write() ?; // PoisonError
-            parents.insert(self.branch.child_family_name(), *self.branch_id.id());
-
-            // Identify the berry from which we grew
-            let parent_bid: BranchId = self.state()
-              .parent_id(self.branch_id.family()) ?;  // PoisonError, MissingParent
-            berry = Some( TwigId(parent_bid, self.branch_id.berry()) );
-        }
-
-        let mut twigs = self.branch_id.0.twigs.write() ?; // PoisonError
-
-        // Erase the berry from which we grew
-        let _ = berry.map(|b| twigs.remove(&b));
-
-        // Do the transaction's iserts
-        for TwigIS(idx,tk) in self.inserts.drain(..) {
-            twigs.insert(TwigId(*self.branch_id.id(),idx), tk.data());
-        }
-        Ok(())
+        self.confirm_at(None)
     }
 
     fn forget(&mut self) {
@@ -103,11 +84,13 @@ impl<'a> Transaction for Advance<'a> {
         let inserts = inserts0.drain(..).map( |TwigIS(idx,tk)| (idx,tk) );
 
         // let insert_branch = self.insert_branch.map(|| Some(self.branch));
+        let tick = self.state().cache_tick();
         let mut cached = self.state().cached.write() ?; //  PoisonError
         if let Some( &mut AdvanceFailValue {
             branch: ref mut c_branch,
             insert_branch: ref mut c_insert_branch,
-            inserts: ref mut hm
+            inserts: ref mut hm,
+            used: ref mut c_used,
           } ) = cached.get_mut(self.branch_id.id()) {
 
             let s: &'static str = match (self.insert_branch.is_some(),c_insert_branch.is_some()) {
@@ -122,6 +105,7 @@ impl<'a> Transaction for Advance<'a> {
 
             // FIXME Not Good !!!
             c_branch.chain = self.branch.chain;
+            *c_used = tick;
 
             hm.reserve(self.inserts.len());
             hm.extend(inserts);
@@ -140,10 +124,15 @@ impl<'a> Transaction for Advance<'a> {
         // let hm = HashMap::<TwigIdx,TwigState>::with_capacity(self.inserts.len());
         // hm.extend(self.inserts.drain(..));
 
+        // Evict before inserting so that an attacker cannot grow
+        // the cache beyond its limit between garbage collections.
+        let limit = self.state().cache_limit();
+        evict_cached(&mut cached, limit.saturating_sub(1));
         cached.insert(*self.branch_id.id(), AdvanceFailValue {
             branch: self.branch.clone(),
             insert_branch: self.insert_branch.clone(),
-            inserts: hm
+            inserts: hm,
+            used: tick,
         } );
 
         Ok(())
@@ -163,7 +152,77 @@ impl<'a> Drop for Advance<'a> {
     }
 }
 
+/// Progress of an `Advance` saved for partial roll back.
+#[derive(Debug, Clone, Copy)]
+pub struct AdvanceSavepoint {
+    inserts: usize,
+    chain: TwigIdx,
+}
+
 impl<'a> Advance<'a> {
+    /// Confirm the transaction, recording that we used our branch
+    /// at `now` if given.
+    fn confirm_at(&mut self, now: Option<SystemTime>) -> RatchetResult<()> {
+        if self.inserts.len() == 0 { return Ok(()); }
+        let berry = self.prepare() ?;  // PoisonError, MissingParent
+        let state: &'a State = self.branch_id.0;
+        let mut guards = state.commit_guards() ?;  // PoisonError
+        self.commit(&mut guards, berry, now);
+        Ok(())
+    }
+
+    /// Identify the berry from which a new branch grew, doing every
+    /// check that might fail before `commit` writes anything.
+    fn prepare(&self) -> RatchetResult<Option<TwigId>> {
+        if self.insert_branch.is_none() || self.initial { return Ok(None); }
+        let parent_bid: BranchId = self.state()
+          .parent_id(self.branch_id.family()) ?;  // PoisonError, MissingParent
+        Ok( Some( TwigId(parent_bid, self.branch_id.berry()) ) )
+    }
+
+    /// Write our updates using locks taken by `State::commit_guards`,
+    /// given the berry found by `prepare`, and record that we used
+    /// our branch at `now` if given.  Never fails.
+    fn commit(&mut self, guards: &mut CommitGuards, berry: Option<TwigId>, now: Option<SystemTime>) {
+        let bid = *self.branch_id.id();
+        if let Some(now) = now { guards.used.insert(bid, now); }
+        if let Some(from) = self.rollover { guards.successors.insert(from, bid); }
+        if let Some(b) = berry {
+            // Record that our berry grew us, and erase it
+            guards.grown.insert(b, bid);
+            guards.twigs.remove(&b);
+        }
+        if self.insert_branch.is_some() {
+            // Add parents link from children's family name to our branch_id
+            guards.parents.insert(self.branch.child_family_name(), bid);
+        }
+
+        // Add or update branch data, as our chain index moves.
+        guards.branches.insert(bid, self.branch.clone());
+
+        // Insert a new branch's initial train key, which our inserts
+        // normally replace.
+        if let Some(TwigIS(idx,ref tk)) = self.insert_branch {
+            guards.twigs.insert(TwigId(bid,idx), tk.clone().data());
+        }
+
+        // Do the transaction's iserts
+        for TwigIS(idx,tk) in self.inserts.drain(..) {
+            guards.twigs.insert(TwigId(bid,idx), tk.data());
+        }
+    }
+
+    /// Save our progress so that `rollback` may undo later clicks.
+    pub fn savepoint(&self) -> AdvanceSavepoint {
+        AdvanceSavepoint { inserts: self.inserts.len(), chain: self.branch.chain }
+    }
+
+    /// Undo all clicks since `savepoint` was called.
+    pub fn rollback(&mut self, savepoint: AdvanceSavepoint) {
+        self.inserts.truncate(savepoint.inserts);
+        self.branch.chain = savepoint.chain;
+    }
+
     /// Begin a transaction to advance the ratchet on the branch `bid`.
     pub fn new(state: &'a State, bid: &BranchId) -> RatchetResult<Advance<'a>> {
 
@@ -178,6 +237,8 @@ impl<'a> Advance<'a> {
                 branch_id: branch_id,
                 insert_branch: None,
                 inserts: Vec::new(), // zero capasity, no allocation
+                rollover: None,
+                initial: false,
             })
         }
 
@@ -208,19 +269,56 @@ impl<'a> Advance<'a> {
             branch: branch,
             insert_branch: Some(TwigIS(TRAIN_START,TwigState::Train(tk))),
             inserts: Vec::new(), // zero capasity, no allocation
+            rollover: None,
+            initial: false,
         })
     }
 
-    /// Retrieve an unspecified twig type using `BranchIdGuard::get_twig(...)`
+    /// Begin a transaction on the initial branch derived from `seed`,
+    /// like `create_initial_branch`, except that we create the branch
+    /// only upon confirmation.  We continue the branch if it exists.
+    pub fn new_initial(state: &'a State, seed: &[u8]) -> RatchetResult<Advance<'a>> {
+        let (bid, branch, tk): (BranchId, Branch, TrainKey) = Branch::new_kdf(seed);
+        let branch_id = lock_branch_id(state,&bid) ?;  // PoisonError, BranchAlreadyLocked
+        let existing = state.branches.read()?.get(&bid).cloned();  // PoisonError
+        Ok( match existing {
+            Some(br) => Advance {
+                branch: br,
+                branch_id: branch_id,
+                insert_branch: None,
+                inserts: Vec::new(),
+                rollover: None,
+                initial: false,
+            },
+            None => Advance {
+                branch: branch,
+                branch_id: branch_id,
+                insert_branch: Some(TwigIS(TRAIN_START,TwigState::Train(tk))),
+                inserts: Vec::new(),
+                rollover: None,
+                initial: true,
+            },
+        } )
+    }
+
+    /// Retrieve an unspecified twig type, preferring our own pending
+    /// inserts over `BranchIdGuard::get_twig(...)`, so that multiple
+    /// steps within one transaction see one another.
     fn get_twig(&self, idx: TwigIdx) -> RatchetResult<TwigState> {
+        if let Some(&TwigIS(_,ref t)) = self.inserts.iter().rev().find(|x| x.0 == idx) {
+            return Ok(t.clone());
+        }
+        if let Some(TwigIS(i,ref t)) = self.insert_branch {
+            if i == idx { return Ok(t.clone()); }
+        }
         let tid = TwigId(*self.branch_id.id(), idx);
         self.branch_id.get_twig(&tid)
     }
 
-    /// Retrieve a specific twig type using `BranchIdGuard::get_twigy(...)`
+    /// Retrieve a specific twig type using `Advance::get_twig(...)`
     fn get_twigy<T: Twigy>(&self, idx: TwigIdx) -> RatchetResult<T> {
-        let tid = TwigId(*self.branch_id.id(), idx);
-        self.branch_id.get_twigy(&tid)
+        let twig = self.get_twig(idx) ?;  // PoisonError, MissingTwig
+        self.verify_twigstate::<T>(idx, &twig)
     }
 
     /// Add a twig to an advance transaction's insert queue.
@@ -254,14 +352,14 @@ impl<'a> Advance<'a> {
 
         let linkkey: LinkKey;
 
-        let (_i,j) = idx.split();
+        let (i,j) = idx.split();
         if j==0 /* idx.is_pure_train() */ {
 
             let trainkey = self.verify_twigstate::<TrainKey>(idx, &twig) ?;
 
             let (x,y,z,lk) = self.branch.kdf_train(idx,&trainkey);
             linkkey = lk;
-            if let Some((a,b)) = TwigIdx::train_children(j) {
+            if let Some((a,b)) = TwigIdx::train_children(i) {
                 self.insert_twig(TwigIdx::make(a,0), x);
                 self.insert_twig(TwigIdx::make(b,0), y);
             } // Not addressable otherwise so no error needed
@@ -305,6 +403,10 @@ impl<'a> Advance<'a> {
 }
 
 
+/// Clients roll over to a child branch once fewer than this many
+/// twigs remain on their current branch.
+pub const ROLLOVER_REMAINING: TwigIdxT = 1 << 10;
+
 /// A transaction for a user iterating their hash iteration ratchet
 /// by a single step.
 ///
@@ -329,9 +431,65 @@ impl<'a> AdvanceUser<'a> {
         Ok( AdvanceUser(Advance::new(state,bid) ?) )
     }
 
+    /// Begin a transaction on the initial branch derived from `seed`,
+    /// which we create only upon confirmation.
+    pub fn new_initial(state: &'a State, seed: &[u8])
+      -> RatchetResult<AdvanceUser<'a>> {
+        Ok( AdvanceUser(Advance::new_initial(state,seed) ?) )
+    }
+
+    /// Begin a transaction on the branch to which we migrated `bid`,
+    /// first rolling over to a child branch if it nears exhaustion.
+    ///
+    /// We grow the child branch from our most recent berry, so the
+    /// node must have processed the packet that produced that berry.
+    /// If not, then `State::abandon_rollover` lets us roll over again
+    /// from an older berry.  If we hold no berries, then we continue
+    /// on the current branch.  We record the rollover only once we
+    /// confirm our transaction.
+    pub fn new_rollover(state: &'a State, bid: &BranchId)
+      -> RatchetResult<AdvanceUser<'a>> {
+        let bid = state.current_branch(bid) ?;  // PoisonError
+        let branch = {
+            let branches = state.branches.read() ?;  // PoisonError
+            branches.get(&bid).cloned()
+        };
+        // Unconfirmed branches cannot be nearing exhaustion.
+        let branch = if let Some(b) = branch { b } else {
+            return AdvanceUser::new(state,&bid);
+        };
+        if branch.chain.remaining() > ROLLOVER_REMAINING {
+            return AdvanceUser::new(state,&bid);
+        }
+        let berry = {
+            let twigs = state.twigs.read() ?;  // PoisonError
+            twigs.0.iter()
+              .filter( |&(tid,tk)| tid.0 == bid && verify_twigy::<BerryKey>(tid,tk).is_ok() )
+              .map( |(tid,_)| tid.1 ).max()
+        };
+        let berry = if let Some(b) = berry { b } else {
+            return AdvanceUser::new(state,&bid);
+        };
+        let child = BranchId { family: branch.child_family_name(), berry };
+        let mut advance = AdvanceUser::new(state,&child) ?;
+          // PoisonError, BranchAlreadyLocked, MissingParent, MissingTwig, ..
+        advance.0.rollover = Some(bid);
+        Ok(advance)
+    }
+
+    /// Confirm like `Transaction::confirm`, but record that we used
+    /// our branch at `now`, so that garbage collection keeps it.
+    pub fn confirm_at(&mut self, now: SystemTime) -> RatchetResult<()> {
+        self.0.confirm_at(Some(now))
+    }
+
     pub fn click(&mut self, ss: &SphinxSecret) 
       -> RatchetResult<(TwigId,MessageKey)> {
         let cidx = self.0.branch.chain;
+        // We cannot advance beyond our final twig, so never use it.
+        if cidx.increment().is_none() {
+            return Err( RatchetError::ExhaustedBranch(*self.0.branch_id.id()) );
+        }
         let linkkey = self.0.do_chain_step(cidx) ?;
           // .. PoisonError, MissingTwig, WrongTwigType .. ??
         let twig = TwigId(self.0.branch_id.1, cidx);
@@ -340,6 +498,126 @@ impl<'a> AdvanceUser<'a> {
 }
 
 
+/// A combined transaction for a client advancing several ratchets,
+/// perhaps with several issuers, while building one header.
+///
+/// We confirm either every advance or none, and support rolling back
+/// to a savepoint.  We reuse one `AdvanceUser` for repeated clicks
+/// on the same branch, which would otherwise be locked.
+pub struct AdvanceClient<'a> {
+    advances: Vec<AdvanceUser<'a>>,
+
+    /// Index into `advances` along with its prior progress for each
+    /// click, so that we may roll back clicks in reverse order.
+    log: Vec<(usize,AdvanceSavepoint)>,
+}
+
+impl<'a> AdvanceClient<'a> {
+    pub fn new() -> AdvanceClient<'a> {
+        AdvanceClient { advances: Vec::new(), log: Vec::new() }
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.advances.reserve(additional);
+        self.log.reserve(additional);
+    }
+
+    /// Number of clicks so far, which serves as a savepoint.
+    pub fn len(&self) -> usize { self.log.len() }
+
+    /// Click once on the branch to which we migrated `bid` in `state`,
+    /// rolling over to a child branch if necessary.
+    pub fn click(&mut self, state: &'a State, bid: &BranchId, ss: &SphinxSecret)
+      -> RatchetResult<(TwigId,MessageKey)> {
+        let current = state.current_branch(bid) ?;  // PoisonError
+        self.click_on(state, &current, ss, || AdvanceUser::new_rollover(state,bid))
+    }
+
+    /// Click once on the initial branch derived from `seed` in `state`,
+    /// which we create only if we confirm.
+    pub fn click_initial(&mut self, state: &'a State, seed: &[u8], ss: &SphinxSecret)
+      -> RatchetResult<(TwigId,MessageKey)> {
+        let bid = Branch::new_kdf(seed).0;
+        self.click_on(state, &bid, ss, || AdvanceUser::new_initial(state,seed))
+    }
+
+    /// Click once on `current`, reusing our advance on `current` if
+    /// any, or else starting one with `new`.
+    fn click_on<F>(&mut self, state: &'a State, current: &BranchId, ss: &SphinxSecret, new: F)
+      -> RatchetResult<(TwigId,MessageKey)>
+      where F: FnOnce() -> RatchetResult<AdvanceUser<'a>> {
+        // We record rollovers only upon confirmation, so an earlier
+        // click may have rolled `current` over within our transaction.
+        let existing = self.advances.iter().position( |a|
+            (a.0.branch_id.id() == current || a.0.rollover == Some(*current))
+            && a.0.branch_id.0 as *const State == state as *const State
+        );
+        let i = match existing {
+            Some(i) => i,
+            None => {
+                let a = new() ?;
+                  // PoisonError, BranchAlreadyLocked, MissingParent, MissingTwig, ..
+                self.advances.push(a);
+                self.advances.len() - 1
+            },
+        };
+        let savepoint = self.advances[i].0.savepoint();
+        match self.advances[i].click(ss) {
+            Ok(r) => {
+                self.log.push((i,savepoint));
+                Ok(r)
+            },
+            Err(e) => {
+                self.advances[i].0.rollback(savepoint);
+                if existing.is_none() {
+                    if let Some(mut a) = self.advances.pop() { a.forget(); }
+                }
+                Err(e)
+            },
+        }
+    }
+
+    /// Undo every click after the first `len` clicks.
+    pub fn rollback(&mut self, len: usize) {
+        while self.log.len() > len {
+            let (i,savepoint) = self.log.pop().unwrap();
+            self.advances[i].0.rollback(savepoint);
+            // Advances without clicks come last, as they were created last.
+            if i+1 == self.advances.len() && ! self.log.iter().any(|&(j,_)| j == i) {
+                if let Some(mut a) = self.advances.pop() { a.forget(); }
+            }
+        }
+    }
+
+    /// Confirm every advance, or none if any fails, recording that
+    /// we used their branches at `now`.
+    pub fn confirm(&mut self, now: SystemTime) -> RatchetResult<()> {
+        let berries = self.advances.iter().map( |a| a.0.prepare() )
+          .collect::<RatchetResult<Vec<Option<TwigId>>>>() ?;  // PoisonError, MissingParent
+
+        // Take every write lock before writing anything, taking
+        // locks on different states in a consistent order.
+        let mut states: Vec<&'a State> = self.advances.iter().map( |a| {
+            let s: &'a State = a.0.branch_id.0;  s
+        } ).collect();
+        states.sort_by_key( |s| *s as *const State as usize );
+        states.dedup_by_key( |s| *s as *const State as usize );
+        let mut guards = Vec::with_capacity(states.len());
+        for s in states.iter() {
+            guards.push( s.commit_guards() ? );  // PoisonError
+        }
+
+        for (a,berry) in self.advances.iter_mut().zip(berries) {
+            let j = states.iter().position( |s| *s as *const State == a.0.branch_id.0 as *const State )
+              .expect("State missing from commit.");
+            a.0.commit(&mut guards[j], berry, Some(now));
+        }
+        self.log.clear();
+        Ok(())
+    }
+}
+
+
 /// A transaction for a mix node iterating a hash iteration ratchet
 /// as directed by a Sphinx packet.
 ///
@@ -445,14 +723,216 @@ impl<'a> AdvanceNode<'a> {
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use std::time::UNIX_EPOCH;
+    use super::*;
     // use rustc_serialize::hex::ToHex;
 
+    /// Advance `client` by one click and `node` as directed by the
+    /// resulting twig, checking that they agree.
+    fn exchange(client: &State, node: &State, bid: &BranchId, ss: &SphinxSecret) -> TwigId {
+        let mut user = AdvanceUser::new_rollover(client,bid).unwrap();
+        let (twig,k1) = user.click(ss).unwrap();
+        let (mut advance,k2) = AdvanceNode::single_click(node,ss,&twig).unwrap();
+        assert_eq!(k1,k2);
+        user.confirm().unwrap();
+        advance.confirm().unwrap();
+        twig
+    }
+
+    /// Move `bid` near exhaustion identically in `state`.
+    fn skip_ahead(state: &State, bid: &BranchId, idx: TwigIdx) {
+        state.branches.write().unwrap().get_mut(bid).unwrap().chain = idx;
+        state.twigs.write().unwrap().insert(TwigId(*bid,idx), ChainKey::make([9u8; 32]).0);
+    }
+
     #[test]
-    fn need_tests() {
+    fn rollover_to_child_branch() {
+        let client = State::new(HasherState::new());
+        let node = State::new(HasherState::new());
+        let (bid,branch,_,_) = create_initial_branch(&client, b"seed").unwrap();
+        create_initial_branch(&node, b"seed").unwrap();
+        let ss = SphinxSecret([3u8; 32]);
+
+        // Cross several train boundaries.
+        for _ in 0..100 {
+            assert_eq!( exchange(&client,&node,&bid,&ss).0, bid );
+        }
+
+        let near = TwigIdx(TwigIdxT::max_value() - 8);
+        skip_ahead(&client,&bid,near);
+        skip_ahead(&node,&bid,near);
+
+        // We grow the child from our most recent berry.
+        let berry = TwigIdx(TRAIN_START.0 + 99);
+        let twig = exchange(&client,&node,&bid,&ss);
+        let child = BranchId { family: branch.child_family_name(), berry };
+        assert_eq!( twig, TwigId(child,TRAIN_START) );
+        assert_eq!( client.current_branch(&bid).unwrap(), child );
+        // Growing the child consumed the berry on both sides.
+        assert!( ! client.twigs.read().unwrap().contains_key(&TwigId(bid,berry)) );
+        assert!( ! node.twigs.read().unwrap().contains_key(&TwigId(bid,berry)) );
+
+        let twig = exchange(&client,&node,&bid,&ss);
+        assert_eq!( twig, TwigId(child,TwigIdx(TRAIN_START.0+1)) );
     }
-}
 
+    #[test]
+    fn rollover_recorded_on_confirm() {
+        let client = State::new(HasherState::new());
+        let (bid,branch,_,_) = create_initial_branch(&client, b"seed").unwrap();
+        let ss = SphinxSecret([3u8; 32]);
+        let node = State::new(HasherState::new());
+        create_initial_branch(&node, b"seed").unwrap();
+        for _ in 0..2 { exchange(&client,&node,&bid,&ss); }
+        skip_ahead(&client,&bid,TwigIdx(TwigIdxT::max_value() - 8));
+        let child = BranchId { family: branch.child_family_name(), berry: TwigIdx(TRAIN_START.0 + 1) };
+
+        // Abandoned and rolled back rollovers leave no successor.
+        {
+            let mut user = AdvanceUser::new_rollover(&client,&bid).unwrap();
+            assert_eq!( user.click(&ss).unwrap().0 .0, child );
+        }
+        assert_eq!( client.current_branch(&bid).unwrap(), bid );
+        {
+            let mut t = AdvanceClient::new();
+            t.click(&client,&bid,&ss).unwrap();
+            t.rollback(0);
+        }
+        assert_eq!( client.current_branch(&bid).unwrap(), bid );
+
+        // Repeated clicks within one transaction stay on the child.
+        {
+            let mut t = AdvanceClient::new();
+            let (t1,_) = t.click(&client,&bid,&ss).unwrap();
+            let (t2,_) = t.click(&client,&bid,&ss).unwrap();
+            assert_eq!( t1, TwigId(child,TRAIN_START) );
+            assert_eq!( t2, TwigId(child,TwigIdx(TRAIN_START.0 + 1)) );
+            t.confirm(UNIX_EPOCH).unwrap();
+        }
+        assert_eq!( client.current_branch(&bid).unwrap(), child );
+    }
 
+    #[test]
+    fn rollover_recovers_from_lost_berry() {
+        let client = State::new(HasherState::new());
+        let node = State::new(HasherState::new());
+        let (bid,branch,_,_) = create_initial_branch(&client, b"seed").unwrap();
+        create_initial_branch(&node, b"seed").unwrap();
+        let ss = SphinxSecret([3u8; 32]);
+        for _ in 0..3 { exchange(&client,&node,&bid,&ss); }
+
+        // The node never sees the packet producing our latest berry.
+        {
+            let mut user = AdvanceUser::new(&client,&bid).unwrap();
+            user.click(&ss).unwrap();
+            user.confirm().unwrap();
+        }
+        let near = TwigIdx(TwigIdxT::max_value() - 8);
+        skip_ahead(&client,&bid,near);
+        skip_ahead(&node,&bid,near);
+
+        let family = branch.child_family_name();
+        let lost = BranchId { family, berry: TwigIdx(TRAIN_START.0 + 3) };
+        {
+            let mut user = AdvanceUser::new_rollover(&client,&bid).unwrap();
+            let (twig,_) = user.click(&ss).unwrap();
+            assert_eq!( twig, TwigId(lost,TRAIN_START) );
+            assert!( AdvanceNode::single_click(&node,&ss,&twig).is_err() );
+            user.confirm().unwrap();
+        }
+        assert_eq!( client.current_branch(&bid).unwrap(), lost );
+
+        // We abandon the child and roll over from our next berry.
+        assert_eq!( client.abandon_rollover(&bid).unwrap(), Some(lost) );
+        assert_eq!( client.current_branch(&bid).unwrap(), bid );
+        let found = BranchId { family, berry: TwigIdx(TRAIN_START.0 + 2) };
+        assert_eq!( exchange(&client,&node,&bid,&ss), TwigId(found,TRAIN_START) );
+        assert_eq!( client.current_branch(&bid).unwrap(), found );
+        assert_eq!( client.abandon_rollover(&lost).unwrap(), None );
+    }
 
+    #[test]
+    fn combined_client_transaction() {
+        let a = State::new(HasherState::new());
+        let b = State::new(HasherState::new());
+        let (ba,_,_,_) = create_initial_branch(&a, b"a").unwrap();
+        let (bb,_,_,_) = create_initial_branch(&b, b"b").unwrap();
+        let ss = SphinxSecret([3u8; 32]);
+        let chain = |s: &State, bid: &BranchId| -> TwigIdx {
+            s.branches.read().unwrap().get(bid).unwrap().chain
+        };
+        let next = TwigIdx(TRAIN_START.0 + 1);
+
+        {
+            let mut t = AdvanceClient::new();
+            t.click(&a,&ba,&ss).unwrap();
+            let savepoint = t.len();
+            let (t1,_) = t.click(&b,&bb,&ss).unwrap();
+            let (t2,_) = t.click(&b,&bb,&ss).unwrap();
+            assert_eq!( t1, TwigId(bb,TRAIN_START) );
+            assert_eq!( t2, TwigId(bb,next) );
+            t.rollback(savepoint);
+            assert_eq!( t.click(&b,&bb,&ss).unwrap().0, t1 );
+            t.confirm(UNIX_EPOCH).unwrap();
+        }
+        assert_eq!( chain(&a,&ba), next );
+        assert_eq!( chain(&b,&bb), next );
+
+        // A failure confirming one advance confirms none.
+        let family = a.branches.read().unwrap().get(&ba).unwrap().child_family_name();
+        let child = BranchId { family, berry: TRAIN_START };
+        {
+            let mut t = AdvanceClient::new();
+            t.click(&b,&bb,&ss).unwrap();
+            t.click(&a,&child,&ss).unwrap();
+            a.parents.write().unwrap().remove(&family);
+            assert!( t.confirm(UNIX_EPOCH).is_err() );
+        }
+        assert_eq!( chain(&b,&bb), next );
+        assert!( a.branches.read().unwrap().get(&child).is_none() );
+    }
+
+    #[test]
+    fn exhausted_branch() {
+        let state = State::new(HasherState::new());
+        let (bid,_,_,_) = create_initial_branch(&state, b"seed").unwrap();
+        skip_ahead(&state,&bid,TwigIdx(TwigIdxT::max_value()));
+        let mut user = AdvanceUser::new(&state,&bid).unwrap();
+        match user.click(&SphinxSecret([3u8; 32])) {
+            Err(RatchetError::ExhaustedBranch(b)) => assert_eq!(b,bid),
+            _ => panic!("Clicked beyond the final twig"),
+        }
+    }
 
+    /// Regression test for pure train steps deriving their children
+    /// from the chain index, which is always zero, so that every train
+    /// overwrote trains 0 and 1, and clicks failed upon reaching train 2.
+    #[test]
+    fn clicks_cross_train_boundaries() {
+        let client = State::new(HasherState::new());
+        let node = State::new(HasherState::new());
+        let (bid,_,_,_) = create_initial_branch(&client, b"seed").unwrap();
+        create_initial_branch(&node, b"seed").unwrap();
+        let ss = SphinxSecret([3u8; 32]);
+
+        // Trains hold 32 twigs, so we advance into train 4.
+        for k in 0..100 {
+            let mut user = AdvanceUser::new(&client,&bid).unwrap();
+            let (twig,k1) = user.click(&ss).unwrap();
+            assert_eq!( twig, TwigId(bid,TwigIdx(TRAIN_START.0 + k)) );
+            let (mut advance,k2) = AdvanceNode::single_click(&node,&ss,&twig).unwrap();
+            assert_eq!(k1,k2);
+            user.confirm().unwrap();
+            advance.confirm().unwrap();
+        }
+        assert_eq!( TwigIdx(TRAIN_START.0 + 99).split(), (4,3) );
+
+        // Trains 2 through 4 grew their own children.
+        for state in [&client,&node].iter() {
+            let twigs = state.twigs.read().unwrap();
+            for i in 5..10 {
+                assert!( twigs.contains_key(&TwigId(bid,TwigIdx::make(i,0))) );
+            }
+        }
+    }
+}
@@ -97,6 +97,9 @@ impl BranchId {
     }
 }
 
+#[cfg(feature = "serde")]
+impl_serde_via_bytes!(BranchId, BRANCH_ID_LENGTH);
+
 
 /// Branchs grow from leaves and DH key exchanges.  A branch's BranchId
 /// used for storage must be tracked seperately by trasaction objects.
@@ -0,0 +1,344 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Consistency checks for Xolotl ratchet state
+//!
+//! We walk every branch verifying its parent link and that it grows
+//! from its parent's berry whenever that berry survives, and walk
+//! every twig verifying that it inhabits a known branch and has a
+//! type permitted at its index.  We describe each inconsistency by
+//! the `RatchetError` it would cause.
+//!
+//! Repair prunes entries that refer to missing branches, and restores
+//! missing parent links, which `Branch::child_family_name` determines.
+//! We never prune branches or mistyped twigs, as doing so destroys
+//! key material, so these require manual attention.
+
+use std::collections::HashSet;
+use std::ops::AddAssign;
+
+use ::state::{HasherState,Storage};
+use super::branch::*;
+use super::twig::*;
+use super::error::*;
+use super::state::*;
+use super::export::{ExportKey,Imported,import_unchecked,export_state,export_client};
+
+
+/// Results of checking ratchet state.
+#[derive(Debug, Default, Clone)]
+pub struct CheckReport {
+    /// Inconsistencies found, described by the errors they cause.
+    pub problems: Vec<RatchetError>,
+
+    /// Entries pruned by repair
+    pub pruned: usize,
+
+    /// Parent links restored by repair
+    pub restored: usize,
+}
+
+impl AddAssign for CheckReport {
+    fn add_assign(&mut self, other: CheckReport) {
+        self.problems.extend(other.problems);
+        self.pruned += other.pruned;
+        self.restored += other.restored;
+    }
+}
+
+/// Inconsistencies found by `audit`, along with the entries that
+/// repair should prune or restore.
+#[derive(Default)]
+struct Findings {
+    problems: Vec<RatchetError>,
+    missing: HashSet<BranchId>,
+    parents: HashSet<BranchName>,
+    restore: Vec<(BranchName,BranchId)>,
+    twigs: HashSet<TwigId>,
+    successors: HashSet<BranchId>,
+    grown: HashSet<TwigId>,
+    established: HashSet<BranchId>,
+}
+
+impl Findings {
+    /// Report each missing branch only once.
+    fn missing(&mut self, bid: BranchId) {
+        if self.missing.insert(bid) {
+            self.problems.push( RatchetError::MissingBranch(bid) );
+        }
+    }
+}
+
+/// Verify that a twig in a branch with chain index `chain` has a
+/// type permitted at its index.
+///
+/// Only train twigs lie beyond the chain index, and the twig at the
+/// chain index is a chain twig, or a train twig at train positions.
+/// Below the chain index, we find train twigs only at train positions
+/// and chain twigs only elsewhere, along with link and berry twigs.
+fn check_twig(tid: &TwigId, tk: &TwigKey, chain: TwigIdx) -> RatchetResult<()> {
+    let idx = tid.1;
+    let pure = idx.is_pure_train();
+    if idx > chain || (idx == chain && pure) {
+        verify_twigy::<TrainKey>(tid,tk) ?;  // WrongTwigType
+    } else if idx == chain {
+        verify_twigy::<ChainKey>(tid,tk) ?;  // WrongTwigType
+    } else {
+        match TwigState::new(*tk) {
+            TwigState::Train(_) if ! pure => { verify_twigy::<ChainKey>(tid,tk) ?; },
+            TwigState::Chain(_) if pure => { verify_twigy::<TrainKey>(tid,tk) ?; },
+            _ => { },
+        }
+    }
+    Ok(())
+}
+
+fn audit(branches: &BranchStorage, parents: &ParentStorage, twigs: &TwigStorage,
+         successors: &BranchSuccessors, grown: &GrownBerries, established: &BranchLocks)
+  -> Findings {
+    let mut f = Findings::default();
+
+    for (family,bid) in parents.0.iter() {
+        match branches.get(bid) {
+            None => {
+                f.missing(*bid);
+                f.parents.insert(*family);
+            },
+            Some(b) => if b.child_family_name() != *family {
+                f.problems.push( RatchetError::CorruptBranch(*bid, "Parent link has wrong family.") );
+                f.parents.insert(*family);
+            },
+        }
+    }
+
+    for (bid,branch) in branches.0.iter() {
+        let name = branch.child_family_name();
+        if parents.get(&name) != Some(bid) {
+            f.problems.push( RatchetError::CorruptBranch(*bid, "Missing parent link.") );
+            f.restore.push((name,*bid));
+        }
+        // Initial branches and branches whose parent expired have
+        // no parent here, and grown berries normally vanish.
+        let parent = match parents.get(&bid.family) { Some(p) => p, None => continue, };
+        let tid = TwigId(*parent,bid.berry);
+        let (p,tk) = match (branches.get(parent),twigs.get(&tid)) {
+            (Some(p),Some(tk)) => (p,tk),
+            _ => continue,
+        };
+        match verify_twigy::<BerryKey>(&tid,tk) {
+            Ok(bk) => {
+                let (child_bid,child,_) = p.kdf_branch(bid.berry, &bk);
+                if child_bid != *bid || child.extra != branch.extra {
+                    f.problems.push( RatchetError::CorruptBranch(*bid, "Does not grow from its berry.") );
+                }
+            },
+            Err(e) => f.problems.push(e),  // WrongTwigType
+        }
+    }
+
+    for (tid,tk) in twigs.0.iter() {
+        match branches.get(&tid.0) {
+            None => {
+                f.missing(tid.0);
+                f.twigs.insert(*tid);
+            },
+            Some(b) => if let Err(e) = check_twig(tid,tk,b.chain) {
+                f.problems.push(e);
+            },
+        }
+    }
+
+    // We record rollovers only upon confirming their child.
+    for (bid,child) in successors.iter() {
+        if ! branches.contains_key(child) {
+            f.missing(*child);
+            f.successors.insert(*bid);
+        }
+    }
+
+    for (berry,child) in grown.iter() {
+        if ! branches.contains_key(child) {
+            f.missing(*child);
+            f.grown.insert(*berry);
+        } else if child.berry != berry.1 {
+            f.problems.push( RatchetError::CorruptBranch(*child, "Grown from another berry.") );
+            f.grown.insert(*berry);
+        }
+    }
+
+    for bid in established.iter() {
+        if ! branches.contains_key(bid) {
+            f.missing(*bid);
+            f.established.insert(*bid);
+        }
+    }
+
+    f
+}
+
+impl State {
+    /// Check the consistency of the tables we save to disk.
+    pub fn check(&self) -> RatchetResult<CheckReport> {
+        // Lock in the same order as `State::collect_garbage`.
+        let branches = self.branches.read() ?;  // PoisonError
+        let parents = self.parents.read() ?;  // PoisonError
+        let twigs = self.twigs.read() ?;  // PoisonError
+        let successors = self.successors.read() ?;  // PoisonError
+        let grown = self.grown.read() ?;  // PoisonError
+        let established = self.established.read() ?;  // PoisonError
+
+        let f = audit(&branches, &parents, &twigs, &successors, &grown, &established);
+        Ok( CheckReport { problems: f.problems, pruned: 0, restored: 0 } )
+    }
+
+    /// Check the consistency of the tables we save to disk, pruning
+    /// entries that refer to missing branches and restoring missing
+    /// parent links.  We report the problems found before repair.
+    ///
+    /// Like `State::collect_garbage`, we never touch branches locked
+    /// by an ongoing transaction, and block new transactions until
+    /// we finish.
+    pub fn repair(&self) -> RatchetResult<CheckReport> {
+        let locked = self.locked.read() ?;  // PoisonError
+        // We never prune branches, so a read lock suffices.
+        let branches = self.branches.read() ?;  // PoisonError
+        let mut parents = self.parents.write() ?;  // PoisonError
+        let mut twigs = self.twigs.write() ?;  // PoisonError
+        let mut successors = self.successors.write() ?;  // PoisonError
+        let mut grown = self.grown.write() ?;  // PoisonError
+        let mut established = self.established.write() ?;  // PoisonError
+
+        let Findings {
+            problems,
+            parents: bad_parents,
+            restore,
+            twigs: bad_twigs,
+            successors: bad_successors,
+            grown: bad_grown,
+            established: bad_established,
+            ..
+        } = audit(&branches, &parents, &twigs, &successors, &grown, &established);
+        let mut report = CheckReport { problems, pruned: 0, restored: 0 };
+
+        let l = parents.len() + twigs.len() + successors.len() + grown.len() + established.len();
+        parents.retain( |family,bid| ! bad_parents.contains(family) || locked.contains(bid) );
+        twigs.retain( |tid,_| ! bad_twigs.contains(tid) || locked.contains(&tid.0) );
+        successors.retain( |bid,child| ! bad_successors.contains(bid) || locked.contains(child) );
+        grown.retain( |berry,child| ! bad_grown.contains(berry) || locked.contains(child) );
+        established.retain( |bid| ! bad_established.contains(bid) || locked.contains(bid) );
+        report.pruned = l - (parents.len() + twigs.len() + successors.len() + grown.len() + established.len());
+
+        for (family,bid) in restore {
+            if ! parents.contains_key(&family) && branches.contains_key(&bid) {
+                parents.insert(family,bid);
+                report.restored += 1;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Check every issuer relationship in `client`, and repair them too
+/// if `repair` is set.
+pub fn check_client(client: &ClientState, repair: bool) -> RatchetResult<CheckReport> {
+    let mut report = CheckReport::default();
+    for state in client.values() {
+        let r = if repair { state.repair() } else { state.check() };
+        report += r ?;  // PoisonError
+    }
+    Ok(report)
+}
+
+/// Check ratchet state exported under `key` by either `export_state`
+/// or `export_client`, and repair it too if `repair` is set, in which
+/// case we return a new export of the repaired state.
+pub fn check_export(key: &ExportKey, export: &[u8], repair: bool)
+  -> RatchetResult<(CheckReport,Option<Vec<u8>>)> {
+    match import_unchecked(key, export, HasherState::new()) ? {
+        // BadExport, UnsupportedExport
+        Imported::State(state) => {
+            if ! repair { return Ok(( state.check()?, None )); }
+            let report = state.repair() ?;  // PoisonError
+            Ok(( report, Some(export_state(key, &state)?) ))
+        },
+        Imported::Client(client) => {
+            let report = check_client(&client, repair) ?;  // PoisonError
+            let export = if repair { Some(export_client(key, &client)?) } else { None };
+            Ok(( report, export ))
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::advance::{Transaction,AdvanceUser,AdvanceNode};
+    use ::sphinx::SphinxSecret;
+
+    #[test]
+    fn consistent_after_exchanges() {
+        let client = State::new(HasherState::new());
+        let node = State::new(HasherState::new());
+        let (bid,_,_,_) = create_initial_branch(&client, b"check").unwrap();
+        create_initial_branch(&node, b"check").unwrap();
+
+        // The node skips ahead, including across trains, and then
+        // receives a packet out of order.
+        let mut twigs = Vec::new();
+        for i in 0..70 {
+            let ss = SphinxSecret([i as u8; 32]);
+            let mut user = AdvanceUser::new(&client, &bid).unwrap();
+            twigs.push((user.click(&ss).unwrap().0,ss));
+            user.confirm().unwrap();
+        }
+        let mut order: Vec<usize> = (0..twigs.len()).filter( |i| i % 3 == 0 ).collect();
+        order.push(twigs.len()-2);
+        for i in order {
+            let (ref twig,ref ss) = twigs[i];
+            let (mut advance,_) = AdvanceNode::single_click(&node, ss, twig).unwrap();
+            advance.confirm().unwrap();
+        }
+
+        let family = client.branches.read().unwrap().0[&bid].child_family_name();
+        let child = BranchId { family, berry: (twigs[5].0).1 };
+        let mut user = AdvanceUser::new(&client, &child).unwrap();
+        user.click(&SphinxSecret([1u8; 32])).unwrap();
+        user.confirm().unwrap();
+        client.establish(&bid).unwrap();
+
+        for state in [&client,&node].iter() {
+            let report = state.check().unwrap();
+            assert!( report.problems.is_empty(), "{:?}", report.problems );
+        }
+    }
+
+    #[test]
+    fn repairs_dangling_entries() {
+        let state = State::new(HasherState::new());
+        let (bid,branch,_,_) = create_initial_branch(&state, b"check").unwrap();
+        let mut advance = AdvanceUser::new(&state, &bid).unwrap();
+        advance.click(&SphinxSecret([1u8; 32])).unwrap();
+        advance.confirm().unwrap();
+
+        let gone = BranchId { family: BranchName([3u8; BRANCH_NAME_LENGTH]), berry: TwigIdx(7) };
+        state.twigs.write().unwrap().insert(TwigId(gone,TRAIN_START), TrainKey::make([2u8; 32]).0);
+        state.twigs.write().unwrap().insert(TwigId(bid,TwigIdx(0x1000)), LinkKey::make([2u8; 32]).0);
+        state.parents.write().unwrap().remove(&branch.child_family_name());
+        state.establish(&gone).unwrap();
+
+        let report = state.check().unwrap();
+        assert_eq!( report.problems.len(), 3 );
+        assert_eq!( report.pruned, 0 );
+        let report = state.repair().unwrap();
+        assert_eq!( report.problems.len(), 3 );
+        assert_eq!( (report.pruned,report.restored), (2,1) );
+
+        // We keep the mistyped twig for manual attention.
+        let report = state.check().unwrap();
+        assert_eq!( report.problems.len(), 1 );
+        match report.problems[0] {
+            RatchetError::WrongTwigType(tid,_,_) => assert_eq!(tid, TwigId(bid,TwigIdx(0x1000))),
+            ref p => panic!("Unexpected problem {:?}", p),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Client management of ratchet branches with issuers
+//!
+//! A client bootstraps a branch with a node by deriving its seed
+//! from the Sphinx shared secret of some packet sent through that
+//! node, and advancing the new branch in the same packet.  A node
+//! recognizes a bootstrap when a `Command::Ratchet` names exactly the
+//! branch derived from its own shared secret.
+//!
+//! We cannot know if the node ever received our bootstrap packet,
+//! so clients should mark branches established once they receive an
+//! acknowledgement, and only select established branches.
+
+use std::time::SystemTime;
+
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+
+use keys::IssuerPublicKey;
+use ::sphinx::SphinxSecret;
+use ::state::{HasherState,Storage};
+use super::MessageKey;
+use super::branch::*;
+use super::twig::TwigId;
+use super::advance::AdvanceClient;
+use super::error::*;
+use super::state::*;
+
+
+/// Derive the seed for bootstrapping a branch from a Sphinx
+/// shared secret, or hybrid key.
+fn bootstrap_seed(ss: &SphinxSecret) -> [u8; 32] {
+    let mut r = [0u8; 32];
+    let mut sha = Sha3::sha3_256();
+    sha.input_str( "Xolotl ratchet bootstrap" );
+    sha.input(&ss.0);
+    sha.result(&mut r);
+    sha.reset();
+    r
+}
+
+/// Identify the branch bootstrapped from `ss`.
+pub fn bootstrap_branch_id(ss: &SphinxSecret) -> BranchId {
+    Branch::new_kdf(&bootstrap_seed(ss)).0
+}
+
+/// Create the branch bootstrapped from `ss`, unless it already exists.
+pub fn bootstrap_branch(state: &State, ss: &SphinxSecret) -> RatchetResult<BranchId> {
+    match create_initial_branch(state, &bootstrap_seed(ss)) {
+        Ok((bid,_,_,_)) => Ok(bid),
+        Err(RatchetError::BranchAlreadyExists(bid)) => Ok(bid),
+        Err(e) => Err(e),  // PoisonError, BranchAlreadyLocked
+    }
+}
+
+impl<'a> AdvanceClient<'a> {
+    /// Click once on the branch bootstrapped from `ss` in `state`,
+    /// which we create only if we confirm, so that abandoning our
+    /// header leaves nothing behind.
+    pub fn bootstrap(&mut self, state: &'a State, ss: &SphinxSecret)
+      -> RatchetResult<(TwigId,MessageKey)> {
+        self.click_initial(state, &bootstrap_seed(ss), ss)
+    }
+}
+
+/// Find or create our ratchet state with `issuer`.
+pub fn issuer_state<'a>(client: &'a mut ClientState, issuer: &IssuerPublicKey, hs: HasherState)
+  -> &'a State {
+    client.entry(*issuer).or_insert_with( || State::new(hs) )
+}
+
+impl State {
+    /// Record that the other party holds `bid`, perhaps because they
+    /// acknowledged the packet that bootstrapped it.
+    pub fn establish(&self, bid: &BranchId) -> RatchetResult<()> {
+        let mut established = self.established.write() ?;  // PoisonError
+        established.insert(*bid);
+        Ok(())
+    }
+
+    /// Is `bid` established with the other party?
+    pub fn is_established(&self, bid: &BranchId) -> RatchetResult<bool> {
+        Ok( self.established.read()?.contains(bid) )  // PoisonError
+    }
+
+    /// Identify the child branch grown from `berry`, if any.
+    pub fn grown_from(&self, berry: &TwigId) -> RatchetResult<Option<BranchId>> {
+        Ok( self.grown.read()?.get(berry).cloned() )  // PoisonError
+    }
+
+    /// Select our best established branch for `Instruction::Ratchet`,
+    /// which is the one used most recently, preferring those with
+    /// more twigs remaining among branches never used.
+    ///
+    /// We return the established branch, not any child to which it
+    /// rolled over, as `AdvanceUser::new_rollover` follows rollovers.
+    pub fn best_branch(&self) -> RatchetResult<Option<BranchId>> {
+        // Release established before taking other locks, which
+        // `State::collect_garbage` takes first.
+        let established: Vec<BranchId> = self.established.read()?  // PoisonError
+          .iter().cloned().collect();
+        let branches = self.branches.read() ?;  // PoisonError
+        let used = self.used.read() ?;  // PoisonError
+        let mut best = None;
+        for bid in established.iter() {
+            let current = self.current_branch(bid) ?;  // PoisonError
+            let remaining = match branches.get(&current) {
+                Some(b) => b.chain.remaining(),
+                // Our child vanished, perhaps in a partial import.
+                None => match branches.get(bid) {
+                    Some(b) => b.chain.remaining(),
+                    None => continue,
+                },
+            };
+            let time = used.get(&current).or(used.get(bid)).cloned()
+              .unwrap_or(::std::time::UNIX_EPOCH);
+            let key: (SystemTime,_) = (time,remaining);
+            if best.as_ref().map_or(true, |&(k,_)| key > k) {
+                best = Some((key,*bid));
+            }
+        }
+        Ok( best.map(|(_,bid)| bid) )
+    }
+}
+
+/// Select our best established branch with `issuer`, if any.
+pub fn best_branch(client: &ClientState, issuer: &IssuerPublicKey)
+  -> RatchetResult<Option<BranchId>> {
+    match client.get(issuer) {
+        Some(state) => state.best_branch(),
+        None => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::advance::{Transaction,AdvanceUser,AdvanceNode};
+
+    #[test]
+    fn bootstrap_and_select() {
+        let client = State::new(HasherState::new());
+        let node = State::new(HasherState::new());
+        let ss = SphinxSecret([5u8; 32]);
+
+        assert_eq!( client.best_branch().unwrap(), None );
+        let bid = bootstrap_branch(&client, &ss).unwrap();
+        assert_eq!( bid, bootstrap_branch_id(&ss) );
+        assert_eq!( bootstrap_branch(&client, &ss).unwrap(), bid );
+        assert_eq!( bootstrap_branch(&node, &ss).unwrap(), bid );
+
+        let mut user = AdvanceUser::new(&client, &bid).unwrap();
+        let (twig,k1) = user.click(&ss).unwrap();
+        let (mut advance,k2) = AdvanceNode::single_click(&node, &ss, &twig).unwrap();
+        assert_eq!(k1,k2);
+        user.confirm().unwrap();
+        advance.confirm().unwrap();
+
+        // Unacknowledged bootstraps are never selected.
+        assert_eq!( client.best_branch().unwrap(), None );
+        client.establish(&bid).unwrap();
+        assert_eq!( client.best_branch().unwrap(), Some(bid) );
+
+        let child = BranchId { family: client.branches.read().unwrap().get(&bid).unwrap().child_family_name(), berry: twig.1 };
+        assert_eq!( client.grown_from(&twig).unwrap(), None );
+        let mut user = AdvanceUser::new(&client, &child).unwrap();
+        user.click(&ss).unwrap();
+        user.confirm().unwrap();
+        assert_eq!( client.grown_from(&twig).unwrap(), Some(child) );
+    }
+
+    #[test]
+    fn best_branch_prefers_recent_use() {
+        let client = State::new(HasherState::new());
+        let start = ::std::time::UNIX_EPOCH;
+        let hour = ::std::time::Duration::from_secs(60*60);
+        let ss = [SphinxSecret([5u8; 32]), SphinxSecret([6u8; 32])];
+        let bids: Vec<BranchId> = ss.iter().map( |ss| bootstrap_branch(&client, ss).unwrap() ).collect();
+        for bid in bids.iter() { client.establish(bid).unwrap(); }
+        let click = |i: usize, now: SystemTime| {
+            let mut user = AdvanceUser::new(&client, &bids[i]).unwrap();
+            user.click(&ss[i]).unwrap();
+            user.confirm_at(now).unwrap();
+        };
+
+        click(0, start + hour);
+        click(1, start + 2*hour);
+        assert_eq!( client.best_branch().unwrap(), Some(bids[1]) );
+        click(0, start + 3*hour);
+        assert_eq!( client.best_branch().unwrap(), Some(bids[0]) );
+    }
+}
@@ -17,7 +17,7 @@ use super::state::*;
 
 #[derive(Debug, Clone)]
 pub enum RatchetError {
-    // InternalError(&'static str),
+    InternalError(&'static str),
     PoisonError(&'static str,&'static str),
     BranchAlreadyLocked(BranchId),
     MissingTwig(TwigId),
@@ -25,6 +25,10 @@ pub enum RatchetError {
     MissingBranch(BranchId),
     MissingParent(BranchName),
     CorruptBranch(BranchId, &'static str),
+    ExhaustedBranch(BranchId),
+    BranchAlreadyExists(BranchId),
+    BadExport(&'static str),
+    UnsupportedExport(u8),
 }
 
 pub type RatchetResult<T> = Result<T,RatchetError>;
@@ -33,6 +37,8 @@ impl fmt::Display for RatchetError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::RatchetError::*;
         match *self {
+            InternalError(s)
+                => write!(f, "Internal error: {}", s),
             PoisonError(l,t)
                 => write!(f, "Internal error: PoisonError< {}<'_,{}> >.", l, t),
             BranchAlreadyLocked(bid)
@@ -47,6 +53,14 @@ impl fmt::Display for RatchetError {
                 => write!(f, "Missing parent branch {}", bn),
             CorruptBranch(s,bid)
                 => write!(f, "Found corrupted branch {} {}.", bid, s),
+            ExhaustedBranch(bid)
+                => write!(f, "Branch {} has no twigs remaining.", bid),
+            BranchAlreadyExists(bid)
+                => write!(f, "Branch {} already exists.", bid),
+            BadExport(s)
+                => write!(f, "Bad ratchet export: {}", s),
+            UnsupportedExport(v)
+                => write!(f, "Unsupported ratchet export version {}.", v),
         }
     }
 }
@@ -59,6 +73,7 @@ impl Error for RatchetError {
     fn cause(&self) -> Option<&Error> {
         use self::RatchetError::*;
         match *self {
+            InternalError(_) => None,
             PoisonError(_,_) => None, // Maybe here
             BranchAlreadyLocked(_) => None,
             MissingTwig(_) => None,
@@ -66,6 +81,10 @@ impl Error for RatchetError {
             MissingBranch(_) => None,
             MissingParent(_) => None,
             CorruptBranch(_,_) => None,
+            ExhaustedBranch(_) => None,
+            BranchAlreadyExists(_) => None,
+            BadExport(_) => None,
+            UnsupportedExport(_) => None,
         }
     }
 }
@@ -96,5 +115,8 @@ impl_XolotlPoisonError!(TwigStorage);
 impl_XolotlPoisonError!(BranchLocks);
 impl_XolotlPoisonError!(AdvanceFailCache);
 impl_XolotlPoisonError!(AdvanceDropErrors);
+impl_XolotlPoisonError!(BranchUsage);
+impl_XolotlPoisonError!(BranchSuccessors);
+impl_XolotlPoisonError!(GrownBerries);
 
 
@@ -0,0 +1,477 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Export and import of Xolotl ratchet state
+//!
+//! We export the tables `State` saves to disk, namely branches,
+//! parents, twigs, successors, grown berries, and established
+//! branches, but not locks, usage times, or cached failures.
+//! A client exports all its issuer relationships together.
+//!
+//! An export consists of a version byte, a kind byte, and a random
+//! salt, all in the clear, followed by the tables encrypted with
+//! IETF ChaCha20, and a poly1305 MAC over everything.  We derive
+//! the ChaCha20 key and nonce and the poly1305 key from an
+//! `ExportKey` and the cleartext header using SHAKE256.
+//!
+//! Importing validates that our tables are consistent with
+//! `State::check`, so that exports from buggy or malicious sources
+//! cannot corrupt state.
+
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+use crypto::mac::Mac;
+use crypto::poly1305::Poly1305;
+
+use chacha::ChaCha as ChaCha20;
+use keystream::KeyStream;
+use rand::{OsRng, Rng};
+
+use keys::IssuerPublicKey;
+use ::state::{HasherState,Storage};
+use super::branch::*;
+use super::twig::*;
+use super::error::*;
+use super::state::*;
+
+
+/// Current version of our export format.
+pub const EXPORT_VERSION: u8 = 1;
+
+/// Kind byte for an export of one `State`.
+const EXPORT_STATE: u8 = b'S';
+
+/// Kind byte for an export of a `ClientState`.
+const EXPORT_CLIENT: u8 = b'C';
+
+const EXPORT_SALT_LENGTH: usize = 32;
+const EXPORT_HEADER_LENGTH: usize = 2 + EXPORT_SALT_LENGTH;
+const EXPORT_TAG_LENGTH: usize = 16;
+
+/// Secret symmetric key with which we encrypt and authenticate
+/// exported ratchet state.
+// #[never_forget]
+#[derive(Clone)]
+pub struct ExportKey(pub [u8; 32]);
+
+/// Hook to upgrade the decrypted tables of an export from an older
+/// version to `EXPORT_VERSION`.
+pub type Migration = fn(u8, Vec<u8>) -> RatchetResult<Vec<u8>>;
+
+/// Upgrade the decrypted tables of an export from `version` to
+/// `EXPORT_VERSION`.
+///
+/// We add an arm here whenever our format changes, say if `TwigIdx`
+/// changes its layout or our KDFs change their labels.  Each arm
+/// should upgrade its version to the next and recurse.
+pub fn migrate(version: u8, tables: Vec<u8>) -> RatchetResult<Vec<u8>> {
+    match version {
+        EXPORT_VERSION => Ok(tables),
+        v => Err( RatchetError::UnsupportedExport(v) ),
+    }
+}
+
+/// Derive our stream cipher and poly1305 key for an export.
+fn export_cipher(key: &ExportKey, header: &[u8]) -> (ChaCha20,[u8; 32]) {
+    let r = &mut [0u8; 32+12+32];  // ClearOnDrop
+    let mut sha = Sha3::shake_256();
+    sha.input_str( "Xolotl ratchet export" );
+    sha.input(&key.0);
+    sha.input(header);
+    sha.result(r);
+    sha.reset();
+    let (k,nonce,poly) = array_refs![r,32,12,32];
+    (ChaCha20::new_ietf(k,nonce), *poly)
+    // TODO Zero r
+}
+
+fn export_tag(poly_key: &[u8; 32], data: &[u8]) -> [u8; EXPORT_TAG_LENGTH] {
+    let mut tag = [0u8; EXPORT_TAG_LENGTH];
+    let mut poly = Poly1305::new(poly_key);
+    poly.input(data);
+    poly.raw_result(&mut tag);
+    poly.reset();
+    tag
+}
+
+/// Encrypt and authenticate `tables` as an export of the given kind.
+fn seal(key: &ExportKey, kind: u8, tables: Vec<u8>) -> RatchetResult<Vec<u8>> {
+    let mut salt = [0u8; EXPORT_SALT_LENGTH];
+    OsRng::new()
+      .map_err( |_| RatchetError::InternalError("Failed to create an OS RNG") ) ?
+      .fill_bytes(&mut salt);
+    let mut r = Vec::with_capacity(EXPORT_HEADER_LENGTH + tables.len() + EXPORT_TAG_LENGTH);
+    r.push(EXPORT_VERSION);
+    r.push(kind);
+    r.extend_from_slice(&salt);
+    r.extend_from_slice(&tables);
+    // TODO Zero tables
+    let (mut chacha,poly_key) = export_cipher(key, &r[..EXPORT_HEADER_LENGTH]);
+    chacha.xor_read(&mut r[EXPORT_HEADER_LENGTH..])
+      .map_err( |_| RatchetError::BadExport("Too large.") ) ?;
+    let tag = export_tag(&poly_key, &r);
+    r.extend_from_slice(&tag);
+    Ok(r)
+}
+
+/// Authenticate and decrypt an export of the given kind, and then
+/// upgrade it to our current version with `migration`.
+fn open(key: &ExportKey, kind: u8, export: &[u8], migration: Migration)
+  -> RatchetResult<Vec<u8>> {
+    if export.len() < EXPORT_HEADER_LENGTH + EXPORT_TAG_LENGTH {
+        return Err( RatchetError::BadExport("Truncated.") );
+    }
+    let (data,tag) = export.split_at(export.len() - EXPORT_TAG_LENGTH);
+    let (header,body) = data.split_at(EXPORT_HEADER_LENGTH);
+    let (mut chacha,poly_key) = export_cipher(key, header);
+    if ! ::consistenttime::ct_u8_slice_eq(&export_tag(&poly_key, data), tag) {
+        return Err( RatchetError::BadExport("Invalid MAC.  Wrong key?") );
+    }
+    if header[1] != kind {
+        return Err( RatchetError::BadExport("Wrong kind of export.") );
+    }
+    let mut tables = body.to_vec();
+    chacha.xor_read(&mut tables)
+      .map_err( |_| RatchetError::BadExport("Too large.") ) ?;
+    migration(header[0], tables)
+}
+
+
+fn put_count(r: &mut Vec<u8>, n: usize) {
+    assert!(n <= u32::max_value() as usize);
+    let n = n as u32;
+    r.extend_from_slice(&[ n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8 ]);
+}
+
+/// Append the tables of `state` that we save to disk.
+fn put_state(r: &mut Vec<u8>, state: &State) -> RatchetResult<()> {
+    // Lock in the same order as `State::collect_garbage`.
+    let branches = state.branches.read() ?;  // PoisonError
+    let parents = state.parents.read() ?;  // PoisonError
+    let twigs = state.twigs.read() ?;  // PoisonError
+    let successors = state.successors.read() ?;  // PoisonError
+    let grown = state.grown.read() ?;  // PoisonError
+    let established = state.established.read() ?;  // PoisonError
+
+    put_count(r, branches.len());
+    for (bid,branch) in branches.0.iter() {
+        r.extend_from_slice(&bid.to_bytes());
+        r.extend_from_slice(&branch.extra.0);
+        r.extend_from_slice(&branch.chain.to_bytes());
+    }
+    put_count(r, parents.len());
+    for (family,bid) in parents.0.iter() {
+        r.extend_from_slice(&family.0);
+        r.extend_from_slice(&bid.to_bytes());
+    }
+    put_count(r, twigs.len());
+    for (tid,tk) in twigs.0.iter() {
+        r.extend_from_slice(&tid.to_bytes());
+        r.extend_from_slice(tk);
+    }
+    put_count(r, successors.len());
+    for (bid,child) in successors.iter() {
+        r.extend_from_slice(&bid.to_bytes());
+        r.extend_from_slice(&child.to_bytes());
+    }
+    put_count(r, grown.len());
+    for (berry,child) in grown.iter() {
+        r.extend_from_slice(&berry.to_bytes());
+        r.extend_from_slice(&child.to_bytes());
+    }
+    put_count(r, established.len());
+    for bid in established.iter() {
+        r.extend_from_slice(&bid.to_bytes());
+    }
+    Ok(())
+}
+
+/// Cursor over decrypted tables.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> RatchetResult<&'a [u8]> {
+        if self.0.len() < n {
+            return Err( RatchetError::BadExport("Truncated tables.") );
+        }
+        let (a,b) = self.0.split_at(n);
+        self.0 = b;
+        Ok(a)
+    }
+
+    fn count(&mut self) -> RatchetResult<usize> {
+        let b = self.take(4) ?;
+        Ok( (b[0] as usize) | (b[1] as usize) << 8 | (b[2] as usize) << 16 | (b[3] as usize) << 24 )
+    }
+
+    fn branch_id(&mut self) -> RatchetResult<BranchId> {
+        let b = self.take(BRANCH_ID_LENGTH) ?;
+        Ok( BranchId::from_bytes(array_ref![b,0,BRANCH_ID_LENGTH]) )
+    }
+
+    fn twig_id(&mut self) -> RatchetResult<TwigId> {
+        let b = self.take(TWIG_ID_LENGTH) ?;
+        Ok( TwigId::from_bytes(array_ref![b,0,TWIG_ID_LENGTH]) )
+    }
+
+    fn key(&mut self) -> RatchetResult<[u8; 32]> {
+        let b = self.take(32) ?;
+        Ok( *array_ref![b,0,32] )
+    }
+
+    fn twig_idx(&mut self) -> RatchetResult<TwigIdx> {
+        let b = self.take(2) ?;
+        Ok( TwigIdx::from_bytes(*array_ref![b,0,2]) )
+    }
+
+    fn branch_name(&mut self) -> RatchetResult<BranchName> {
+        let b = self.take(BRANCH_NAME_LENGTH) ?;
+        Ok( BranchName(*array_ref![b,0,BRANCH_NAME_LENGTH]) )
+    }
+
+    /// Read the tables `put_state` wrote into a new `State`.
+    fn state(&mut self, hs: HasherState) -> RatchetResult<State> {
+        let state = State::new(hs);
+        {
+        let mut branches = state.branches.write() ?;  // PoisonError
+        let mut parents = state.parents.write() ?;  // PoisonError
+        let mut twigs = state.twigs.write() ?;  // PoisonError
+        let mut successors = state.successors.write() ?;  // PoisonError
+        let mut grown = state.grown.write() ?;  // PoisonError
+        let mut established = state.established.write() ?;  // PoisonError
+
+        for _ in 0..self.count()? {
+            let bid = self.branch_id() ?;
+            let extra = ExtraKey(self.key()?);
+            let chain = self.twig_idx() ?;
+            branches.insert(bid, Branch { extra, chain });
+        }
+        for _ in 0..self.count()? {
+            let family = self.branch_name() ?;
+            parents.insert(family, self.branch_id()?);
+        }
+        for _ in 0..self.count()? {
+            let tid = self.twig_id() ?;
+            twigs.insert(tid, self.key()?);
+        }
+        for _ in 0..self.count()? {
+            let bid = self.branch_id() ?;
+            successors.insert(bid, self.branch_id()?);
+        }
+        for _ in 0..self.count()? {
+            let berry = self.twig_id() ?;
+            grown.insert(berry, self.branch_id()?);
+        }
+        for _ in 0..self.count()? {
+            established.insert(self.branch_id()?);
+        }
+        }
+        Ok(state)
+    }
+
+    /// Read the issuer relationships `export_client` wrote into a
+    /// new `ClientState`.
+    fn client(&mut self, hs: HasherState) -> RatchetResult<ClientState> {
+        let mut client = ClientState::new();
+        for _ in 0..self.count()? {
+            let issuer = IssuerPublicKey(self.key()?);
+            let state = self.state(hs) ?;
+            if client.insert(issuer, state).is_some() {
+                return Err( RatchetError::BadExport("Duplicate issuer.") );
+            }
+        }
+        Ok(client)
+    }
+
+    /// Check that the `TwigIdx` layout matches ours.
+    fn layout(&mut self) -> RatchetResult<()> {
+        let b = self.take(1) ?;
+        if b[0] != CHAIN_V_TRAIN_WIDTH {
+            return Err( RatchetError::BadExport("TwigIdx layout differs.  Missing migration?") );
+        }
+        Ok(())
+    }
+
+    fn done(&self) -> RatchetResult<()> {
+        if self.0.len() > 0 {
+            return Err( RatchetError::BadExport("Trailing bytes.") );
+        }
+        Ok(())
+    }
+}
+
+/// Check that imported tables are consistent using `State::check`,
+/// returning the first problem found.
+fn validate(state: &State) -> RatchetResult<()> {
+    match state.check()?.problems.into_iter().next() {  // PoisonError
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Ratchet state imported by `import_unchecked`.
+pub enum Imported {
+    State(State),
+    Client(ClientState),
+}
+
+/// Import an export of either kind without checking its consistency,
+/// so that `check_export` may examine and repair it.
+pub fn import_unchecked(key: &ExportKey, export: &[u8], hs: HasherState)
+  -> RatchetResult<Imported> {
+    // We authenticate this kind byte in `open`.
+    let kind = if export.len() > 1 { export[1] } else { EXPORT_STATE };
+    let tables = open(key, kind, export, migrate) ?;
+    let mut r = Reader(&tables);
+    r.layout() ?;
+    let imported = match kind {
+        EXPORT_CLIENT => Imported::Client( r.client(hs)? ),
+        EXPORT_STATE => Imported::State( r.state(hs)? ),
+        _ => return Err( RatchetError::BadExport("Unknown kind of export.") ),
+    };
+    r.done() ?;
+    Ok(imported)
+}
+
+
+/// Export `state` encrypted under `key`.
+pub fn export_state(key: &ExportKey, state: &State) -> RatchetResult<Vec<u8>> {
+    let mut tables = vec![CHAIN_V_TRAIN_WIDTH];
+    put_state(&mut tables, state) ?;  // PoisonError
+    seal(key, EXPORT_STATE, tables)
+}
+
+/// Import a `State` exported by `export_state` under `key`,
+/// upgrading older versions with `migrate`.
+pub fn import_state(key: &ExportKey, export: &[u8], hs: HasherState)
+  -> RatchetResult<State> {
+    import_state_with(key, export, hs, migrate)
+}
+
+/// Import a `State` exported by `export_state` under `key`,
+/// upgrading older versions with `migration`.
+pub fn import_state_with(key: &ExportKey, export: &[u8], hs: HasherState, migration: Migration)
+  -> RatchetResult<State> {
+    let tables = open(key, EXPORT_STATE, export, migration) ?;
+    let mut r = Reader(&tables);
+    r.layout() ?;
+    let state = r.state(hs) ?;
+    r.done() ?;
+    validate(&state) ?;
+    Ok(state)
+}
+
+/// Export all issuer relationships in `client` encrypted under `key`.
+pub fn export_client(key: &ExportKey, client: &ClientState) -> RatchetResult<Vec<u8>> {
+    let mut tables = vec![CHAIN_V_TRAIN_WIDTH];
+    put_count(&mut tables, client.len());
+    for (issuer,state) in client.iter() {
+        tables.extend_from_slice(&issuer.0);
+        put_state(&mut tables, state) ?;  // PoisonError
+    }
+    seal(key, EXPORT_CLIENT, tables)
+}
+
+/// Import a `ClientState` exported by `export_client` under `key`,
+/// upgrading older versions with `migrate`.
+pub fn import_client(key: &ExportKey, export: &[u8], hs: HasherState)
+  -> RatchetResult<ClientState> {
+    import_client_with(key, export, hs, migrate)
+}
+
+/// Import a `ClientState` exported by `export_client` under `key`,
+/// upgrading older versions with `migration`.
+pub fn import_client_with(key: &ExportKey, export: &[u8], hs: HasherState, migration: Migration)
+  -> RatchetResult<ClientState> {
+    let tables = open(key, EXPORT_CLIENT, export, migration) ?;
+    let mut r = Reader(&tables);
+    r.layout() ?;
+    let client = r.client(hs) ?;
+    r.done() ?;
+    for state in client.values() {
+        validate(state) ?;
+    }
+    Ok(client)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::advance::{Transaction,AdvanceUser};
+    use super::super::client::issuer_state;
+    use ::sphinx::SphinxSecret;
+
+    fn populated() -> (State,BranchId,BranchId) {
+        let state = State::new(HasherState::new());
+        let (bid,_,_,_) = create_initial_branch(&state, b"export").unwrap();
+        let mut advance = AdvanceUser::new(&state, &bid).unwrap();
+        let (berry,_) = advance.click(&SphinxSecret([1u8; 32])).unwrap();
+        advance.click(&SphinxSecret([2u8; 32])).unwrap();
+        advance.confirm().unwrap();
+        let family = state.branches.read().unwrap().0[&bid].child_family_name();
+        let child = BranchId { family, berry: berry.1 };
+        let mut advance = AdvanceUser::new(&state, &child).unwrap();
+        advance.click(&SphinxSecret([3u8; 32])).unwrap();
+        advance.confirm().unwrap();
+        state.establish(&bid).unwrap();
+        (state,bid,child)
+    }
+
+    fn next_key(state: &State, bid: &BranchId) -> super::super::MessageKey {
+        let mut advance = AdvanceUser::new(state, bid).unwrap();
+        advance.click(&SphinxSecret([4u8; 32])).unwrap().1
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let key = ExportKey([9u8; 32]);
+        let (state,bid,child) = populated();
+        let export = export_state(&key, &state).unwrap();
+        let imported = import_state(&key, &export, HasherState::new()).unwrap();
+
+        assert_eq!( imported.twigs.read().unwrap().len(), state.twigs.read().unwrap().len() );
+        assert_eq!( imported.parents.read().unwrap().len(), state.parents.read().unwrap().len() );
+        assert_eq!( *imported.grown.read().unwrap(), *state.grown.read().unwrap() );
+        assert!( imported.is_established(&bid).unwrap() );
+        assert_eq!( next_key(&imported, &bid), next_key(&state, &bid) );
+        assert_eq!( next_key(&imported, &child), next_key(&state, &child) );
+
+        assert!( import_state(&ExportKey([8u8; 32]), &export, HasherState::new()).is_err() );
+        assert!( import_client(&key, &export, HasherState::new()).is_err() );
+        let mut tampered = export.clone();
+        tampered[EXPORT_HEADER_LENGTH] ^= 1;
+        assert!( import_state(&key, &tampered, HasherState::new()).is_err() );
+        fn reject(v: u8, _: Vec<u8>) -> RatchetResult<Vec<u8>> {
+            Err( RatchetError::UnsupportedExport(v) )
+        }
+        assert!( import_state_with(&key, &export, HasherState::new(), reject).is_err() );
+    }
+
+    #[test]
+    fn import_rejects_inconsistent_state() {
+        let key = ExportKey([9u8; 32]);
+        let (state,bid,child) = populated();
+        state.parents.write().unwrap().insert(bid.family, child);
+        let export = export_state(&key, &state).unwrap();
+        match import_state(&key, &export, HasherState::new()) {
+            Err(RatchetError::CorruptBranch(b,_)) => assert_eq!(b, child),
+            r => panic!("Imported inconsistent state: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn client_round_trip() {
+        let key = ExportKey([9u8; 32]);
+        let hs = HasherState::new();
+        let mut client = ClientState::new();
+        let issuer = IssuerPublicKey([6u8; 32]);
+        let bid = {
+            let state = issuer_state(&mut client, &issuer, hs);
+            create_initial_branch(state, b"client").unwrap().0
+        };
+        let export = export_client(&key, &client).unwrap();
+        let imported = import_client(&key, &export, hs).unwrap();
+        assert_eq!( imported.len(), 1 );
+        assert_eq!( next_key(&imported[&issuer], &bid), next_key(&client[&issuer], &bid) );
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Garbage collection for Xolotl ratchet state
+//!
+//! Twigs accumulate whenever packets skip ahead along a chain, or
+//! never arrive, and berries accumulate whenever nobody grows their
+//! child branch.  We therefore expire branches nobody used recently,
+//! bound the twigs kept for any one branch and for any one `State`,
+//! and bound the cache of failed advance transactions.
+//!
+//! A node keeps one `State` for all its clients, while a client keeps
+//! one `State` per issuer in its `ClientState`, so the per `State`
+//! bound acts as a bound per issuer relationship for clients.
+
+use std::collections::{HashMap,HashSet};
+use std::ops::AddAssign;
+use std::time::{Duration,SystemTime};
+
+use super::branch::*;
+use super::twig::*;
+use super::error::*;
+use super::state::*;
+use ::state::Storage;
+
+
+/// Default time after which we expire unused branches.
+pub const DEFAULT_MAX_IDLE_SECS: u64 = 90*24*60*60;
+
+/// Default bound on twigs kept per branch.
+pub const DEFAULT_MAX_TWIGS_PER_BRANCH: usize = 4096;
+
+/// Default bound on twigs kept per `State`.
+pub const DEFAULT_MAX_TWIGS: usize = 1 << 20;
+
+/// Garbage collection policy for ratchet state.
+#[derive(Debug, Clone)]
+pub struct GcPolicy {
+    /// Expire branches unused for this long.
+    pub max_idle: Duration,
+
+    /// Bound on twigs kept per branch.  We discard the lowest indexed
+    /// link and berry twigs below the branch's chain index first,
+    /// but never train twigs or twigs beyond the chain index, so a
+    /// branch may exceed this bound.
+    pub max_twigs_per_branch: usize,
+
+    /// Bound on twigs kept in one `State`, which we enforce by
+    /// expiring the least recently used branches.
+    pub max_twigs: usize,
+
+    /// Bound on entries in `State::cached`, which we enforce by
+    /// evicting the least recently used entries.
+    pub max_cached: usize,
+}
+
+impl Default for GcPolicy {
+    fn default() -> GcPolicy {
+        GcPolicy {
+            max_idle: Duration::from_secs(DEFAULT_MAX_IDLE_SECS),
+            max_twigs_per_branch: DEFAULT_MAX_TWIGS_PER_BRANCH,
+            max_twigs: DEFAULT_MAX_TWIGS,
+            max_cached: DEFAULT_MAX_CACHED,
+        }
+    }
+}
+
+/// Counts of what garbage collection reclaimed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GcReport {
+    /// Branches expired
+    pub branches: usize,
+
+    /// Twigs removed, including those of expired branches
+    pub twigs: usize,
+
+    /// Entries evicted from `State::cached`
+    pub cached: usize,
+}
+
+impl AddAssign for GcReport {
+    fn add_assign(&mut self, other: GcReport) {
+        self.branches += other.branches;
+        self.twigs += other.twigs;
+        self.cached += other.cached;
+    }
+}
+
+impl State {
+    /// Reclaim storage according to `policy` at time `now`.
+    ///
+    /// We never touch branches locked by an ongoing transaction, and
+    /// we block new transactions until we finish.  Callers should
+    /// record use of branches with `State::touch`, although branches
+    /// never touched count as used when we first see them.
+    pub fn collect_garbage(&self, now: SystemTime, policy: &GcPolicy)
+      -> RatchetResult<GcReport> {
+        let mut report = GcReport::default();
+
+        // Holding this prevents `lock_branch_id` from locking more
+        // branches while we work.
+        let locked = self.locked.read() ?;  // PoisonError
+        let mut branches = self.branches.write() ?;  // PoisonError
+        let mut parents = self.parents.write() ?;  // PoisonError
+        let mut twigs = self.twigs.write() ?;  // PoisonError
+        let mut used = self.used.write() ?;  // PoisonError
+
+        used.retain( |bid,_| branches.contains_key(bid) );
+        for bid in branches.0.keys() {
+            used.entry(*bid).or_insert(now);
+        }
+
+        let mut expired: HashSet<BranchId> = branches.0.keys()
+          .filter( |bid| ! locked.contains(*bid) )
+          .filter( |bid| used.get(*bid).and_then( |t| now.duration_since(*t).ok() )
+                           .map_or(false, |d| d >= policy.max_idle) )
+          .cloned().collect();
+
+        // Sort the twigs of remaining unlocked branches by branch.
+        let mut by_branch: HashMap<BranchId,Vec<(TwigIdx,bool)>> = HashMap::new();
+        let mut total = 0usize;
+        for (&TwigId(bid,idx),tk) in twigs.0.iter() {
+            if expired.contains(&bid) { continue; }
+            total += 1;
+            if locked.contains(&bid) { continue; }
+            let train = if let TwigState::Train(_) = TwigState::new(*tk) { true } else { false };
+            by_branch.entry(bid).or_insert_with(Vec::new).push((idx,train));
+        }
+
+        // Discard the oldest evictable twigs of overly large branches.
+        let mut evict: HashSet<TwigId> = HashSet::new();
+        for (bid,v) in by_branch.iter_mut() {
+            if v.len() <= policy.max_twigs_per_branch { continue; }
+            let chain = if let Some(b) = branches.get(bid) { b.chain } else { continue; };
+            let excess = v.len() - policy.max_twigs_per_branch;
+            v.sort();
+            let discard: Vec<TwigIdx> = v.iter()
+              .filter( |&&(idx,train)| ! train && idx < chain )
+              .map( |&(idx,_)| idx ).take(excess).collect();
+            total -= discard.len();
+            for idx in discard {
+                evict.insert(TwigId(*bid,idx));
+            }
+        }
+
+        // Expire least recently used branches while we hold too many twigs.
+        if total > policy.max_twigs {
+            let mut lru: Vec<(SystemTime,BranchId)> = by_branch.keys()
+              .map( |bid| (used.get(bid).cloned().unwrap_or(now), *bid) ).collect();
+            lru.sort_by_key( |&(t,_)| t );
+            for (_,bid) in lru {
+                if total <= policy.max_twigs { break; }
+                let n = by_branch[&bid].len()
+                  - evict.iter().filter( |tid| tid.0 == bid ).count();
+                total -= n;
+                expired.insert(bid);
+            }
+        }
+
+        let l = twigs.len();
+        twigs.retain( |tid,_| ! expired.contains(&tid.0) && ! evict.contains(tid) );
+        report.twigs = l - twigs.len();
+
+        let l = branches.len();
+        branches.retain( |bid,_| ! expired.contains(bid) );
+        report.branches = l - branches.len();
+        parents.retain( |_,bid| ! expired.contains(bid) );
+        used.retain( |bid,_| ! expired.contains(bid) );
+        let mut successors = self.successors.write() ?;  // PoisonError
+        successors.retain( |_,child| ! expired.contains(child) );
+        ::std::mem::drop(successors);
+        let mut grown = self.grown.write() ?;  // PoisonError
+        grown.retain( |berry,child| ! expired.contains(&berry.0) && ! expired.contains(child) );
+        ::std::mem::drop(grown);
+        let mut established = self.established.write() ?;  // PoisonError
+        established.retain( |bid| ! expired.contains(bid) );
+        ::std::mem::drop(established);
+
+        let mut cached = self.cached.write() ?;  // PoisonError
+        let l = cached.len();
+        cached.retain( |bid,_| ! expired.contains(bid) );
+        self.set_cache_limit(policy.max_cached);
+        evict_cached(&mut cached, policy.max_cached);
+        report.cached = l - cached.len();
+
+        Ok(report)
+    }
+}
+
+/// Reclaim storage for every issuer relationship in `client`
+/// according to `policy` at time `now`.
+pub fn collect_client_garbage(client: &ClientState, now: SystemTime, policy: &GcPolicy)
+  -> RatchetResult<GcReport> {
+    let mut report = GcReport::default();
+    for state in client.values() {
+        report += state.collect_garbage(now, policy) ?;  // PoisonError
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::advance::{Transaction,AdvanceUser,AdvanceClient};
+    use ::sphinx::SphinxSecret;
+    use ::state::HasherState;
+
+    fn clicks(state: &State, bid: &BranchId, n: usize) {
+        for _ in 0..n {
+            let mut advance = AdvanceUser::new(state, bid).unwrap();
+            advance.click(&SphinxSecret([7u8; 32])).unwrap();
+            advance.confirm().unwrap();
+        }
+    }
+
+    fn twig_count(state: &State, bid: &BranchId) -> usize {
+        state.twigs.read().unwrap().0.keys().filter( |tid| tid.0 == *bid ).count()
+    }
+
+    #[test]
+    fn expires_idle_branches() {
+        let start = ::std::time::UNIX_EPOCH;
+        let day = Duration::from_secs(24*60*60);
+        let state = State::new(HasherState::new());
+        let (old,_,_,_) = create_initial_branch(&state, b"old").unwrap();
+        let (new,_,_,_) = create_initial_branch(&state, b"new").unwrap();
+        clicks(&state, &old, 2);
+        clicks(&state, &new, 2);
+        let policy = GcPolicy { max_idle: 2*day, .. GcPolicy::default() };
+
+        assert_eq!( state.collect_garbage(start, &policy).unwrap(), GcReport::default() );
+        state.touch(&new, start + day).unwrap();
+        let n = twig_count(&state, &old);
+        let report = state.collect_garbage(start + 2*day, &policy).unwrap();
+        assert_eq!( report, GcReport { branches: 1, twigs: n, cached: 0 } );
+        assert!( state.branches.read().unwrap().get(&old).is_none() );
+        assert_eq!( twig_count(&state, &old), 0 );
+        assert!( AdvanceUser::new(&state, &new).is_ok() );
+    }
+
+    #[test]
+    fn client_confirms_keep_branches() {
+        let start = ::std::time::UNIX_EPOCH;
+        let day = Duration::from_secs(24*60*60);
+        let ss = SphinxSecret([7u8; 32]);
+        let state = State::new(HasherState::new());
+        let (a,_,_,_) = create_initial_branch(&state, b"a").unwrap();
+        let (b,_,_,_) = create_initial_branch(&state, b"b").unwrap();
+        let (c,_,_,_) = create_initial_branch(&state, b"c").unwrap();
+        let policy = GcPolicy { max_idle: 2*day, .. GcPolicy::default() };
+        assert_eq!( state.collect_garbage(start, &policy).unwrap(), GcReport::default() );
+
+        let mut t = AdvanceClient::new();
+        t.click(&state, &a, &ss).unwrap();
+        t.confirm(start + day).unwrap();
+        ::std::mem::drop(t);
+        let mut advance = AdvanceUser::new(&state, &b).unwrap();
+        advance.click(&ss).unwrap();
+        advance.confirm_at(start + 2*day).unwrap();
+        ::std::mem::drop(advance);
+
+        // Only the branch we never used since we first saw it expires.
+        let report = state.collect_garbage(start + 2*day + day/2, &policy).unwrap();
+        assert_eq!( report.branches, 1 );
+        assert!( state.branches.read().unwrap().get(&c).is_none() );
+        let report = state.collect_garbage(start + 3*day + day/2, &policy).unwrap();
+        assert_eq!( report.branches, 1 );
+        assert!( state.branches.read().unwrap().get(&a).is_none() );
+        assert!( state.branches.read().unwrap().get(&b).is_some() );
+    }
+
+    #[test]
+    fn bounds_twigs_per_branch() {
+        let now = ::std::time::UNIX_EPOCH;
+        let state = State::new(HasherState::new());
+        let (bid,_,_,_) = create_initial_branch(&state, b"seed").unwrap();
+        clicks(&state, &bid, 6);
+        let n = twig_count(&state, &bid);
+        let chain = state.branches.read().unwrap().get(&bid).unwrap().chain;
+        let policy = GcPolicy { max_twigs_per_branch: n-2, .. GcPolicy::default() };
+        let report = state.collect_garbage(now, &policy).unwrap();
+        assert_eq!( report, GcReport { branches: 0, twigs: 2, cached: 0 } );
+        // We discarded the two oldest berries but kept our chain twig.
+        let twigs = state.twigs.read().unwrap();
+        assert!( ! twigs.contains_key(&TwigId(bid,TRAIN_START)) );
+        assert!( twigs.contains_key(&TwigId(bid,chain)) );
+        // Our next click still works.
+        ::std::mem::drop(twigs);
+        clicks(&state, &bid, 1);
+    }
+}
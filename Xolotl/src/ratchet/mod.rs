@@ -8,13 +8,22 @@ mod branch;
 mod twig;
 mod state;
 mod advance;
+mod gc;
+mod client;
+mod export;
+mod check;
 pub mod error;
 
 pub use self::branch::{BranchId,BRANCH_ID_LENGTH}; // BranchName,BRANCH_NAME_LENGTH
 pub use self::twig::{TwigIdxT,TwigIdx,TwigId,TWIG_ID_LENGTH};
-pub use self::advance::{Transaction,Advance,AdvanceNode,AdvanceUser};
+pub use self::advance::{Transaction,Advance,AdvanceNode,AdvanceUser,AdvanceClient};
 
 pub use self::state::{State,ClientState};
+pub use self::gc::{GcPolicy,GcReport,collect_client_garbage};
+pub use self::client::{bootstrap_branch,bootstrap_branch_id,issuer_state,best_branch};
+pub use self::export::{ExportKey,Migration,EXPORT_VERSION,migrate,export_state,import_state,
+                       import_state_with,export_client,import_client,import_client_with};
+pub use self::check::{CheckReport,check_client,check_export};
 pub type RatchetState = State;
 pub type ClientRatchetState = ClientState;
 
@@ -5,7 +5,9 @@
 //! ...
 
 use std::collections::{HashMap,HashSet};
-use std::sync::{RwLock}; // RwLockReadGuard, RwLockWriteGuard
+use std::sync::{RwLock,RwLockWriteGuard}; // RwLockReadGuard
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::SystemTime;
 use std::ops::{Deref,DerefMut};
 // use std::hash::{Hash, Hasher};
 
@@ -22,6 +24,15 @@ pub type ParentStorage = HashMapStorage<BranchName,BranchId>;
 
 pub type BranchLocks = HashSet<BranchId>;
 
+/// Time at which we last advanced each branch.
+pub type BranchUsage = HashMap<BranchId,SystemTime>;
+
+/// Branches we abandoned as exhausted, mapped to their child branch.
+pub type BranchSuccessors = HashMap<BranchId,BranchId>;
+
+/// Berries that grew child branches, mapped to those child branches.
+pub type GrownBerries = HashMap<TwigId,BranchId>;
+
 
 /// The value site of a cached record of a failed advance transaction,
 /// excludes the BranchId which acts as a key.
@@ -35,11 +46,32 @@ pub struct AdvanceFailValue {
 
     /// Keys to insert upon confirmation
     pub inserts: HashMap<TwigIdx,TwigState>,
+
+    /// Tick from `State::cache_tick` when last updated, so that we
+    /// may evict the least recently used entries.
+    pub used: usize,
 }
 
 /// A cache for of failed advance transactions.
 pub type AdvanceFailCache = HashMap<BranchId,AdvanceFailValue>;
 
+/// Default bound on the number of entries in `State::cached`.
+pub const DEFAULT_MAX_CACHED: usize = 4096;
+
+/// Evict the least recently used entries from `cached` until at
+/// most `limit` remain, returning how many we evicted.
+pub fn evict_cached(cached: &mut AdvanceFailCache, limit: usize) -> usize {
+    let excess = cached.len().saturating_sub(limit);
+    if excess == 0 { return 0; }
+    let mut lru: Vec<(usize,BranchId)> = cached.iter()
+      .map(|(bid,v)| (v.used,*bid)).collect();
+    lru.sort_by_key(|&(used,_)| used);
+    for &(_,ref bid) in lru[..excess].iter() {
+        cached.remove(bid);
+    }
+    excess
+}
+
 pub type AdvanceDropErrors = Vec<RatchetError>;
 
 #[derive(Debug)]
@@ -61,10 +93,113 @@ pub struct State {
     pub cached: RwLock< AdvanceFailCache >,
 
     /// Errors encountered when dropping Advance
-    pub advance_drop_errors: RwLock<AdvanceDropErrors>
+    pub advance_drop_errors: RwLock<AdvanceDropErrors>,
+
+    /// Time each branch was last used, not saved to disk.
+    /// Branches absent here count as used when garbage collection
+    /// first sees them.
+    pub used: RwLock<BranchUsage>,
+
+    /// Branches we rolled over to a child branch because they neared
+    /// exhaustion.  Only used by clients, saved to disk.
+    pub successors: RwLock<BranchSuccessors>,
+
+    /// Berries from which we grew child branches, saved to disk.
+    pub grown: RwLock<GrownBerries>,
+
+    /// Branches the other party confirmed holding.  Only used by
+    /// clients, saved to disk.
+    pub established: RwLock<BranchLocks>,
+
+    /// Counter ordering updates to `cached`.
+    cache_tick: AtomicUsize,
+
+    /// Bound on the number of entries in `cached`.
+    cache_limit: AtomicUsize,
 }
 
 impl State {
+    /// Create an empty ratchet state.
+    pub fn new(hs: HasherState) -> State {
+        State {
+            branches: RwLock::new(BranchStorage::new(hs)),
+            parents: RwLock::new(ParentStorage::new(hs)),
+            twigs: RwLock::new(TwigStorage::new(hs)),
+            locked: RwLock::new(BranchLocks::new()),
+            cached: RwLock::new(AdvanceFailCache::new()),
+            advance_drop_errors: RwLock::new(AdvanceDropErrors::new()),
+            used: RwLock::new(BranchUsage::new()),
+            successors: RwLock::new(BranchSuccessors::new()),
+            grown: RwLock::new(GrownBerries::new()),
+            established: RwLock::new(BranchLocks::new()),
+            cache_tick: AtomicUsize::new(0),
+            cache_limit: AtomicUsize::new(DEFAULT_MAX_CACHED),
+        }
+    }
+
+    /// Record that we used the branch `bid` at time `now`, which
+    /// protects it from expiry by garbage collection.
+    pub fn touch(&self, bid: &BranchId, now: SystemTime) -> RatchetResult<()> {
+        let mut used = self.used.write() ?; // PoisonError
+        used.insert(*bid, now);
+        Ok(())
+    }
+
+    /// Find the branch to which we migrated `bid`, following any
+    /// rollovers, or `bid` itself if it never rolled over.
+    pub fn current_branch(&self, bid: &BranchId) -> RatchetResult<BranchId> {
+        let successors = self.successors.read() ?; // PoisonError
+        let mut bid = *bid;
+        // Bound our walk in case of a corrupt cycle.
+        for _ in 0..successors.len() {
+            match successors.get(&bid) {
+                Some(child) => bid = *child,
+                None => break,
+            }
+        }
+        Ok(bid)
+    }
+
+    /// Abandon our most recent rollover from `bid` or any branch to
+    /// which it rolled over, returning the abandoned child.
+    ///
+    /// We grow children from our most recent berry, but if the node
+    /// never processed the packet that produced that berry, then it
+    /// drops every packet on the child.  We should abandon a child on
+    /// which packets go unacknowledged, so that `AdvanceUser::new_rollover`
+    /// rolls over again from our next most recent berry, as growing
+    /// the child consumed its berry.  Garbage collection reclaims the
+    /// abandoned child.
+    pub fn abandon_rollover(&self, bid: &BranchId) -> RatchetResult<Option<BranchId>> {
+        let mut successors = self.successors.write() ?; // PoisonError
+        let mut last = None;
+        let mut b = *bid;
+        // Bound our walk in case of a corrupt cycle.
+        for _ in 0..successors.len() {
+            match successors.get(&b) {
+                Some(child) => { last = Some(b);  b = *child; },
+                None => break,
+            }
+        }
+        Ok( last.and_then( |from| successors.remove(&from) ) )
+    }
+
+    /// Next tick for `AdvanceFailValue::used`.
+    pub fn cache_tick(&self) -> usize {
+        self.cache_tick.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Bound on the number of entries in `cached`.
+    pub fn cache_limit(&self) -> usize {
+        self.cache_limit.load(Ordering::Relaxed)
+    }
+
+    /// Bound the number of entries in `cached`, which takes effect
+    /// upon the next insertion or garbage collection.
+    pub fn set_cache_limit(&self, limit: usize) {
+        self.cache_limit.store(limit, Ordering::Relaxed);
+    }
+
     /// Identify a branch's parent branch.
     pub fn parent_id(&self, family: BranchName) -> RatchetResult<BranchId> {
         let parents = self.parents.read() ?; // PoisonError
@@ -76,6 +211,30 @@ impl State {
     }
 }
 
+/// Write locks held while committing transactions, which we take
+/// in the same order as `State::collect_garbage`.
+pub struct CommitGuards<'a> {
+    pub branches: RwLockWriteGuard<'a,BranchStorage>,
+    pub parents: RwLockWriteGuard<'a,ParentStorage>,
+    pub twigs: RwLockWriteGuard<'a,TwigStorage>,
+    pub used: RwLockWriteGuard<'a,BranchUsage>,
+    pub successors: RwLockWriteGuard<'a,BranchSuccessors>,
+    pub grown: RwLockWriteGuard<'a,GrownBerries>,
+}
+
+impl State {
+    /// Take the write locks needed to commit transactions.
+    pub fn commit_guards(&self) -> RatchetResult<CommitGuards> {
+        let branches = self.branches.write() ?;  // PoisonError
+        let parents = self.parents.write() ?;  // PoisonError
+        let twigs = self.twigs.write() ?;  // PoisonError
+        let used = self.used.write() ?;  // PoisonError
+        let successors = self.successors.write() ?;  // PoisonError
+        let grown = self.grown.write() ?;  // PoisonError
+        Ok( CommitGuards { branches, parents, twigs, used, successors, grown } )
+    }
+}
+
 /// Create a locked branch identifier.
 pub fn lock_branch_id<'a>(state: &'a State, bid: &BranchId) -> RatchetResult<BranchIdGuard<'a>> {
     let mut locked = state.locked.write() ?; // PoisonError
@@ -146,6 +305,10 @@ pub fn create_initial_branch(state: &State, seed: &[u8])
     //   their lines?  logging?
     let mut branches = state.branches.write() ?;  // PoisonError
     let bs: &mut BranchStorage = branches.deref_mut();  
+    // Never reset an existing branch to its initial state.
+    if bs.contains_key(&bid) {
+        return Err(RatchetError::BranchAlreadyExists(bid));
+    }
     bs.insert(bid, branch.clone());
     let mut parents = state.parents.write() ?;  // PoisonError
     let ps: &mut ParentStorage = parents.deref_mut();  
@@ -168,7 +168,7 @@ pub struct TwigIdx(pub TwigIdxT);
 /// We choose 5 bits giving 32 chain keys per chain, or 512 bytes,
 /// along with at most 3*(16-5) = 33 additional train and chain keys
 /// to reach the chan, so malicious packets can waste at most 1kb.  
-const CHAIN_V_TRAIN_WIDTH : u8 = 5;
+pub const CHAIN_V_TRAIN_WIDTH : u8 = 5;
 
 pub const TRAIN_START : TwigIdx = TwigIdx( 1 << CHAIN_V_TRAIN_WIDTH );
 
@@ -206,6 +206,10 @@ impl TwigIdx {
         if self.0 < TwigIdxT::max_value() { Some(TwigIdx(self.0+1)) } else { None }
     }
 
+    /// Number of times we may still increment before exhausting
+    /// our branch.
+    pub fn remaining(self) -> TwigIdxT  {  TwigIdxT::max_value() - self.0  }
+
     /// Says if we progress to the next train step.
     pub fn is_pure_train(self) -> bool  {  (self.0 & CHAIN_MASK) == 0  }
 
@@ -260,6 +264,9 @@ impl TwigId {
     }
 }
 
+#[cfg(feature = "serde")]
+impl_serde_via_bytes!(TwigId, TWIG_ID_LENGTH);
+
 
 /// A twigs' index and state together
 #[derive(Debug, Clone)]
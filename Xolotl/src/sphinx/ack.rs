@@ -0,0 +1,322 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Delivery acknowledgements and retransmission
+//!
+//! A sender may attach an acknowledgement SURB to any payload, which
+//! the recipient fires with an empty body upon receiving the payload.
+//! We track unacknowledged packets by the `PacketName` with which
+//! their acknowledgement SURB arrives, and retransmit them over a
+//! freshly built route if no acknowledgement arrives in time.
+//!
+//! Recipients fire acknowledgements only if they expect every payload
+//! to carry our prefix, as configured by `Router::set_fire_acks`.
+
+use std::collections::HashMap;
+use std::time::{Duration,SystemTime};
+
+use rand::Rng;
+
+use keys::time::Clock;
+use ::state::HasherState;
+use super::layout::{Params,ImplParams,PreHeader,SURB_PREFIX_LENGTH,encode_header};
+use super::mailbox::{ArrivingStore,OutgoingPacket};
+use super::body;
+use super::error::*;
+use super::*;
+
+
+/// Length of the prefix giving the length of any attached
+/// acknowledgement SURB as a little endian `u16`.
+pub const ACK_PREFIX_LENGTH: usize = 2;
+
+/// We wait this multiple of the expected round trip delay before
+/// retransmitting, because delays are randomized per hop.
+pub const ACK_DELAY_FACTOR: u32 = 3;
+
+/// Additional grace period in seconds before retransmitting, covering
+/// transmission and processing time not included in hop delays.
+pub const ACK_GRACE_SECS: u64 = 60;
+
+/// Prefix `payload` with an optional acknowledgement SURB.
+pub fn attach_ack(surb: Option<&PreHeader>, payload: &[u8]) -> SphinxResult<Vec<u8>> {
+    let surb = surb.map(|s| s.encode_surb());
+    let l = surb.as_ref().map_or(0, |s| s.len());
+    if l > 0xFFFF {
+        return Err( SphinxError::BadLength("Acknowledgement SURB too long", l) );
+    }
+    let mut r = Vec::with_capacity(ACK_PREFIX_LENGTH + l + payload.len());
+    r.extend_from_slice(&[ l as u8, (l >> 8) as u8 ]);
+    if let Some(s) = surb { r.extend_from_slice(&s); }
+    r.extend_from_slice(payload);
+    Ok(r)
+}
+
+/// Split a payload produced by `attach_ack` into any acknowledgement
+/// SURB and the remaining payload.
+pub fn detach_ack(payload: &[u8]) -> SphinxResult<(Option<PreHeader>,&[u8])> {
+    if payload.len() < ACK_PREFIX_LENGTH {
+        return Err( SphinxError::BadLength("Payload shorter than acknowledgement prefix", payload.len()) );
+    }
+    let (l,rest) = payload.split_at(ACK_PREFIX_LENGTH);
+    let l = (l[0] as usize) | (l[1] as usize) << 8;
+    if l == 0 { return Ok((None,rest)); }
+    if l < SURB_PREFIX_LENGTH || l > rest.len() {
+        return Err( SphinxError::BadPacket("Bad acknowledgement SURB length", l as u64) );
+    }
+    let (surb,rest) = rest.split_at(l);
+    Ok(( Some(PreHeader::decode_surb(surb) ?), rest ))  // BadLength
+}
+
+/// Build the packet that fires an acknowledgement SURB with an
+/// empty payload in our shortest body.
+pub fn fire_ack<P,R,K>(rng: &mut R, clock: &K, surb: PreHeader) -> SphinxResult<OutgoingPacket>
+  where P: Params, R: Rng, K: Clock+?Sized {
+    fire_surb::<P,R,K>(rng, clock, surb, &[])
+}
+
+/// Build the packet that fires a SURB carrying `payload` in our
+/// shortest body with sufficient capacity.
+///
+/// We prefix `payload` with an empty acknowledgement prefix, so that
+/// recipients who fire acknowledgements parse it like any payload.
+pub fn fire_surb<P,R,K>(rng: &mut R, clock: &K, surb: PreHeader, payload: &[u8])
+  -> SphinxResult<OutgoingPacket>
+  where P: Params, R: Rng, K: Clock+?Sized {
+    let mut surb = surb;
+    let route = surb.route;
+    // A SURB's beta holds only what its creator wrote, so we zero
+    // the remainder exactly like a cross over point does.
+    if surb.beta.len() > P::MAX_SURB_BETA_LENGTH {
+        return Err( SphinxError::BadLength("SURB's beta too long", surb.beta.len()) );
+    }
+    let payload = attach_ack(None, payload) ?;
+    let i = P::BODY_LENGTHS.iter()
+      .position( |l| payload.len() <= body::payload_capacity(*l) )
+      .ok_or( SphinxError::BadLength("Payload exceeds every body length", payload.len()) ) ?;
+    let mut beta = surb.beta.into_vec();
+    beta.resize(P::BETA_LENGTH, 0);
+    surb.beta = beta.into_boxed_slice();
+    let header = encode_header::<P,R>(rng, surb) ?;  // InternalError
+    let mut body = P::boxed_zeroed_body(i);
+    body::frame_body(&payload, &mut body) ?;  // BadLength
+    Ok( OutgoingPacket { route, time: clock.now(), header, body } )
+}
+
+/// Time after sending which we consider a packet lost, given the
+/// expected delays of its header and of its acknowledgement SURB,
+/// as reported by `NewHeader::delay`.
+pub fn ack_timeout(send_delay: Duration, ack_delay: Duration) -> Duration {
+    (send_delay + ack_delay) * ACK_DELAY_FACTOR + Duration::from_secs(ACK_GRACE_SECS)
+}
+
+
+/// An unacknowledged packet.
+struct Pending<T> {
+    /// Time after which we retransmit
+    deadline: SystemTime,
+
+    /// Number of times we have sent this packet
+    attempts: usize,
+
+    /// Caller data from which we rebuild the packet
+    data: T,
+}
+
+/// Tracks unacknowledged packets by the `PacketName` of their
+/// acknowledgement SURB.
+pub struct AckTracker<T> {
+    /// We abandon packets after this many transmissions.
+    pub max_attempts: usize,
+
+    pending: HashMap<PacketName,Pending<T>,HasherState>,
+}
+
+impl<T> AckTracker<T> {
+    pub fn new(hs: HasherState, max_attempts: usize) -> AckTracker<T> {
+        AckTracker { max_attempts, pending: HashMap::with_hasher(hs) }
+    }
+
+    /// Number of unacknowledged packets.
+    pub fn pending(&self) -> usize { self.pending.len() }
+
+    /// Record that we sent a packet whose acknowledgement SURB
+    /// arrives with packet name `ack`, and whose acknowledgement
+    /// we expect within `timeout`.
+    pub fn track<K: Clock+?Sized>(&mut self, clock: &K, ack: PacketName, timeout: Duration, data: T)
+      -> SphinxResult<()> {
+        self.insert(clock, ack, timeout, data, 1)
+    }
+
+    fn insert<K: Clock+?Sized>(&mut self, clock: &K, ack: PacketName, timeout: Duration, data: T, attempts: usize)
+      -> SphinxResult<()> {
+        let deadline = clock.now() + timeout;
+        if self.pending.contains_key(&ack) {
+            return Err( SphinxError::InternalError("Packet name collision detected!") );
+        }
+        self.pending.insert(ack, Pending { deadline, attempts, data });
+        Ok(())
+    }
+
+    /// Mark the packet acknowledged by `ack` as delivered, returning
+    /// its data if we were waiting for it.
+    pub fn acknowledge(&mut self, ack: &PacketName) -> Option<T> {
+        self.pending.remove(ack).map(|p| p.data)
+    }
+
+    /// Remove acknowledgements from `arrivals`, leaving all other
+    /// arriving packets in place, and return the data of the packets
+    /// they acknowledge.
+    pub fn drain_acks(&mut self, arrivals: &ArrivingStore) -> Vec<T> {
+        let mut arrivals = arrivals.write().unwrap();  // PoisonError ???
+        let mut acked = Vec::new();
+        let mut i = 0;
+        while i < arrivals.len() {
+            if let Some(data) = self.acknowledge(&arrivals[i].packet_name) {
+                arrivals.swap_remove(i);
+                acked.push(data);
+            } else { i += 1; }
+        }
+        acked
+    }
+
+    /// Retransmit every packet whose deadline passed using `resend`,
+    /// which should build the packet anew over a fresh route with a
+    /// fresh acknowledgement SURB, send it, and return the new SURB's
+    /// packet name along with the new timeout.
+    ///
+    /// Returns the data of packets abandoned after `max_attempts`
+    /// transmissions.  We also abandon any packet for which `resend`
+    /// fails, or returns a packet name we already track, so that one
+    /// bad packet cannot stall the others.
+    pub fn retransmit<K,F>(&mut self, clock: &K, mut resend: F) -> Vec<T>
+      where K: Clock+?Sized,
+            F: FnMut(&T) -> SphinxResult<(PacketName,Duration)> {
+        let now = clock.now();
+        let due: Vec<PacketName> = self.pending.iter()
+          .filter(|&(_,p)| p.deadline <= now)
+          .map(|(n,_)| *n).collect();
+        let mut abandoned = Vec::new();
+        for n in due {
+            let Pending { attempts, data, .. } = self.pending.remove(&n).unwrap();
+            if attempts >= self.max_attempts {
+                abandoned.push(data);
+                continue;
+            }
+            let (ack,timeout) = match resend(&data) {
+                Ok(r) => r,
+                Err(_) => { abandoned.push(data);  continue; },
+            };
+            // Only a broken `resend` reuses a packet name.
+            if self.pending.contains_key(&ack) {
+                abandoned.push(data);
+                continue;
+            }
+            self.pending.insert(ack, Pending { deadline: now + timeout, attempts: attempts+1, data });
+        }
+        abandoned
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+    use keys::time::MockClock;
+    use super::super::client::ClientRatchetState;
+    use super::super::commands::Instruction;
+    use super::super::params::ChatParams;
+    use super::super::testing::TestNet;
+    use super::*;
+
+    #[test]
+    fn ack_prefix_round_trips() {
+        let payload = [3u8; 40];
+        let r = attach_ack(None, &payload).unwrap();
+        let (surb,rest) = detach_ack(&r).unwrap();
+        assert!(surb.is_none());
+        assert_eq!(rest, &payload[..]);
+        assert!( detach_ack(&[0xFF,0x00,1,2,3]).is_err() );
+    }
+
+    #[test]
+    fn ack_tracker_retransmits() {
+        let clock = MockClock::new(UNIX_EPOCH);
+        let mut t = AckTracker::<u8>::new(HasherState::new(), 2);
+        let timeout = ack_timeout(Duration::from_secs(10), Duration::from_secs(20));
+        assert_eq!(timeout, Duration::from_secs(150));
+        t.track(&clock, PacketName([1u8; 16]), timeout, 1).unwrap();
+        t.track(&clock, PacketName([2u8; 16]), timeout, 2).unwrap();
+        assert_eq!(t.acknowledge(&PacketName([1u8; 16])), Some(1));
+        assert_eq!(t.acknowledge(&PacketName([1u8; 16])), None);
+
+        let mut resent = Vec::new();
+        assert!( t.retransmit(&clock, |d| { resent.push(*d); Ok((PacketName([3u8; 16]),timeout)) }).is_empty() );
+        assert!( resent.is_empty() );
+
+        clock.advance(timeout);
+        assert!( t.retransmit(&clock, |d| { resent.push(*d); Ok((PacketName([3u8; 16]),timeout)) }).is_empty() );
+        assert_eq!(resent, vec![2]);
+        assert_eq!(t.acknowledge(&PacketName([2u8; 16])), None);
+
+        clock.advance(timeout);
+        let abandoned = t.retransmit(&clock, |_| panic!("Exceeded max_attempts!"));
+        assert_eq!(abandoned, vec![2]);
+        assert_eq!(t.pending(), 0);
+    }
+
+    #[test]
+    fn ack_tracker_abandons_collisions() {
+        let clock = MockClock::new(UNIX_EPOCH);
+        let mut t = AckTracker::<u8>::new(HasherState::new(), 3);
+        let timeout = Duration::from_secs(10);
+        t.track(&clock, PacketName([1u8; 16]), timeout, 1).unwrap();
+        t.track(&clock, PacketName([2u8; 16]), timeout * 10, 2).unwrap();
+        clock.advance(timeout);
+        // A resend reusing a tracked packet name abandons the packet.
+        let abandoned = t.retransmit(&clock, |_| Ok((PacketName([2u8; 16]),timeout)));
+        assert_eq!(abandoned, vec![1]);
+        assert_eq!(t.acknowledge(&PacketName([2u8; 16])), Some(2));
+    }
+
+    #[test]
+    fn acks_fire_and_match() {
+        let mut net = TestNet::<ChatParams>::new(3);
+        for r in net.routers.iter_mut() { r.set_fire_acks(true); }
+        let ratchets = ClientRatchetState::new();
+        let world = net.world(&ratchets);
+
+        // Our acknowledgement SURB returns to us at node 0 via node 2.
+        let surb = net.build(&world, true, 2, vec![
+            Instruction::Transmit { route: net.names[0] },
+            Instruction::ArrivalSURB { },
+        ]).unwrap();
+        let ack_delay = surb.delay();
+        let (surb,keys) = surb.into_surb().unwrap();
+        let ack = net.routers[0].surbs().insert_arrival(keys).unwrap();
+
+        // We send our payload through node 1 to arrive at node 2.
+        let h = net.build(&world, false, 1, vec![
+            Instruction::Transmit { route: net.names[2] },
+            Instruction::ArrivalDirect { },
+        ]).unwrap();
+        let mut tracker = AckTracker::new(HasherState::new(), 3);
+        tracker.track(&*net.clock, ack, ack_timeout(h.delay(), ack_delay), 7u32).unwrap();
+        net.send(1, &h, &attach_ack(Some(&surb), b"Hello").unwrap()).unwrap();
+
+        // Node 2 stores our payload without its prefix, and fires our
+        // SURB, whose first hop is node 2 itself.
+        assert_eq!(net.relay(1,2).unwrap(), 1);
+        {
+            let arrivals = net.routers[2].arrivals().read().unwrap();
+            assert_eq!(arrivals.len(), 1);
+            assert_eq!(&arrivals[0].body[..], b"Hello");
+        }
+        assert_eq!(tracker.drain_acks(net.routers[2].arrivals()), Vec::<u32>::new());
+        assert_eq!(net.relay(2,2).unwrap(), 1);
+        assert_eq!(net.relay(2,0).unwrap(), 1);
+
+        assert_eq!(tracker.drain_acks(net.routers[0].arrivals()), vec![7]);
+        assert_eq!(tracker.pending(), 0);
+        assert!( net.routers[0].arrivals().read().unwrap().is_empty() );
+    }
+}
@@ -34,7 +34,7 @@ impl<P: Params> BodyCipher<P> {
 
     pub fn encrypt(&self, body: &mut [u8]) -> SphinxResult<()> {
         P::check_body_length(body.len()) ?;
-        if body.len() > 0 { return Ok(()); }
+        if body.len() == 0 { return Ok(()); }
         Ok(self.cipher.encrypt(body) ?)
     }
 
@@ -45,6 +45,66 @@ impl<P: Params> BodyCipher<P> {
     }
 }
 
+
+/// Length of the block of zeros with which we begin every body.
+///
+/// Lioness is a wide block cipher, so any modification to the
+/// ciphertext anywhere randomizes the entire plaintext.  We detect
+/// tagging attacks by checking that the plaintext starts with these
+/// zeros after removing the final Lioness layer.
+pub const BODY_ZEROS_LENGTH: usize = 16;
+
+/// Length of our body framing, consisting of the zeros block
+/// followed by the payload length as a little endian `u32`.
+pub const BODY_FRAMING_LENGTH: usize = BODY_ZEROS_LENGTH + 4;
+
+/// Maximum length of a payload inside a body of length `body_length`.
+pub fn payload_capacity(body_length: usize) -> usize {
+    if body_length < BODY_FRAMING_LENGTH { 0 } else { body_length - BODY_FRAMING_LENGTH }
+}
+
+/// Write `payload` into `body` with our framing before encryption,
+/// zeroing any remaining padding.
+pub fn frame_body(payload: &[u8], body: &mut [u8]) -> SphinxResult<()> {
+    if body.len() == 0 && payload.len() == 0 { return Ok(()); }
+    if body.len() < BODY_FRAMING_LENGTH || payload.len() > payload_capacity(body.len()) {
+        return Err( SphinxError::BadLength("Payload exceeds body capacity", payload.len()) );
+    }
+    let (framing,rest) = body.split_at_mut(BODY_FRAMING_LENGTH);
+    {
+        let (zeros,length) = mut_array_refs![array_mut_ref![framing,0,BODY_FRAMING_LENGTH],BODY_ZEROS_LENGTH,4];
+        for i in zeros.iter_mut() { *i = 0; }
+        let l = payload.len() as u32;
+        *length = [ l as u8, (l >> 8) as u8, (l >> 16) as u8, (l >> 24) as u8 ];
+    }
+    let (p,padding) = rest.split_at_mut(payload.len());
+    p.copy_from_slice(payload);
+    for i in padding.iter_mut() { *i = 0; }
+    Ok(())
+}
+
+/// Authenticate a fully decrypted body by checking our framing,
+/// and return its payload.
+///
+/// Returns `SphinxError::TaggingAttack` if the zeros block was
+/// corrupted, presumably by someone modifying the body in transit.
+pub fn unframe_body(body: &[u8]) -> SphinxResult<&[u8]> {
+    if body.len() == 0 { return Ok(body); }
+    if body.len() < BODY_FRAMING_LENGTH {
+        return Err( SphinxError::BadLength("Body too short for framing", body.len()) );
+    }
+    let (zeros,length) = array_refs![array_ref![body,0,BODY_FRAMING_LENGTH],BODY_ZEROS_LENGTH,4];
+    if ! ::consistenttime::ct_u8_slice_eq(zeros, &[0u8; BODY_ZEROS_LENGTH]) {
+        return Err( SphinxError::TaggingAttack );
+    }
+    let l = (length[0] as usize) | (length[1] as usize) << 8
+          | (length[2] as usize) << 16 | (length[3] as usize) << 24;
+    if l > payload_capacity(body.len()) {
+        return Err( SphinxError::BadPacket("Body payload length exceeds body",l as u64) );
+    }
+    Ok( &body[BODY_FRAMING_LENGTH..BODY_FRAMING_LENGTH+l] )
+}
+
 /// We could call `.unwrap()` above to avoid this because
 /// `BodyCipher::compatable_length` has a more complete error test,
 /// and gets called by `ImplParams::check_body_length`
@@ -60,3 +120,59 @@ impl<'a> From<LionessError> for SphinxError {
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{OsRng, Rng};
+    use super::*;
+    use super::super::params::{ChatParams,FileParams};
+
+    fn random_cipher<P: Params, R: Rng>(rng: &mut R) -> BodyCipher<P> {
+        let mut key = [0u8; RAW_KEY_SIZE];
+        rng.fill_bytes(&mut key);
+        BodyCipher {
+            params: PhantomData,
+            cipher: LionessDefault::new_raw(&key),
+        }
+    }
+
+    fn round_trips<P: Params>() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let ciphers: Vec<BodyCipher<P>> = (0..3).map(|_| random_cipher(&mut r)).collect();
+        for l in P::BODY_LENGTHS {
+            let mut payload = vec![0u8; payload_capacity(*l) / 2];
+            r.fill_bytes(&mut payload);
+            let mut body = vec![0u8; *l];
+            frame_body(&payload, &mut body).unwrap();
+            for c in ciphers.iter().rev() { c.encrypt(&mut body).unwrap(); }
+            if *l > 0 { assert!(&body[..] != &payload[..]); }
+
+            let mut tagged = body.clone();
+            for c in ciphers.iter() { c.decrypt(&mut body).unwrap(); }
+            assert_eq!(unframe_body(&body).unwrap(), &payload[..]);
+
+            if *l == 0 { continue; }
+            tagged[*l / 2] ^= 0x40;
+            for c in ciphers.iter() { c.decrypt(&mut tagged).unwrap(); }
+            match unframe_body(&tagged) {
+                Err( SphinxError::TaggingAttack ) => { },
+                r => panic!("Tagging attack undetected: {:?}", r.map(|x| x.len())),
+            }
+        }
+    }
+
+    #[test]
+    fn body_round_trips() {
+        round_trips::<ChatParams>();
+        round_trips::<FileParams>();
+    }
+
+    #[test]
+    fn body_framing_bounds() {
+        let mut body = [0u8; 64];
+        assert!( frame_body(&[1u8; 45], &mut body).is_err() );
+        frame_body(&[1u8; 44], &mut body).unwrap();
+        assert_eq!(unframe_body(&body).unwrap(), &[1u8; 44][..]);
+    }
+}
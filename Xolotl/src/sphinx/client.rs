@@ -10,13 +10,15 @@ use std::time::{Duration}; // SystemTime, UNIX_EPOCH
 
 use rand::{Rng, Rand, ChaChaRng, SeedableRng};
 
-use ratchet::{BranchId,TwigId,Transaction,AdvanceUser}; // BRANCH_ID_LENGTH,TWIG_ID_LENGTH
+use ratchet::{BranchId,TwigId,AdvanceClient}; // BRANCH_ID_LENGTH,TWIG_ID_LENGTH
 pub use ratchet::ClientState as ClientRatchetState;
 
 pub use keys::{RoutingName,RoutingPublic,Concensus};
-pub use super::mailbox::{MailboxName,MAILBOX_NAME_LENGTH};
+use keys::kem::Kem;
+use keys::time::{Clock,SystemClock};
+pub use super::mailbox::{MailboxName,MAILBOX_NAME_LENGTH,SlotName,SLOT_NAME_LENGTH};
 use super::commands::{PreCommand,Command,Instruction};
-use super::layout::{Params,PreHeader}; // ImplParams
+use super::layout::{Params,ImplParams,PreHeader};
 use super::error::*;
 use super::*;
 
@@ -34,11 +36,22 @@ pub struct World<'a,C,P> where C: Concensus+'a, P: Params {
 
     /// Our ratchets with other nodes.
     ratchets: &'a ClientRatchetState,
+
+    /// KEM used by hybrid sub-hops, if any.
+    kem: Option<&'a Kem>,
+
+    /// Source of time for recording when we used ratchet branches.
+    clock: &'a (Clock+Sync),
 }
 
+/// Default `World::clock`.
+static SYSTEM_CLOCK: SystemClock = SystemClock;
+
 /// We cannot `#[derive(Clone)]` if we need a where clause.
 impl<'a,C,P> Clone for World<'a,C,P> where C: Concensus+'a, P: Params {
-    fn clone(&self) -> World<'a,C,P> { World::new(self.concensus, self.ratchets) }
+    fn clone(&self) -> World<'a,C,P> {
+        World { params: PhantomData, concensus: self.concensus, ratchets: self.ratchets, kem: self.kem, clock: self.clock }
+    }
 }
 
 impl<'a,C,P> World<'a,C,P> where C: Concensus+'a, P: Params {
@@ -46,9 +59,24 @@ impl<'a,C,P> World<'a,C,P> where C: Concensus+'a, P: Params {
         World {
             params: PhantomData,
             concensus, ratchets,
+            kem: None,
+            clock: &SYSTEM_CLOCK,
         }
     }
 
+    /// Record ratchet branch use with times from `clock`, instead of
+    /// the system time.
+    pub fn with_clock(mut self, clock: &'a (Clock+Sync)) -> World<'a,C,P> {
+        self.clock = clock;
+        self
+    }
+
+    /// Permit `Instruction::Hybrid` using `kem`.
+    pub fn with_kem(mut self, kem: &'a Kem) -> World<'a,C,P> {
+        self.kem = Some(kem);
+        self
+    }
+
     pub fn build_headers<R: Rng>(&self, rng: R) -> BuildScaffold<'a,C,P,R> {
         BuildScaffold {
             world: self.clone(),  rng,
@@ -114,8 +142,9 @@ impl<'a,C,P,R> BuildScaffold<'a,C,P,R> where C: Concensus+'a, P: Params, R: Rng
     pub fn make_surb(mut self) -> BuildScaffold<'a,C,P,R>
       { self.orientation = Orientation::SURB { surb_keys: Vec::new() }; self }
 
-    /// Produce the `Scaffold` with which we build one header.
-    fn go(self, route: RoutingName) -> SphinxResult<Scaffold<'a,C,P,R>> {
+    /// Produce the `Scaffold` with which we build one header,
+    /// whose first hop is `route`.
+    pub fn go(self, route: RoutingName) -> SphinxResult<Scaffold<'a,C,P,R>> {
         let BuildScaffold { world, mut rng, mut orientation, capacity } = self;
 
         let aa = rng.gen();
@@ -138,8 +167,9 @@ impl<'a,C,P,R> BuildScaffold<'a,C,P,R> where C: Concensus+'a, P: Params, R: Rng
             world: world.clone(),
             rng, v, orientation,
             commands: Vec::with_capacity(capacity),
-            advances: Vec::with_capacity(capacity),
+            advances: AdvanceClient::new(),
             ciphers: Vec::with_capacity(capacity+1),
+            reports: Vec::new(),
         };
         s.add_sphinx(route) ?;
         Ok( s )
@@ -246,6 +276,15 @@ impl ScaffoldOrientation {
         );
     }
 
+    /// Says if we currently collect `SURBHopKey`s for SURB unwinding.
+    fn collects_surb_keys(&self) -> bool {
+        use self::Orientation::*;
+        match *self {
+            Send { .. } => false,
+            SURB { .. } | SendAndSURB { .. } => true,
+        }
+    }
+
     fn do_send_and_surb(&mut self) -> SphinxResult<()> {
         use self::Orientation::*;
         let bodies = match *self {
@@ -351,14 +390,16 @@ pub struct Scaffold<'a,C,P,R>
     /// an index into `hops` for the next `HeaderCipher`.
     commands: Vec<PreCommand<usize>>,
 
-    /// Ratchet advance transactions 
-    ///
-    /// TODO: Refactor to have only one transaction and/or support repeats?!?
-    advances: Vec<AdvanceUser<'a>>,
+    /// Combined ratchet advance transaction for all ratchet sub-hops,
+    /// which we confirm all together in `done`.
+    advances: AdvanceClient<'a>,
 
     /// Stream ciphers for 
     ciphers: Vec<stream::HeaderCipher<P>>,
 
+    /// Ids of sub-hops from which we requested error reports.
+    reports: Vec<ErrorPacketId>,
+
     /// Header orientation along with associated key material.
     /// 
     /// A sending packet records the indexes of header ciphers that
@@ -384,6 +425,10 @@ impl<'a,C,P,R> Scaffold<'a,C,P,R>
       -> SphinxResult<()> {
         let mut other = other.unwrap_or(&self.v.route_public.validity).clone();
         other += self.v.delay;
+        // Coarsen each hop's validity before intersecting, so that
+        // our final validity reveals little about our routing keys.
+        let other = P::VALIDITY_POLICY.coarsen(&other)
+          .ok_or( SphinxError::InternalError("Validity too short for validity policy") ) ?;
         self.v.validity = self.v.validity.intersect(&other)
           .ok_or( SphinxError::InternalError("Validity Error") ) ?;
         Ok(())
@@ -448,7 +493,6 @@ impl<'a,C,P,R> Scaffold<'a,C,P,R>
           // because the borrow checker cannot tell that the immutable
           // borrow returned does not hide some mutable borrow via
           // interior mutability, ala `RefCell`.
-        // TODO: Figure out how to prevent leakage via validity
         self.intersect_validity(None) ?;
         let rpoint = ::curve::Point::decompress(&self.v.route_public.public) ?;  // BadAlpha
         let ss = rpoint.key_exchange(&self.v.aa);
@@ -456,30 +500,79 @@ impl<'a,C,P,R> Scaffold<'a,C,P,R>
         self.add_cipher(None)
     }
 
+    /// Add a hybrid sub-hop whose key mixes a fresh KEM shared secret
+    /// for the current hop's `RoutingPublic::kem` into the key for
+    /// the preceding Sphinx sub-hop, returning the KEM ciphertext.
+    fn add_hybrid(&mut self) -> SphinxResult<(Box<[u8]>,usize)> {
+        let kem = self.world.kem
+          .ok_or( SphinxError::InternalError("Hybrid sub-hop requires a KEM") ) ?;
+        let seed: [u8; 32] = self.rng.gen();
+        let (ciphertext,kem_ss) = {
+            let public = self.v.route_public.kem.as_ref()
+              .ok_or( ::keys::error::KeysError::Routing(self.v.route.end,"Routing key lacks a KEM public key.") ) ?;
+            kem.encapsulate(public, &seed) ?  // KeysError
+        };
+        {
+            let key = self.v.key.as_mut().expect("Cannot add hybrid sub-hop without a previous key!");
+            *key = stream::SphinxKey::<P>::new_kdf_hybrid(&SphinxSecret(key.chacha.key), &kem_ss, &self.v.route.end);
+        }
+        // Our hybrid key superceeds the Sphinx sub-hop's key for
+        // processing the body and SURB log.
+        self.orientation.pop();
+        let i = self.add_cipher(None) ?;
+        Ok(( ciphertext, i ))
+    }
+
+    /// Refuse ratchet sub-hops in SURBs because SURB unwinding cannot
+    /// yet record the berries they produce, see
+    /// `surbs::SURBStore::unwind_delivery_surbs`.
+    fn check_ratchet_orientation(&self) -> SphinxResult<()> {
+        if self.orientation.collects_surb_keys() {
+            return Err( SphinxError::InternalError("Ratchet sub-hops are unsupported in SURBs") );
+        }
+        Ok(())
+    }
+
+    /// Bootstrap a ratchet branch from the current hop's key, and
+    /// advance it like `add_ratchet`.  We create the branch only when
+    /// `done` confirms our advances.
+    fn add_bootstrap(&mut self) -> SphinxResult<(TwigId,usize)> {
+        self.check_ratchet_orientation() ?;  // InternalError
+        let ratchet = self.world.ratchets.get(&self.v.route_public.issuer)
+          .ok_or( SphinxError::IssuerHasNoRatchet(self.v.route_public.issuer) ) ?;
+        let twig = {
+            let key = self.v.key.as_mut().expect("Cannot add ratchet without a previous key!");
+            let (twig,k) = self.advances.bootstrap(ratchet, &SphinxSecret(key.chacha.key)) ?; // RatchetError
+            key.chacha.key = k;
+            twig
+        };
+        let i = self.add_cipher( Some(twig) ) ?;
+        Ok(( twig, i ) )
+    }
+
     /// Assumes ...  !!!!!!!!!!
     fn add_ratchet(&mut self, branch_id: BranchId)
       -> SphinxResult<(TwigId,usize)> {
+        self.check_ratchet_orientation() ?;  // InternalError
         let ratchet = self.world.ratchets.get(&self.v.route_public.issuer)
           .ok_or( SphinxError::IssuerHasNoRatchet(self.v.route_public.issuer) ) ?;
-        let mut advance = AdvanceUser::new(ratchet,&branch_id) ?;  // RatchetError
         let twig = {
             let key = self.v.key.as_mut().expect("Cannot add ratchet without a previous key!");
-            let (twig,k) = advance.click(&SphinxSecret(key.chacha.key)) ?; // RatchetError
+            let (twig,k) = self.advances.click(ratchet, &branch_id, &SphinxSecret(key.chacha.key)) ?; // RatchetError
             key.chacha.key = k;
             twig
         };
         let i = self.add_cipher( Some(twig) ) ?;
-        self.advances.push(advance);
-          // TODO: Refactor to have only one transaction and/or support repeats
         Ok(( twig, i ) )
     }
 
-    pub fn add<'s: 'a>(&'s mut self) -> Hoist<'s,'a,C,P,R> {
+    pub fn add<'s>(&'s mut self) -> Hoist<'s,'a,C,P,R> {
         Hoist {
             saved_v: self.v.clone(),
             commands_len: self.commands.len(),
             advances_len: self.advances.len(),
             ciphers_len: self.ciphers.len(),
+            reports_len: self.reports.len(),
             orientation: self.orientation.clone(),
             s: self
         }
@@ -507,14 +600,18 @@ pub struct Hoist<'s,'a,C,P,R>
     /// when this `Hoist` transaction started.
     commands_len: usize,
 
-    /// Saved number of ratchet `Advance` transactions recorded by our
-    /// `Scaffold` when this `Hoist` transaction started.
+    /// Saved number of ratchet clicks recorded by our `Scaffold`
+    /// when this `Hoist` transaction started.
     advances_len: usize,
 
     /// Saved length of `HeaderCipher`s recorded by our `Scaffold`
     /// when this `Hoist` transaction started.
     ciphers_len: usize,
 
+    /// Saved number of report replay codes recorded by our `Scaffold`
+    /// when this `Hoist` transaction started.
+    reports_len: usize,
+
     /// Saved clone of header orientation along with associated key
     /// material encoded by our `Scaffold` when this `Hoist`
     /// transaction started.
@@ -542,6 +639,7 @@ impl<'s,'a,C,P,R> Hoist<'s,'a,C,P,R>
         let mut eaten = 0usize;
         let mut extra = 0usize;
         let l = instrustion.beta_length();
+        let mut kem = 0usize;
 
         { // p
         let mut p = |c: PreCommand<usize>| {
@@ -557,6 +655,10 @@ impl<'s,'a,C,P,R> Hoist<'s,'a,C,P,R>
                 let (twig,gamma) = s.add_ratchet(branch) ?;
                 p(Command::Ratchet { twig, gamma });
             },
+            Instruction::Bootstrap { } => {
+                let (twig,gamma) = s.add_bootstrap() ?;
+                p(Command::Ratchet { twig, gamma });
+            },
             Instruction::CrossOver { surb: PreHeader { validity, route, alpha, gamma, beta } } => {
                 s.intersect_validity(Some(&validity)) ?;
                 extra = beta.len();
@@ -566,22 +668,50 @@ impl<'s,'a,C,P,R> Hoist<'s,'a,C,P,R>
                 p(Command::Contact { }),
             Instruction::Greeting { } => 
                 p(Command::Greeting { }),
+            Instruction::Hybrid { } => {
+                match s.commands.last() {
+                    None | Some(&Command::Transmit { .. }) => { },
+                    _ => return Err( SphinxError::InternalError("Hybrid instruction must follow transmit") ),
+                }
+                let (ciphertext,gamma) = s.add_hybrid() ?;
+                kem = ciphertext.len();
+                p(Command::Hybrid { ciphertext, gamma });
+            },
             Instruction::Deliver { mailbox } =>
                 p(Command::Deliver { mailbox }),
+            Instruction::Report { surb: PreHeader { validity, route, alpha, gamma, beta } } => {
+                // Our report precedes the next command processed by
+                // the current last cipher.
+                if let Some(&Command::Report { .. }) = s.commands.last() {
+                    return Err( SphinxError::InternalError("Repeated report instruction") );
+                }
+                s.intersect_validity(Some(&validity)) ?;
+                let id = s.ciphers.last().expect("Scaffold always has a first cipher.").replay_code().error_packet_id();
+                s.reports.push(id);
+                p(Command::Report { route, alpha, gamma, surb_beta: beta });
+            },
             Instruction::ArrivalSURB { } =>
                 p(Command::ArrivalSURB { }),
+            Instruction::DropOff { slot } => {
+                // Our drop off precedes the next command processed by
+                // the current last cipher, like reports.
+                if let Some(&Command::DropOff { .. }) = s.commands.last() {
+                    return Err( SphinxError::InternalError("Repeated drop off instruction") );
+                }
+                p(Command::DropOff { slot });
+            },
             Instruction::ArrivalDirect { } => 
                 p(Command::ArrivalDirect { }),
-            // Instruction::DropOff { } => 
-            //     p(Command::DropOff { },
-            // Instruction::Delete { } => 
-            //     p(Command::Delete { },
+            Instruction::Delete { mailbox, packet_name, tag } =>
+                p(Command::Delete { mailbox, packet_name, tag }),
+            Instruction::Extension { opcode, data } =>
+                p(Command::Extension { opcode, data }),
             // Instruction::Dummy { } => 
             //     p(Command::Dummy { },
         }
         } // p
 
-        debug_assert_eq!(l,eaten);
+        debug_assert_eq!(l+kem,eaten);
         if eaten - extra  > P::MAX_BETA_TAIL_LENGTH {
             return Err( SphinxError::InternalError("Command exceeded beta tail length") );
         }
@@ -614,126 +744,126 @@ impl<'s,'a,C,P,R> Drop for Hoist<'s,'a,C,P,R>
     /// There is no way to repair an `Option<Vec<T>>` converted into
     /// `None` though, so no transaction may do that.
     fn drop(&mut self) {
-        let Hoist { ref mut s, ref saved_v, commands_len, advances_len, ciphers_len, ref mut orientation } = *self;
+        let Hoist { ref mut s, ref saved_v, commands_len, advances_len, ciphers_len, reports_len, ref mut orientation } = *self;
         s.v.clone_from(saved_v);
         s.commands.truncate(commands_len);
-        s.advances.truncate(advances_len);
+        s.advances.rollback(advances_len);
         s.ciphers.truncate(ciphers_len);
+        s.reports.truncate(reports_len);
         ::std::mem::swap(&mut s.orientation, orientation);
     }
 }
 
 
-impl<'a,C,P,R> Scaffold<'a,C,P,R>
-  where C: Concensus+'a, P: Params, R: Rng {
-    /// Write fully encrypted tails `phi` to `beta`.
-    ///
-    /// We place the SURB for a cross over point inside `beta` so
-    /// our SURB's `beta` must be shorter, so our cross over points
-    /// zeros the remainder of `beta` not coming form the SURB and
-    /// processes a hop.  We must plan for this zeroing behavior
-    /// when creating SURBs by treating that portion of `beta` like
-    /// a tail `phi` from the Sphinx paper.  We specify the initial
-    /// stream cipher offset into `beta` with `offset` for this
-    /// purpose.
-    ///
-    /// TODO: Right now, we construct these tails inside a single
-    /// enlarged `beta` buffer.  We put in the commands with their
-    /// `gamma` values into this same buffer as we construct the
-    /// final `beta` with onion encryption.  This makes the system
-    /// conceptially cleaner, but increases the stream cipher load
-    /// by 25% for forward packets containing SURBs, or 75% more
-    /// when creating a SURB.  We should improve performance by 
-    /// returning a `Vec<Vec<u8>>` of seperate tails here, and making
-    /// `create_gamma` take beta in two parts, and refactoring
-    /// everything else acordingly.
-    fn do_beta_tails(&mut self, mut offset: usize, beta_tail: &mut [u8])
-      -> SphinxResult<()> {
-        let mut length = beta_tail.len() - self.v.eaten;
-        let mut tail = 0;
-        let mut j = 0;
-        for c in self.commands.iter() {
-            tail += c.command_length();
-            let g = if let Some(g) = c.get_gamma() { g } else { continue; };
-            debug_assert_eq!(g,j+1);  // Test that every cipher gets used
-            self.ciphers[j].xor_beta(&mut beta_tail[..length],offset,tail) ?;
-            offset -= tail;
-            length += tail;
-            tail = 0;
-            j = g;
+/// Onion encrypt `commands` into beta for the sub-hops `ciphers`,
+/// returning beta and the `Gamma` for `ciphers[0]`.
+///
+/// Commands for `ciphers[j]` run through the command whose gamma
+/// indexes `ciphers[j+1]`.  Any hop `j` receives a beta whose final
+/// bytes were produced as tails by previous hops, so we compute these
+/// fillers `fillers[j]` seperately, encrypt only the head of beta for
+/// each hop, and MAC the head and filler as two parts.
+///
+/// We place the SURB for a cross over point inside `beta` so our
+/// SURB's `beta` must be shorter, so our cross over point zeros the
+/// remainder of `beta` not coming form the SURB and processes a hop.
+/// We plan for this zeroing behavior when creating SURBs, indicated
+/// by `padding` being `None`, by treating that portion of `beta` like
+/// an initial filler, and return only the SURB's shorter `beta`.
+/// Otherwise we fill unused space with random `padding`.
+///
+/// We store the SURB's `beta` in the `CrossOver` command, so this
+/// consumes `commands`.
+fn build_beta<P,R>(commands: &mut Vec<PreCommand<usize>>,
+                   ciphers: &mut [stream::HeaderCipher<P>],
+                   padding: Option<&mut R>)
+  -> SphinxResult<(Box<[u8]>,stream::Gamma)>
+  where P: Params, R: Rng
+{
+    let beta_length = P::BETA_LENGTH;
+
+    // Amount of beta eaten by each sub-hop
+    let mut eaten = vec![0usize];
+    for c in commands.iter() {
+        *eaten.last_mut().unwrap() += c.command_length();
+        if let Some(g) = c.get_gamma() {
+            debug_assert_eq!(g, eaten.len());  // Test that every cipher gets used
+            eaten.push(0);
         }
-        self.ciphers[j].xor_beta(&mut beta_tail[..length],offset,0) ?;
-        Ok(())
+    }
+    if eaten.len() != ciphers.len() {
+        return Err( SphinxError::InternalError("Commands do not use every cipher!") );
+    }
+    let total: usize = eaten.iter().sum();
+    if total > beta_length {
+        return Err( SphinxError::InternalError("Commands exceed beta!") );
+    }
+    let stored = if padding.is_some() { beta_length } else { total };
+
+    // Compute each sub-hop's filler from the previous filler and tail.
+    let mut fillers: Vec<Vec<u8>> = Vec::with_capacity(ciphers.len());
+    fillers.push(vec![0u8; beta_length - stored]);
+    for j in 1..ciphers.len() {
+        let mut f = fillers[j-1].clone();
+        let l = f.len();
+        f.resize(l+eaten[j-1], 0);
+        ciphers[j-1].xor_beta(&mut f, beta_length-l, eaten[j-1]) ?;  // InternalError
+        fillers.push(f);
     }
 
-    /// Write commands with correct `gamma` tags to `beta`.
-    ///
-    /// We store the SURB's `beta` in the `CrossOver` command,
-    /// so this must consume `self.commands`, and hence it may
-    /// only be called once by `done`.
-    fn do_beta_with_gammas(&mut self, beta: &mut [u8]) -> SphinxResult<stream::Gamma> {
-        // We insert our newly created PreCommand<Gamma> directly
-        // into `beta` and do not constrcut a Vec<PreCommand<Gamma>>.
-        let mut j = None;  // Previous ciphers index for testing in debug mode.
-        let mut o = self.v.eaten;
-        let mut tail = 0;
-        while let Some(c) = self.commands.pop() {
-            let l = c.command_length();
-            let c: PreCommand<stream::Gamma> = c.map_gamma( |g| {
-                // Test that every cipher gets used
-                debug_assert_eq!(g+1, j.unwrap_or(self.ciphers.len()) );
-                j = Some(g);
-                // Test that the tail gets zeroed, but only in debug mode
-                if ! cfg!(test) { tail=0; }
-                let beta = &mut beta[o..o+P::BETA_LENGTH+tail];
-                self.ciphers[g].xor_beta(beta,0,tail) ?;
-                tail=0;
-                // if let Ratchet { twig, gamma } = c { ??.push(twig) }
-                self.ciphers[g].create_gamma(beta)
-            } ) ?;
-            tail += l;
-            o -= l;
-            c.write_command(&mut beta[o..o+l]);
-        }
-        debug_assert!(j == Some(1) || j == None);
-        self.ciphers[0].xor_beta(beta,0,tail) ?;
-        // Test that the tail gets zeroed, but only in debug mode
-        if cfg!(test) {
-            assert!( beta.iter().skip(P::BETA_LENGTH).all(|x| *x==0) );
-        }
-        self.ciphers[0].create_gamma(&beta)
+    // Sub-hop `j` finds its commands at `beta[offsets[j]..]`.
+    let mut offsets = Vec::with_capacity(ciphers.len());
+    let mut o = 0;
+    for e in eaten.iter() { offsets.push(o);  o += e; }
+
+    let mut beta = vec![0u8; stored];
+    if let Some(rng) = padding { rng.fill_bytes(&mut beta[total..]); }
+
+    // We insert our newly created PreCommand<Gamma> directly into
+    // `beta` and do not constrcut a Vec<PreCommand<Gamma>>.
+    let mut o = total;
+    while let Some(c) = commands.pop() {
+        let l = c.command_length();
+        let c: PreCommand<stream::Gamma> = c.map_gamma( |g| {
+            debug_assert_eq!(o, offsets[g]);
+            let head = &mut beta[o..];
+            ciphers[g].xor_beta_head(head) ?;
+            ciphers[g].create_gamma_parts(head, &fillers[g])
+        } ) ?;
+        o -= l;
+        c.write_command(&mut beta[o..o+l]);
     }
+    debug_assert_eq!(o, 0);
+    ciphers[0].xor_beta_head(&mut beta) ?;
+    let gamma = ciphers[0].create_gamma_parts(&beta, &fillers[0]) ?;
+    Ok(( beta.into_boxed_slice(), gamma ))
+}
 
+impl<'a,C,P,R> Scaffold<'a,C,P,R>
+  where C: Concensus+'a, P: Params, R: Rng {
     pub fn done(mut self) -> SphinxResult<NewHeader<P>> {
-        let eaten = self.v.eaten;
-        let mut beta = vec![0u8; P::BETA_LENGTH+eaten];
-        match self.orientation {
+        let (beta,gamma) = match self.orientation {
             // Unknown {..} => return Err( SphinxError::InternalError("Cannot build header without knowing if sending or recieving") );
-            Orientation::Send {..} | Orientation::SendAndSURB {..} => {
-                self.rng.fill_bytes(&mut beta[eaten..P::BETA_LENGTH]);
-                self.do_beta_tails(P::BETA_LENGTH, &mut beta[P::BETA_LENGTH..]) ?;
-            },
-            Orientation::SURB {..} => {
-                // Padding ??
-                // self.rng.fill_bytes(&mut beta[eaten..???]);
-                self.do_beta_tails(eaten, &mut beta[eaten..]) ?;
-            },
-        }
-        let gamma = self.do_beta_with_gammas(beta.as_mut()) ?;
+            Orientation::Send {..} | Orientation::SendAndSURB {..} =>
+                build_beta(&mut self.commands, &mut self.ciphers, Some(&mut self.rng)) ?,
+            Orientation::SURB {..} =>
+                build_beta(&mut self.commands, &mut self.ciphers, None::<&mut R>) ?,
+        };
 
-        let Scaffold { v, orientation, mut advances, mut ciphers, .. } = self;
-        let Values { route, alpha0, validity, .. } = v;
+        // Fuzz validity to prevent leaking route information
+        let validity = P::VALIDITY_POLICY.fuzz(&mut self.rng, &self.v.validity)
+          .ok_or( SphinxError::InternalError("Validity too short for validity policy") ) ?;
+
+        let now = self.world.clock.now();
+        let Scaffold { v, orientation, mut advances, mut ciphers, reports, .. } = self;
+        let Values { route, alpha0, delay, .. } = v;
 
-        // TODO: Fuzz validity to prevent leaking route information
         let preheader = PreHeader {
             validity: validity,
             route: route.start,
             alpha: alpha0,
             gamma,
-            beta: {
-                beta.truncate(P::BETA_LENGTH);
-                beta.into_boxed_slice()
-            },
+            beta,
         };
 
         let orientation = orientation.map(
@@ -744,8 +874,8 @@ impl<'a,C,P,R> Scaffold<'a,C,P,R>
                 hops: surbs
             }
         );
-        for mut t in advances.drain(..) { t.confirm() ?; }
-        Ok( NewHeader { preheader, orientation } )
+        advances.confirm(now) ?;  // RatchetError
+        Ok( NewHeader { preheader, orientation, delay, reports } )
     }
 }
 
@@ -754,6 +884,77 @@ impl<'a,C,P,R> Scaffold<'a,C,P,R>
 pub struct NewHeader<P: Params> {
     preheader: PreHeader,
     orientation: HeaderOrientation<P>,
+
+    /// Expected delay imposed by the mix nodes along our route.
+    delay: Duration,
+
+    /// Ids identifying our packet in any error reports.
+    reports: Vec<ErrorPacketId>,
+}
+
+impl<P: Params> NewHeader<P> {
+    /// Expected delay before our packet reaches its final hop,
+    /// assuming every hop queues it as instructed.
+    pub fn delay(&self) -> Duration { self.delay }
+
+    /// Ids with which hops identify our packet when reporting errors,
+    /// for registration with a `report::ErrorCorrelator`.
+    pub fn report_ids(&self) -> &[ErrorPacketId] { &self.reports }
+
+    /// Encode our header for sending along with a body sealed by
+    /// `seal_body`.
+    pub fn encode_header<R: Rng>(&self, rng: &mut R) -> SphinxResult<Box<[u8]>> {
+        layout::encode_header::<P,R>(rng, self.preheader.clone())  // InternalError
+    }
+
+    /// Split a SURB into the `PreHeader` we hand out and the
+    /// `DeliverySURB` key material we archive for unwinding.
+    pub fn into_surb(self) -> SphinxResult<(PreHeader,surbs::DeliverySURB)> {
+        match self.orientation {
+            Orientation::SURB { surb_keys } => Ok(( self.preheader, surb_keys )),
+            _ => Err( SphinxError::InternalError("Header is not a SURB.") ),
+        }
+    }
+
+    /// Frame `payload` into a body of length `P::BODY_LENGTHS[i]` and
+    /// onion encrypt it for sending with our header.
+    ///
+    /// Anyone replying with a SURB should merely frame their payload
+    /// using `body::frame_body` because SURB unwinding does the rest.
+    pub fn seal_body(&self, i: usize, payload: &[u8]) -> SphinxResult<Box<[u8]>> {
+        let bodies = match self.orientation {
+            Orientation::Send { ref bodies } => bodies,
+            Orientation::SendAndSURB { ref bodies, .. } => bodies,
+            Orientation::SURB { .. } =>
+                return Err( SphinxError::InternalError("Cannot seal a body for a SURB.") ),
+        };
+        if i >= P::BODY_LENGTHS.len() {
+            return Err( SphinxError::BadLength("Unapproaved body length index", i) );
+        }
+        let mut body = P::boxed_zeroed_body(i);
+        body::frame_body(payload, &mut body) ?;  // BadLength
+        // The first hop removes the outermost layer.
+        for c in bodies.iter().rev() { c.encrypt(&mut body) ?; }
+        Ok(body)
+    }
+}
+
+/// Seal each fragment produced by `fragment::fragment_message` into
+/// a body of length `P::BODY_LENGTHS[i]`, sending each with a header
+/// built independently by `build`.
+///
+/// We build a fresh header for every fragment so that an observer
+/// cannot link fragments by their route.
+pub fn seal_fragments<P,F>(fragments: &[Box<[u8]>], i: usize, mut build: F)
+  -> SphinxResult<Vec<(NewHeader<P>,Box<[u8]>)>>
+  where P: Params, F: FnMut() -> SphinxResult<NewHeader<P>> {
+    let mut r = Vec::with_capacity(fragments.len());
+    for f in fragments.iter() {
+        let h = build() ?;
+        let body = h.seal_body(i,f) ?;  // BadLength
+        r.push((h,body));
+    }
+    Ok(r)
 }
 
 
@@ -775,3 +976,226 @@ struct Client<P: Params, C: Concensus> {
 
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{OsRng, Rng, ChaChaRng, SeedableRng};
+    use super::super::layout::HeaderMuts;
+    use super::super::commands::CommandNode;
+    use super::super::stream::{ChaChaKnN,Gamma,HeaderCipher};
+    use super::super::params::ChatParams;
+    use super::super::testing::TestNet;
+    use ratchet::TWIG_ID_LENGTH;
+    use ::state::HasherState;
+    use super::*;
+
+    fn keys<R: Rng>(r: &mut R, n: usize) -> Vec<ChaChaKnN> {
+        (0..n).map( |_| ChaChaKnN { key: r.gen(), nonce: r.gen() } ).collect()
+    }
+
+    fn commands(last: PreCommand<usize>) -> Vec<PreCommand<usize>> {
+        vec![
+            Command::DropOff { slot: SlotName([5u8; SLOT_NAME_LENGTH]) },
+            Command::Transmit { route: RoutingName([1u8; 16]), gamma: 1 },
+            Command::Ratchet { twig: TwigId::from_bytes(&[3u8; TWIG_ID_LENGTH]), gamma: 2 },
+            Command::Transmit { route: RoutingName([2u8; 16]), gamma: 3 },
+            last,
+        ]
+    }
+
+    /// Process `beta` exactly like nodes, but without any key exchange,
+    /// followed by zeros if `beta` came from a SURB.
+    fn process(keys: &[ChaChaKnN], beta: &[u8], gamma: Gamma) -> Vec<CommandNode> {
+        let mut h = ChatParams::boxed_zeroed_header();
+        let mut refs = HeaderMuts::<ChatParams>::new_sliced(&mut h).unwrap();
+        refs.beta[..beta.len()].copy_from_slice(beta);
+        *refs.gamma = gamma.0;
+        let mut r = Vec::new();
+        for k in keys.iter() {
+            let mut hop = k.header_cipher::<ChatParams>().unwrap();
+            refs.verify_gamma(&hop).unwrap();
+            let (c,_) = refs.peal_beta(&mut hop).unwrap();
+            match c {
+                Command::Transmit { gamma, .. } | Command::Ratchet { gamma, .. } => *refs.gamma = gamma.0,
+                _ => { },
+            }
+            r.push(c);
+        }
+        r
+    }
+
+    #[test]
+    fn beta_survives_processing() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let keys = keys(&mut r, 4);
+        let mailbox = MailboxName([9u8; MAILBOX_NAME_LENGTH]);
+
+        let mut ciphers: Vec<_> = keys.iter().map( |k| k.header_cipher::<ChatParams>().unwrap() ).collect();
+        let mut c = commands(Command::Deliver { mailbox });
+        let (beta,gamma) = build_beta(&mut c, &mut ciphers, Some(&mut r)).unwrap();
+        assert_eq!(beta.len(), ChatParams::BETA_LENGTH);
+        match process(&keys, &beta, gamma).pop().unwrap() {
+            Command::Deliver { mailbox: m } => assert_eq!(m, mailbox),
+            c => panic!("Wrong final command {:?}", c),
+        }
+
+        // SURBs leave the remainder of beta to be zeroed.
+        let mut ciphers: Vec<_> = keys.iter().map( |k| k.header_cipher::<ChatParams>().unwrap() ).collect();
+        let mut c = commands(Command::ArrivalSURB { });
+        let (beta,gamma) = build_beta(&mut c, &mut ciphers, None::<&mut OsRng>).unwrap();
+        assert!(beta.len() < ChatParams::BETA_LENGTH);
+        match process(&keys, &beta, gamma).pop().unwrap() {
+            Command::ArrivalSURB { } => { },
+            c => panic!("Wrong final command {:?}", c),
+        }
+    }
+
+    /// Our original `Scaffold::do_beta_tails` and `do_beta_with_gammas`,
+    /// which built beta inside one enlarged buffer, kept as a reference
+    /// for `build_beta`.  We encrypt only each hop's portion of that
+    /// buffer and MAC only its first `BETA_LENGTH` bytes, as `xor_beta`
+    /// and `create_gamma` always required.
+    fn baseline_beta<R: Rng>(commands: &mut Vec<PreCommand<usize>>,
+                             ciphers: &mut [HeaderCipher<ChatParams>],
+                             padding: Option<&mut R>)
+      -> SphinxResult<(Box<[u8]>,Gamma)> {
+        let beta_length = ChatParams::BETA_LENGTH;
+        let eaten: usize = commands.iter().map( |c| c.command_length() ).sum();
+        let mut beta = vec![0u8; beta_length+eaten];
+        let mut offset = match padding {
+            Some(rng) => { rng.fill_bytes(&mut beta[eaten..beta_length]);  beta_length },
+            None => eaten,
+        };
+        {
+            let beta_tail = &mut beta[offset..];
+            let mut length = beta_tail.len() - eaten;
+            let mut tail = 0;
+            let mut j = 0;
+            for c in commands.iter() {
+                tail += c.command_length();
+                let g = if let Some(g) = c.get_gamma() { g } else { continue; };
+                ciphers[j].xor_beta(&mut beta_tail[..length],offset,tail) ?;
+                offset -= tail;
+                length += tail;
+                tail = 0;
+                j = g;
+            }
+            ciphers[j].xor_beta(&mut beta_tail[..length],offset,0) ?;
+        }
+        let mut o = eaten;
+        let mut tail = 0;
+        while let Some(c) = commands.pop() {
+            let l = c.command_length();
+            let c: PreCommand<Gamma> = c.map_gamma( |g| {
+                let beta = &mut beta[o..o+beta_length+tail];
+                ciphers[g].xor_beta(beta,0,tail) ?;
+                tail = 0;
+                ciphers[g].create_gamma(&beta[..beta_length])
+            } ) ?;
+            tail += l;
+            o -= l;
+            c.write_command(&mut beta[o..o+l]);
+        }
+        ciphers[0].xor_beta(&mut beta[..beta_length+tail],0,tail) ?;
+        // Every tail gets zeroed.
+        assert!( beta.iter().skip(beta_length).all(|x| *x==0) );
+        let gamma = ciphers[0].create_gamma(&beta[..beta_length]) ?;
+        beta.truncate(beta_length);
+        Ok(( beta.into_boxed_slice(), gamma ))
+    }
+
+    #[test]
+    fn beta_matches_baseline() {
+        let keys = keys(&mut ChaChaRng::from_seed(&[0x6b657973]), 4);
+        let seed = [0x70616464u32];
+        let ciphers = |n: usize| -> Vec<HeaderCipher<ChatParams>> {
+            keys[..n].iter().map( |k| k.header_cipher::<ChatParams>().unwrap() ).collect()
+        };
+        let mailbox = MailboxName([9u8; MAILBOX_NAME_LENGTH]);
+        let cases: Vec<(usize, fn(MailboxName) -> Vec<PreCommand<usize>>)> = vec![
+            (4, |m| commands(Command::Deliver { mailbox: m })),
+            (4, |_| commands(Command::ArrivalSURB { })),
+            (1, |_| vec![ Command::ArrivalDirect { } ]),
+        ];
+        for &(n, ref f) in cases.iter() {
+            let (beta0,gamma0) = baseline_beta(&mut f(mailbox), &mut ciphers(n),
+                Some(&mut ChaChaRng::from_seed(&seed))).unwrap();
+            let (beta1,gamma1) = build_beta(&mut f(mailbox), &mut ciphers(n),
+                Some(&mut ChaChaRng::from_seed(&seed))).unwrap();
+            assert_eq!(beta0, beta1);
+            assert_eq!(gamma0, gamma1);
+
+            // SURBs now omit the trailing zeros their cross over point restores.
+            let (beta0,gamma0) = baseline_beta(&mut f(mailbox), &mut ciphers(n), None::<&mut OsRng>).unwrap();
+            let (beta1,gamma1) = build_beta(&mut f(mailbox), &mut ciphers(n), None::<&mut OsRng>).unwrap();
+            assert_eq!(&beta0[..beta1.len()], &*beta1);
+            assert!( beta0[beta1.len()..].iter().all(|x| *x==0) );
+            assert_eq!(gamma0, gamma1);
+        }
+    }
+
+    #[test]
+    fn beta_detects_tampering() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let keys = keys(&mut r, 4);
+        let mut ciphers: Vec<_> = keys.iter().map( |k| k.header_cipher::<ChatParams>().unwrap() ).collect();
+        let mut c = commands(Command::ArrivalDirect { });
+        let (beta,gamma) = build_beta(&mut c, &mut ciphers, Some(&mut r)).unwrap();
+        let mut beta = beta.into_vec();
+        let i = r.gen_range(0,beta.len());
+        beta[i] ^= 1;
+        let hop = keys[0].header_cipher::<ChatParams>().unwrap();
+        assert!( hop.verify_gamma(&beta, &gamma).is_err() );
+    }
+
+    #[test]
+    fn bootstrap_created_on_confirm() {
+        let net = TestNet::<ChatParams>::new(1);
+        let mut ratchets = ClientRatchetState::new();
+        ratchets.insert(net.issuers[0], ::ratchet::State::new(HasherState::new()));
+        let count = |r: &ClientRatchetState| {
+            let s = &r[&net.issuers[0]];
+            (s.branches.read().unwrap().len(), s.parents.read().unwrap().len(), s.twigs.read().unwrap().len())
+        };
+
+        // A bootstrap we never approve leaves nothing behind.
+        {
+            let world = net.world(&ratchets);
+            let b = world.build_headers(OsRng::new().expect("failed to create an OS RNG")).long();
+            let mut s = b.go(net.names[0]).unwrap();
+            {
+                let mut h = s.add();
+                h.instruct(Instruction::Bootstrap { }).unwrap();
+            }
+            {
+                let mut h = s.add();
+                h.instruct(Instruction::ArrivalDirect { }).unwrap();
+                h.approve();
+            }
+            s.done().unwrap();
+        }
+        assert_eq!( count(&ratchets), (0,0,0) );
+
+        let h = net.build(&net.world(&ratchets), false, 0,
+            vec![ Instruction::Bootstrap { }, Instruction::ArrivalDirect { } ]).unwrap();
+        assert_eq!( count(&ratchets).0, 1 );
+        net.send(0, &h, b"Hello").unwrap();
+        assert_eq!( net.routers[0].arrivals().read().unwrap().len(), 1 );
+    }
+
+    #[test]
+    fn surbs_refuse_ratchet_subhops() {
+        let net = TestNet::<ChatParams>::new(1);
+        let mut ratchets = ClientRatchetState::new();
+        ratchets.insert(net.issuers[0], ::ratchet::State::new(HasherState::new()));
+        let world = net.world(&ratchets);
+
+        assert!( net.build(&world, true, 0,
+            vec![ Instruction::Bootstrap { }, Instruction::ArrivalSURB { } ]).is_err() );
+        assert!( ratchets[&net.issuers[0]].branches.read().unwrap().is_empty() );
+        net.build(&world, false, 0,
+            vec![ Instruction::Bootstrap { }, Instruction::ArrivalDirect { } ]).unwrap();
+        assert_eq!( ratchets[&net.issuers[0]].branches.read().unwrap().len(), 1 );
+    }
+}
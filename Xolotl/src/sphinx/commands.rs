@@ -12,7 +12,8 @@ pub use ratchet::{TwigId,TWIG_ID_LENGTH};
 use keys::{RoutingName,ROUTING_NAME_LENGTH}; // RoutingNameBytes
 use curve::{AlphaBytes,ALPHA_LENGTH};
 use super::stream::{Gamma,GAMMA_LENGTH}; // GammaBytes
-use super::mailbox::{MailboxName,MAILBOX_NAME_LENGTH};
+use super::mailbox::{MailboxName,MAILBOX_NAME_LENGTH,SlotName,SLOT_NAME_LENGTH,DeleteTag,DELETE_TAG_LENGTH};
+use super::extension::Extensions;
 use super::error::*;
 use super::slice::*;
 use super::*; // {PacketName,PACKET_NAME_LENGTH};
@@ -102,6 +103,13 @@ pub enum Command<G,D> where G: CommandGamma, D: CommandData {
         // unimplemented!()
     },
 
+    /// Mix a post-quantum KEM shared secret into the key for
+    /// another sub-hop, see `keys::kem`.
+    Hybrid {
+        ciphertext: Box<[u8]>,
+        gamma: G,
+    },
+
     /// Deliver message to the specified mailbox, roughly equivelent
     /// to transmition to a non-existant mix network node.
     Deliver {
@@ -109,14 +117,58 @@ pub enum Command<G,D> where G: CommandGamma, D: CommandData {
         mailbox: MailboxName,
     },
 
+    /// Request an error report if this hop drops the packet, sent
+    /// along the embedded SURB.
+    ///
+    /// We must precede another command processed by the same sub-hop,
+    /// which the node parses together with us.  Unlike `CrossOver`,
+    /// our SURB's beta counts towards `Params::MAX_BETA_TAIL_LENGTH`,
+    /// which must therefore cover even a one hop SURB.  We exceed the
+    /// tails of most presets, so senders need `ReportingChatParams`.
+    Report {
+        route: RoutingName,
+        alpha: AlphaBytes,
+        gamma: Gamma,
+        surb_beta: Box<[u8]>,
+    },
+
+    /// Park the packet in a slot on this node, instead of forwarding
+    /// it immediately, or else release packets parked there.
+    ///
+    /// We must precede another command processed by the same sub-hop,
+    /// which the node parses together with us.  Preceding `Transmit`
+    /// parks the packet until either a later packet releases it or
+    /// the node's holding time expires.  Preceding any delivery or
+    /// arrival command releases all packets parked in the slot.
+    DropOff {
+        /// Opaque slot name
+        slot: SlotName,
+    },
+
     /// Arrival of a SURB we created and archived.
     ArrivalSURB { },
 
     /// Arrival of a message for a local application.
     ArrivalDirect { },
 
-    // DropOff { },
-    // Delete { },
+    /// Delete a packet stored in a mailbox on this node, and
+    /// confirm using the SURB attached to the body.
+    Delete {
+        /// Mailbox name
+        mailbox: MailboxName,
+        /// Packet to delete
+        packet_name: PacketName,
+        /// Authenticates the deletion, see `MailboxKey::delete_tag`.
+        tag: DeleteTag,
+    },
+
+    /// Extension command using an opcode reserved for extensions,
+    /// see the `extension` module.
+    Extension {
+        opcode: u8,
+        data: Box<[u8]>,
+    },
+
     // Dummy { },
 }
 
@@ -153,14 +205,28 @@ impl<G: CommandGamma,D: CommandData> Command<G,D> {
                 f(&[ &[0x60u8; 1], unimplemented!() ]),
             Greeting { } => 
                 f(&[ &[0x61u8; 1], unimplemented!() ]),
+            Hybrid { ref ciphertext, ref gamma } => {
+                let l = ciphertext.len();
+                debug_assert!(l <= 0xFFFF);
+                f(&[ &[0x62u8, l as u8, (l >> 8) as u8], ciphertext, gamma.gamma() ])
+            },
             Deliver { mailbox } =>
                 f(&[ &[0x50u8; 1], &mailbox.0 ]),
-            // DropOff
+            DropOff { slot } =>
+                f(&[ &[0x51u8; 1], &slot.0 ]),
+            Report { route, alpha, ref gamma, ref surb_beta } => {
+                let l = surb_beta.len();
+                debug_assert!(l <= 0xFFFF);
+                f(&[ &[0x52u8, l as u8, (l >> 8) as u8], &route.0, &alpha, &gamma.0, surb_beta ])
+            },
             ArrivalSURB { } => 
                 f(&[ &[0x70u8; 1] ]),
             ArrivalDirect { } =>
                 f(&[ &[0x71u8; 1] ]),
-            // Delete
+            Delete { mailbox, packet_name, tag } =>
+                f(&[ &[0x7Fu8; 1], &mailbox.0, &packet_name.0, &tag.0 ]),
+            Extension { opcode, ref data } =>
+                f(&[ &[opcode; 1], data ]),
         }
     }
 
@@ -198,32 +264,45 @@ impl CommandNode {
     /// We only return the SURBs length and do not seperate it
     /// because this only gets called from `peal_beta` which leaves
     /// the SURB in place.
-    pub fn parse(mut beta: &[u8]) -> SphinxResult<(CommandNode,usize)> {
+    pub fn parse(beta: &[u8]) -> SphinxResult<(CommandNode,usize)> {
+        Command::parse_extended(beta,None)
+    }
+
+    /// Read a command from the start of beta, like `parse`, but also
+    /// recognize commands registered in `extensions`.
+    pub fn parse_extended(mut beta: &[u8], extensions: Option<&Extensions>)
+      -> SphinxResult<(CommandNode,usize)> {
         use self::Command::*;
         let beta_len = beta.len();
         // We could tweak RoutingName or TwigId to shave off one byte
         // eventually, but sounds like premature optimization now.
-        let b0 = reserve_fixed!(&mut beta,1)[0];
+        let b0 = try_reserve_fixed!(&mut beta,1)[0];
+        // Registration ensures extensions never override core commands.
+        if let Some(e) = extensions.and_then(|x| x.get(b0)) {
+            let l = e.length(beta) ?;  // BadLength, BadPacket
+            let data = try_reserve(&mut beta,l) ?.to_vec().into_boxed_slice();
+            return Ok(( Extension { opcode: b0, data }, beta_len-beta.len() ));
+        }
         let command = match b0 {
             // Transmit if the high bit is set.
             0x80..0xFF => Transmit {
-                route: RoutingName(*reserve_fixed!(&mut beta,ROUTING_NAME_LENGTH)),
-                gamma: Gamma(*reserve_fixed!(&mut beta,GAMMA_LENGTH)),
+                route: RoutingName(*try_reserve_fixed!(&mut beta,ROUTING_NAME_LENGTH)),
+                gamma: Gamma(*try_reserve_fixed!(&mut beta,GAMMA_LENGTH)),
             },
             // Ratchet if the two high bits are clear.
             0x00..0x3F => Ratchet {
-                twig: TwigId::from_bytes(reserve_fixed!(&mut beta,TWIG_ID_LENGTH)),
-                gamma: Gamma(*reserve_fixed!(&mut beta,GAMMA_LENGTH)),
+                twig: TwigId::from_bytes(try_reserve_fixed!(&mut beta,TWIG_ID_LENGTH)),
+                gamma: Gamma(*try_reserve_fixed!(&mut beta,GAMMA_LENGTH)),
             },
             // Anything else has the form 0b01??_????
             // CrossOver from Beta if 0b0100_????
             0x40..0x4F => CrossOver {
                 surb_beta: (
-                    (((b0 & 0x0F) as u16) << 8) | (reserve_fixed!(&mut beta,1)[0] as u16)
+                    (((b0 & 0x0F) as u16) << 8) | (try_reserve_fixed!(&mut beta,1)[0] as u16)
                 ) as usize,
-                route: RoutingName(*reserve_fixed!(&mut beta,ROUTING_NAME_LENGTH)),
-                alpha: *reserve_fixed!(&mut beta,ALPHA_LENGTH),
-                gamma: Gamma(*reserve_fixed!(&mut beta,GAMMA_LENGTH)),
+                route: RoutingName(*try_reserve_fixed!(&mut beta,ROUTING_NAME_LENGTH)),
+                alpha: *try_reserve_fixed!(&mut beta,ALPHA_LENGTH),
+                gamma: Gamma(*try_reserve_fixed!(&mut beta,GAMMA_LENGTH)),
             },
             // Authenticated cross overs have the form 0b0110_????
             0x60 => Contact {
@@ -232,19 +311,41 @@ impl CommandNode {
             0x61 => Greeting {
                 // unimplemented!()
             },
-            0x62..0x6F => { return Err( SphinxError::BadPacket("Unknown authenticated cross over command",b0 as u64)); },
+            0x62 => Hybrid {
+                ciphertext: {
+                    let l = try_reserve_fixed!(&mut beta,2);
+                    let l = (l[0] as usize) | (l[1] as usize) << 8;
+                    try_reserve(&mut beta,l) ?.to_vec().into_boxed_slice()
+                },
+                gamma: Gamma(*try_reserve_fixed!(&mut beta,GAMMA_LENGTH)),
+            },
+            0x63..0x6F => { return Err( SphinxError::BadPacket("Unknown authenticated cross over command",b0 as u64)); },
             // Deliveries have form 0b0110_????
             0x50 => Deliver {
-                mailbox: MailboxName(*reserve_fixed!(&mut beta,MAILBOX_NAME_LENGTH)),
+                mailbox: MailboxName(*try_reserve_fixed!(&mut beta,MAILBOX_NAME_LENGTH)),
             },
-            // 0x51 => DropOff { 
-            // },
-            0x51..0x5F => { return Err( SphinxError::BadPacket("Unknown deliver command",b0 as u64)); },
+            0x51 => DropOff {
+                slot: SlotName(*try_reserve_fixed!(&mut beta,SLOT_NAME_LENGTH)),
+            },
+            0x52 => {
+                let l = try_reserve_fixed!(&mut beta,2);
+                let l = (l[0] as usize) | (l[1] as usize) << 8;
+                Report {
+                    route: RoutingName(*try_reserve_fixed!(&mut beta,ROUTING_NAME_LENGTH)),
+                    alpha: *try_reserve_fixed!(&mut beta,ALPHA_LENGTH),
+                    gamma: Gamma(*try_reserve_fixed!(&mut beta,GAMMA_LENGTH)),
+                    surb_beta: try_reserve(&mut beta,l) ?.to_vec().into_boxed_slice(),
+                }
+            },
+            0x53..0x5F => { return Err( SphinxError::BadPacket("Unknown deliver command",b0 as u64)); },
             // Arivals have the form 0b0111_????
             0x70 => ArrivalSURB { },
             0x71 => ArrivalDirect { },
-            // 0x7F => Delete {
-            // },
+            0x7F => Delete {
+                mailbox: MailboxName(*try_reserve_fixed!(&mut beta,MAILBOX_NAME_LENGTH)),
+                packet_name: PacketName(*try_reserve_fixed!(&mut beta,PACKET_NAME_LENGTH)),
+                tag: DeleteTag(*try_reserve_fixed!(&mut beta,DELETE_TAG_LENGTH)),
+            },
             0x72..0x7F => { return Err( SphinxError::BadPacket("Unknown arrival command",b0 as u64)); },
             c => { return Err( SphinxError::BadPacket("Unknown command",c as u64)); },
         };
@@ -266,11 +367,15 @@ impl<G0> PreCommand<G0> where G0: CommandGamma {
               => CrossOver { route, alpha, gamma, surb_beta },
             Contact { } => Contact { },
             Greeting { } => Greeting { },
+            Hybrid { ciphertext, gamma } => Hybrid { ciphertext, gamma: f(gamma) ? },
             Deliver { mailbox } => Deliver { mailbox },
+            Report { route, alpha, gamma, surb_beta }
+              => Report { route, alpha, gamma, surb_beta },
+            DropOff { slot } => DropOff { slot },
             ArrivalSURB { } => ArrivalSURB { },
             ArrivalDirect { } => ArrivalDirect { },
-            // DropOff { } => DropOff { },
-            // Delete { } => Delete { },
+            Delete { mailbox, packet_name, tag } => Delete { mailbox, packet_name, tag },
+            Extension { opcode, data } => Extension { opcode, data },
             // Dummy { } => Dummy { },
         } )
     }
@@ -281,6 +386,7 @@ impl<G0> PreCommand<G0> where G0: CommandGamma+Clone+Copy {
         match *self {
             Command::Transmit { gamma, .. } => Some(gamma),
             Command::Ratchet { gamma, .. } => Some(gamma),
+            Command::Hybrid { gamma, .. } => Some(gamma),
             _ => None,
         }
         // We wanted to reduce this to `map_gamma` but it benifits
@@ -294,6 +400,7 @@ impl<G0> PreCommand<G0> where G0: CommandGamma {
         match *self {
             Command::Transmit { .. } => true,
             Command::Ratchet { .. } => true,
+            Command::Hybrid { .. } => true,
             _ => false,
         }
         // We can define this without needing any constraints on
@@ -320,6 +427,11 @@ pub enum Instruction {
         branch: ::ratchet::BranchId,
     },
 
+    /// Bootstrap a ratchet branch with the current hop from its
+    /// Sphinx shared secret, and advance it like `Ratchet`.
+    /// Our issuer must already have an entry in `ClientState`.
+    Bootstrap { },
+
     /// Crossover with SURB in beta
     CrossOver {
         surb: layout::PreHeader,
@@ -333,6 +445,10 @@ pub enum Instruction {
         // unimplemented!()
     },
 
+    /// Add a hybrid sub-hop using the KEM public key advertised by
+    /// the current hop.  Must directly follow that hop's `Transmit`.
+    Hybrid { },
+
     /// Deliver message to the specified mailbox, roughly equivelent
     /// to transmition to a non-existant mix network node.
     Deliver {
@@ -340,18 +456,51 @@ pub enum Instruction {
         mailbox: MailboxName,
     },
 
+    /// Request that the current hop report dropping this packet
+    /// along the specified SURB.  Must precede the instruction for
+    /// that hop.
+    Report {
+        surb: layout::PreHeader,
+    },
+
+    /// Request that the current hop park this packet in the
+    /// specified slot, or release packets parked there.  Must
+    /// precede the instruction for that hop.
+    DropOff {
+        /// Opaque slot name
+        slot: SlotName,
+    },
+
     /// Arrival of a SURB we created and archived.
     ArrivalSURB { },
 
     /// Arrival of a message for a local application.
     ArrivalDirect { },
 
-    // DropOff { },
-    // Delete { },
+    /// Delete a packet stored in a mailbox on this node, and
+    /// confirm using the SURB attached to the body.
+    Delete {
+        /// Mailbox name
+        mailbox: MailboxName,
+        /// Packet to delete
+        packet_name: PacketName,
+        /// Authenticates the deletion, see `MailboxKey::delete_tag`.
+        tag: DeleteTag,
+    },
+
+    /// Extension command using an opcode reserved for extensions,
+    /// see the `extension` module.
+    Extension {
+        opcode: u8,
+        data: Box<[u8]>,
+    },
+
     // Dummy { },
 }
 
 impl Instruction {
+    /// Length of the commands we add to beta, excluding any KEM
+    /// ciphertext whose length depends upon the `Kem`.
     pub fn beta_length(&self) -> usize {
         let gamma = Gamma([0u8; GAMMA_LENGTH]);
         let p = |c: commands::CommandNode| c.command_length();
@@ -362,22 +511,32 @@ impl Instruction {
                 let twig = ::ratchet::TwigId(branch,::ratchet::TwigIdx(0));
                 p(Command::Ratchet { twig, gamma })
             },
+            Instruction::Bootstrap { } => {
+                let twig = ::ratchet::TwigId::from_bytes(&[0u8; ::ratchet::TWIG_ID_LENGTH]);
+                p(Command::Ratchet { twig, gamma })
+            },
             Instruction::CrossOver { surb: layout::PreHeader { route, alpha, gamma, ref beta, .. } } =>
                 p(Command::CrossOver { route, alpha, gamma, surb_beta: beta.len() }) + beta.len(),
             Instruction::Contact { } =>
                 p(Command::Contact { }),
             Instruction::Greeting { } => 
                 p(Command::Greeting { }),
+            Instruction::Hybrid { } =>
+                p(Command::Hybrid { ciphertext: Box::new([]), gamma }),
             Instruction::Deliver { mailbox } =>
                 p(Command::Deliver { mailbox }),
+            Instruction::Report { surb: layout::PreHeader { route, alpha, gamma, ref beta, .. } } =>
+                p(Command::Report { route, alpha, gamma, surb_beta: beta.clone() }),
             Instruction::ArrivalSURB { } =>
                 p(Command::ArrivalSURB { }),
+            Instruction::DropOff { slot } =>
+                p(Command::DropOff { slot }),
             Instruction::ArrivalDirect { } => 
                 p(Command::ArrivalDirect { }),
-            // Instruction::DropOff { } => 
-            //     p(Command::DropOff { },
-            // Instruction::Delete { } => 
-            //     p(Command::Delete { },
+            Instruction::Delete { mailbox, packet_name, tag } =>
+                p(Command::Delete { mailbox, packet_name, tag }),
+            Instruction::Extension { ref data, .. } =>
+                1 + data.len(),
             // Instruction::Dummy { } => 
             //     p(Command::Dummy { },
         }
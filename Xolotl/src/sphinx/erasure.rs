@@ -0,0 +1,185 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Erasure coding for fragments
+//!
+//! We protect fragmented messages against packet loss with a
+//! systematic Reed-Solomon style erasure code over GF(2^8), so that
+//! any `k` of the `n` fragments reconstruct the message.  We use a
+//! generator matrix consisting of the identity stacked above a Cauchy
+//! matrix, every `k` by `k` submatrix of which is invertible.
+//!
+//! We favor simplicity over speed here since fragments are small
+//! compared with the cost of sending them through the mix network.
+
+use super::error::*;
+
+
+/// Maximum number of shards, data and parity together, in one code.
+pub const MAX_SHARDS: usize = 256;
+
+/// Log and exponent tables for GF(2^8) with the polynomial
+/// x^8 + x^4 + x^3 + x^2 + 1.
+struct Gf256 {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Gf256 {
+    fn new() -> Gf256 {
+        let mut gf = Gf256 { exp: [0u8; 512], log: [0u8; 256] };
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            gf.exp[i] = x as u8;
+            gf.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 { x ^= 0x11D; }
+        }
+        for i in 255..512 { gf.exp[i] = gf.exp[i-255]; }
+        gf
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 { return 0; }
+        self.exp[ self.log[a as usize] as usize + self.log[b as usize] as usize ]
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        debug_assert!(a != 0);
+        self.exp[ 255 - self.log[a as usize] as usize ]
+    }
+
+    /// Row `r` of our generator matrix for `k` data shards.
+    fn generator_row(&self, k: usize, r: usize) -> Vec<u8> {
+        if r < k {
+            let mut row = vec![0u8; k];
+            row[r] = 1;
+            return row;
+        }
+        // Cauchy matrix entries 1/(x_r + y_i) with x_r = r and y_i = i,
+        // which never coincide because r >= k > i.
+        (0..k).map( |i| self.inv((r as u8) ^ (i as u8)) ).collect()
+    }
+
+    /// Accumulate `c * src` into `dst`.
+    fn mul_add(&self, dst: &mut [u8], c: u8, src: &[u8]) {
+        if c == 0 { return; }
+        for (d,s) in dst.iter_mut().zip(src.iter()) { *d ^= self.mul(c,*s); }
+    }
+}
+
+fn check_shape(k: usize, n: usize) -> SphinxResult<()> {
+    if k == 0 || k > n || n > MAX_SHARDS {
+        return Err( SphinxError::BadLength("Invalid erasure code shape", n) );
+    }
+    Ok(())
+}
+
+/// Compute `n - k` parity shards for the `k` equal length data shards.
+pub fn encode_parity(data: &[&[u8]], n: usize) -> SphinxResult<Vec<Box<[u8]>>> {
+    let k = data.len();
+    check_shape(k,n) ?;
+    let len = data[0].len();
+    if data.iter().any(|d| d.len() != len) {
+        return Err( SphinxError::InternalError("Data shards differ in length") );
+    }
+    let gf = Gf256::new();
+    Ok( (k..n).map( |r| {
+        let mut p = vec![0u8; len];
+        for (c,d) in gf.generator_row(k,r).iter().zip(data.iter()) {
+            gf.mul_add(&mut p, *c, d);
+        }
+        p.into_boxed_slice()
+    } ).collect() )
+}
+
+/// Recover the `k` data shards from any `k` of the `n` shards,
+/// given as `Some` in `shards`.  We permit more than `MAX_SHARDS`
+/// shards only when all data shards are present.
+pub fn reconstruct(k: usize, shards: &[Option<Box<[u8]>>]) -> SphinxResult<Vec<Box<[u8]>>> {
+    let n = shards.len();
+    if k == 0 || k > n {
+        return Err( SphinxError::BadLength("Invalid erasure code shape", n) );
+    }
+    let rows: Vec<usize> = (0..n).filter(|r| shards[*r].is_some()).take(k).collect();
+    if rows.len() < k {
+        return Err( SphinxError::BadLength("Too few shards to reconstruct", rows.len()) );
+    }
+    let shard = |r: usize| shards[r].as_ref().unwrap();
+    let len = shard(rows[0]).len();
+    if rows.iter().any(|r| shard(*r).len() != len) {
+        return Err( SphinxError::BadPacket("Shards differ in length", len as u64) );
+    }
+    // Systematic code, so nothing to do if we hold all data shards.
+    if rows.iter().enumerate().all(|(i,r)| i == *r) {
+        return Ok( rows.iter().map(|r| shard(*r).clone()).collect() );
+    }
+    check_shape(k,n) ?;
+
+    // Invert the submatrix of our generator given by `rows` using
+    // Gauss-Jordan elimination on `[m | inv]`.
+    let gf = Gf256::new();
+    let mut m: Vec<Vec<u8>> = rows.iter().map(|r| gf.generator_row(k,*r)).collect();
+    let mut inv: Vec<Vec<u8>> = (0..k).map(|r| gf.generator_row(k,r)).collect();
+    for col in 0..k {
+        let pivot = (col..k).find(|r| m[*r][col] != 0)
+          .ok_or( SphinxError::InternalError("Singular erasure code matrix") ) ?;
+        m.swap(col,pivot);
+        inv.swap(col,pivot);
+        let c = gf.inv(m[col][col]);
+        for j in 0..k {
+            m[col][j] = gf.mul(c, m[col][j]);
+            inv[col][j] = gf.mul(c, inv[col][j]);
+        }
+        for r in 0..k {
+            if r == col || m[r][col] == 0 { continue; }
+            let f = m[r][col];
+            let (mc,ic) = (m[col].clone(), inv[col].clone());
+            gf.mul_add(&mut m[r], f, &mc);
+            gf.mul_add(&mut inv[r], f, &ic);
+        }
+    }
+
+    Ok( inv.iter().map( |row| {
+        let mut d = vec![0u8; len];
+        for (c,r) in row.iter().zip(rows.iter()) {
+            gf.mul_add(&mut d, *c, shard(*r));
+        }
+        d.into_boxed_slice()
+    } ).collect() )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{OsRng, Rng};
+    use super::*;
+
+    #[test]
+    fn erasure_survives_drops() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        for &(k,n) in [(1,3),(4,6),(10,15),(200,256)].iter() {
+            let data: Vec<Vec<u8>> = (0..k).map( |_| {
+                let mut d = vec![0u8; 64];  r.fill_bytes(&mut d);  d
+            } ).collect();
+            let refs: Vec<&[u8]> = data.iter().map(|d| &d[..]).collect();
+            let parity = encode_parity(&refs, n).unwrap();
+            let mut shards: Vec<Option<Box<[u8]>>> = data.iter()
+              .map(|d| Some(d.clone().into_boxed_slice()))
+              .chain(parity.into_iter().map(Some)).collect();
+
+            // Drop n-k random shards
+            for _ in 0..(n-k) {
+                let live: Vec<usize> = (0..n).filter(|i| shards[*i].is_some()).collect();
+                shards[ live[r.gen_range(0,live.len())] ] = None;
+            }
+            let recovered = reconstruct(k, &shards).unwrap();
+            for (a,b) in recovered.iter().zip(data.iter()) { assert_eq!(&a[..], &b[..]); }
+
+            if n > k {
+                let i = (0..n).find(|i| shards[*i].is_some()).unwrap();
+                shards[i] = None;
+                assert!( reconstruct(k, &shards).is_err() );
+            }
+        }
+    }
+}
@@ -16,25 +16,51 @@ use keys::error::KeysError;
 use ratchet::error::RatchetError;
 
 
-/// `ErrorPacketId` is a `ReplayCode` in testing and empty otherwise.
-#[cfg(not(test))]
-#[derive(Copy,Clone)]
+/// `ErrorPacketId` is a `ReplayCode` in testing or with the
+/// `error_reports` feature, and empty otherwise.
+#[cfg(not(any(test, feature = "error_reports")))]
+#[derive(Copy,Clone,Default,PartialEq,Eq,Hash)]
 pub struct ErrorPacketId;
 
-/// `ErrorPacketId` is a `ReplayCode` in testing and empty otherwise.
-#[cfg(test)]
-pub type ErrorPacketId = ReplayCode;
+/// `ErrorPacketId` is a `ReplayCode` in testing or with the
+/// `error_reports` feature, and empty otherwise.  We wrap the
+/// `ReplayCode` so that error messages scrub it outside testing.
+#[cfg(any(test, feature = "error_reports"))]
+#[derive(Copy,Clone,Default,PartialEq,Eq,Hash)]
+pub struct ErrorPacketId(pub [u8; ERROR_PACKET_ID_LENGTH]);
 
-#[cfg(not(test))]
+/// Length of an `ErrorPacketId` in error reports.
+#[cfg(not(any(test, feature = "error_reports")))]
+pub const ERROR_PACKET_ID_LENGTH : usize = 0;
+
+/// Length of an `ErrorPacketId` in error reports.
+#[cfg(any(test, feature = "error_reports"))]
+pub const ERROR_PACKET_ID_LENGTH : usize = super::replay::REPLAY_CODE_LENGTH;
+
+#[cfg(not(any(test, feature = "error_reports")))]
 impl ReplayCode {
     pub fn error_packet_id(&self) -> ErrorPacketId { ErrorPacketId }
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "error_reports"))]
 impl ReplayCode {
-    pub fn error_packet_id(&self) -> ErrorPacketId { *self }
+    pub fn error_packet_id(&self) -> ErrorPacketId { ErrorPacketId(self.0) }
+}
+
+#[cfg(not(any(test, feature = "error_reports")))]
+impl ErrorPacketId {
+    pub fn to_bytes(&self) -> [u8; ERROR_PACKET_ID_LENGTH] { [] }
+    pub fn from_bytes(_: &[u8; ERROR_PACKET_ID_LENGTH]) -> ErrorPacketId { ErrorPacketId }
 }
 
+#[cfg(any(test, feature = "error_reports"))]
+impl ErrorPacketId {
+    pub fn to_bytes(&self) -> [u8; ERROR_PACKET_ID_LENGTH] { self.0 }
+    pub fn from_bytes(b: &[u8; ERROR_PACKET_ID_LENGTH]) -> ErrorPacketId { ErrorPacketId(*b) }
+}
+
+/// Scrub packet details from error messages, even with the
+/// `error_reports` feature, so that nodes never log replay codes.
 #[cfg(not(test))]
 impl fmt::Debug for ErrorPacketId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -42,6 +68,14 @@ impl fmt::Debug for ErrorPacketId {
     }
 }
 
+#[cfg(test)]
+impl fmt::Debug for ErrorPacketId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use hex::ToHex;
+        write!(f, "ErrorPacketId({:})", self.0.to_hex())
+    }
+}
+
 
 #[derive(Debug, Clone)]
 pub enum SphinxError {
@@ -52,11 +86,13 @@ pub enum SphinxError {
     KeysError(KeysError),
     Replay(ErrorPacketId), 
     InvalidMac(ErrorPacketId),
+    TaggingAttack,
     BadAlpha([u8; 32]),
     BadPacket(&'static str,u64),
     BadPacketName(PacketName),
     ConcensusLacking(&'static str),
     IssuerHasNoRatchet(::keys::IssuerPublicKey),
+    Backpressure(&'static str),
 }
 
 pub type SphinxResult<T> = Result<T,SphinxError>;
@@ -80,6 +116,8 @@ impl fmt::Display for SphinxError {
                 => write!(f, "Replay attack detected on {:?}.", id),
             InvalidMac(id)
                 => write!(f, "Invalid MAC with {:?}.", id),
+            TaggingAttack
+                => write!(f, "Body failed authentication, probably a tagging attack."),
             BadAlpha(alpha)
                 => write!(f, "Invalid Alpha {}.", alpha.to_hex()),
             BadPacket(s,v)
@@ -90,6 +128,8 @@ impl fmt::Display for SphinxError {
                 => write!(f, "Bad packet name {}.", s),
             IssuerHasNoRatchet(i)
                 => write!(f, "Issuer {} has no ratchet for us.", i.0.to_hex()),
+            Backpressure(s)
+                => write!(f, "Refused packet due to backpressure: {}.", s),
         }
     }
 }
@@ -109,11 +149,13 @@ impl Error for SphinxError {
             RatchetError(ref e) => e.cause(),
             Replay(_) => None,
             InvalidMac(_) => None,
+            TaggingAttack => None,
             BadAlpha(_) => None,
             BadPacket(_,_) => None,
             BadPacketName(_) => None,
             ConcensusLacking(_) => None,
             IssuerHasNoRatchet(_) => None,
+            Backpressure(_) => None,
         }
     }
 }
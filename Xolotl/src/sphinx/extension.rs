@@ -0,0 +1,170 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Extension commands for prototyping new sub-hops
+//!
+//! `Command::parse` rejects opcodes in several reserved ranges.
+//! We let applications claim opcodes in these ranges by registering
+//! an `Extension` with the `Router`, and build headers containing
+//! them using `Instruction::extension`.  Extension commands end
+//! processing of beta like deliveries, so they cannot forward the
+//! packet themselves, but they may reply with a fresh packet.
+//!
+//! We never permit extensions to override the core commands.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::commands::Instruction;
+use super::mailbox::{MailboxName,OutgoingPacket};
+use super::error::*;
+use super::*;
+
+
+/// Is `opcode` reserved for extension commands?
+pub fn is_extension_opcode(opcode: u8) -> bool {
+    match opcode {
+        // Reserved for deliveries
+        0x53..0x5F => true,
+        // Reserved for authenticated cross overs
+        0x63..0x6F => true,
+        // Reserved for arrivals
+        0x72..0x7F => true,
+        _ => false,
+    }
+}
+
+/// Outcome of a node processing an extension command.
+pub enum ExtensionAction {
+    /// Discard the packet silently
+    Discard,
+
+    /// Deliver the packet to a local mailbox
+    Deliver {
+        /// Mailbox name
+        mailbox: MailboxName,
+    },
+
+    /// Discard the packet and send a new packet in its place
+    Reply {
+        packet: OutgoingPacket,
+    },
+}
+
+/// Node side of an extension command.
+pub trait Extension : Send+Sync {
+    /// Length of our command's data, which follows our opcode,
+    /// given the remainder of beta.
+    ///
+    /// We see only the decrypted `Params::MAX_BETA_TAIL_LENGTH`
+    /// bytes of beta, so longer commands get rejected.
+    fn length(&self, beta: &[u8]) -> SphinxResult<usize>;
+
+    /// Act upon our command's `data` after the node decrypted
+    /// `body`, but before removing the body's framing.
+    fn handle(&self, packet_name: &PacketName, data: &[u8], body: &[u8])
+      -> SphinxResult<ExtensionAction>;
+}
+
+/// Client side of an extension command.
+pub trait ExtensionInstruction {
+    /// Our opcode, which must satisfy `is_extension_opcode`.
+    fn opcode(&self) -> u8;
+
+    /// Length of our command's data, excluding our opcode.
+    fn length(&self) -> usize;
+
+    /// Write our command's data into `data`, which has length
+    /// `self.length()`.
+    fn encode(&self, data: &mut [u8]);
+}
+
+impl Instruction {
+    /// Create an `Instruction` from an extension command.
+    pub fn extension<E>(e: &E) -> SphinxResult<Instruction>
+      where E: ExtensionInstruction+?Sized {
+        let opcode = e.opcode();
+        if ! is_extension_opcode(opcode) {
+            return Err( SphinxError::InternalError("Extension opcode not reserved for extensions") );
+        }
+        let mut data = vec![0u8; e.length()].into_boxed_slice();
+        e.encode(&mut data);
+        Ok( Instruction::Extension { opcode, data } )
+    }
+}
+
+/// Registry of extension commands by opcode.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    extensions: HashMap<u8,Arc<Extension>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions { Extensions::default() }
+
+    /// Register `extension` to handle `opcode`.
+    ///
+    /// Returns an `InternalError` if `opcode` is not reserved for
+    /// extensions or some extension already claimed it.
+    pub fn register(&mut self, opcode: u8, extension: Arc<Extension>) -> SphinxResult<()> {
+        if ! is_extension_opcode(opcode) {
+            return Err( SphinxError::InternalError("Extension opcode not reserved for extensions") );
+        }
+        if self.extensions.contains_key(&opcode) {
+            return Err( SphinxError::InternalError("Extension opcode already registered") );
+        }
+        self.extensions.insert(opcode, extension);
+        Ok(())
+    }
+
+    /// Find the extension registered for `opcode`, if any.
+    pub fn get(&self, opcode: u8) -> Option<&Extension> {
+        self.extensions.get(&opcode).map(|e| &**e)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::super::commands::{Command,PreCommand};
+    use super::*;
+
+    struct Echo;
+
+    impl Extension for Echo {
+        fn length(&self, beta: &[u8]) -> SphinxResult<usize> {
+            beta.first().map(|l| 1 + *l as usize)
+              .ok_or( SphinxError::BadLength("Echo lacks length",0) )
+        }
+        fn handle(&self, _: &PacketName, _: &[u8], _: &[u8]) -> SphinxResult<ExtensionAction> {
+            Ok( ExtensionAction::Discard )
+        }
+    }
+
+    impl ExtensionInstruction for Echo {
+        fn opcode(&self) -> u8 { 0x72 }
+        fn length(&self) -> usize { 4 }
+        fn encode(&self, data: &mut [u8]) { data.copy_from_slice(&[3,7,8,9]); }
+    }
+
+    #[test]
+    fn extension_commands_round_trip() {
+        let mut extensions = Extensions::new();
+        assert!( extensions.register(0x50, Arc::new(Echo)).is_err() );
+        extensions.register(0x72, Arc::new(Echo)).unwrap();
+        assert!( extensions.register(0x72, Arc::new(Echo)).is_err() );
+
+        let data = match Instruction::extension(&Echo).unwrap() {
+            Instruction::Extension { opcode: 0x72, data } => data,
+            _ => panic!("Wrong instruction"),
+        };
+        let c: PreCommand<usize> = Command::Extension { opcode: 0x72, data };
+        let mut beta = [0u8; 8];
+        c.write_command(&mut beta);
+        assert!( Command::parse(&beta).is_err() );
+        match Command::parse_extended(&beta, Some(&extensions)).unwrap() {
+            (Command::Extension { opcode: 0x72, data }, 5) => assert_eq!(&*data, &[3,7,8,9]),
+            _ => panic!("Extension command failed to parse"),
+        }
+        assert!( Command::parse_extended(&beta[..3], Some(&extensions)).is_err() );
+    }
+}
@@ -0,0 +1,524 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Message fragmentation and reassembly across multiple Sphinx bodies
+//!
+//! We split any message too large for one body into numbered fragments
+//! tagged with a random `MessageId`, send each fragment in its own
+//! packet with an independently built header, and reassemble arriving
+//! fragments, dropping duplicates and abandoning incomplete messages
+//! after a timeout.
+//!
+//! Senders may optionally add parity fragments from `erasure`, so that
+//! any `threshold` of the `count` fragments reconstruct the message.
+
+use std::collections::HashMap;
+use std::time::{Duration,SystemTime};
+
+use rand::Rng;
+
+use keys::time::Clock;
+use ::state::HasherState;
+use super::body::payload_capacity;
+use super::mailbox::ArrivingStore;
+use super::erasure;
+use super::error::*;
+
+
+pub const MESSAGE_ID_LENGTH : usize = 16;
+
+/// Random identifier shared by all fragments of one message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MessageId(pub [u8; MESSAGE_ID_LENGTH]);
+
+/// Length of our fragment header, consisting of the `MessageId`,
+/// the fragment's index, the number of fragments required to
+/// reconstruct the message, and the total number of fragments,
+/// with all numbers encoded as little endian `u16`s.
+pub const FRAGMENT_HEADER_LENGTH : usize = MESSAGE_ID_LENGTH + 2 + 2 + 2;
+
+/// Maximum number of fragments into which we split one message.
+pub const MAX_FRAGMENTS : usize = 0xFFFF;
+
+/// Length of the message length prefix in the first fragment.
+const MESSAGE_LENGTH_LENGTH : usize = 4;
+
+/// Amount of message carried by each fragment in a body of length
+/// `body_length`.
+pub fn fragment_capacity(body_length: usize) -> usize {
+    let c = payload_capacity(body_length);
+    if c <= FRAGMENT_HEADER_LENGTH { 0 } else { c - FRAGMENT_HEADER_LENGTH }
+}
+
+/// Header of a single fragment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub id: MessageId,
+    pub index: u16,
+    pub threshold: u16,
+    pub count: u16,
+}
+
+impl FragmentHeader {
+    pub fn to_bytes(&self) -> [u8; FRAGMENT_HEADER_LENGTH] {
+        let mut r = [0u8; FRAGMENT_HEADER_LENGTH];
+        {
+        let (id,index,threshold,count) = mut_array_refs![&mut r,MESSAGE_ID_LENGTH,2,2,2];
+        *id = self.id.0;
+        *index = [ (self.index & 0xFF) as u8, (self.index >> 8) as u8 ];
+        *threshold = [ (self.threshold & 0xFF) as u8, (self.threshold >> 8) as u8 ];
+        *count = [ (self.count & 0xFF) as u8, (self.count >> 8) as u8 ];
+        }
+        r
+    }
+
+    pub fn from_bytes(b: &[u8; FRAGMENT_HEADER_LENGTH]) -> FragmentHeader {
+        let (id,index,threshold,count) = array_refs![b,MESSAGE_ID_LENGTH,2,2,2];
+        FragmentHeader {
+            id: MessageId(*id),
+            index: ((index[1] as u16) << 8) | (index[0] as u16),
+            threshold: ((threshold[1] as u16) << 8) | (threshold[0] as u16),
+            count: ((count[1] as u16) << 8) | (count[0] as u16),
+        }
+    }
+
+    /// Split a payload into its fragment header and fragment data.
+    pub fn parse(payload: &[u8]) -> SphinxResult<(FragmentHeader,&[u8])> {
+        if payload.len() < FRAGMENT_HEADER_LENGTH {
+            return Err( SphinxError::BadLength("Fragment shorter than its header", payload.len()) );
+        }
+        let h = FragmentHeader::from_bytes(array_ref![payload,0,FRAGMENT_HEADER_LENGTH]);
+        if h.count == 0 || h.index >= h.count {
+            return Err( SphinxError::BadPacket("Fragment index out of range", h.index as u64) );
+        }
+        if h.threshold == 0 || h.threshold > h.count
+           || (h.threshold < h.count && h.count as usize > erasure::MAX_SHARDS) {
+            return Err( SphinxError::BadPacket("Fragment threshold out of range", h.threshold as u64) );
+        }
+        Ok(( h, &payload[FRAGMENT_HEADER_LENGTH..] ))
+    }
+}
+
+/// Split `message` into fragment payloads, each of which fits into
+/// a body of length `body_length` via `body::frame_body`.
+///
+/// We add `ceil(redundancy * k)` parity fragments to the `k` fragments
+/// holding the message, so that any `k` fragments reconstruct it.
+/// A `redundancy` of zero yields plain fragmentation.
+pub fn fragment_message<R: Rng>(rng: &mut R, message: &[u8], body_length: usize, redundancy: f64)
+  -> SphinxResult<(MessageId,Vec<Box<[u8]>>)> {
+    let capacity = fragment_capacity(body_length);
+    if capacity == 0 {
+        return Err( SphinxError::BadLength("Body too short for fragments", body_length) );
+    }
+    if ! (redundancy >= 0.0) || ! redundancy.is_finite() {
+        return Err( SphinxError::InternalError("Negative or non-finite fragment redundancy") );
+    }
+    let length = MESSAGE_LENGTH_LENGTH + message.len();
+    let threshold = (length + capacity - 1) / capacity;
+    // We bound the parity count as a float before casting, so that
+    // huge redundancies cannot overflow.
+    let extra = (redundancy * threshold as f64).ceil();
+    if extra > 0.0 && threshold as f64 + extra > erasure::MAX_SHARDS as f64 {
+        return Err( SphinxError::BadLength("Message requires too many erasure coded fragments", message.len()) );
+    }
+    let count = threshold + extra as usize;
+    if count > MAX_FRAGMENTS {
+        return Err( SphinxError::BadLength("Message requires too many fragments", message.len()) );
+    }
+    let mut id = MessageId::default();
+    rng.fill_bytes(&mut id.0);
+
+    // We prefix the message length and pad with zeros, so that all
+    // fragments carry equal length shards for the erasure code.
+    let mut padded = vec![0u8; threshold * capacity];
+    {
+        let l = message.len() as u32;
+        let (len,msg) = padded.split_at_mut(MESSAGE_LENGTH_LENGTH);
+        len.copy_from_slice(&[ l as u8, (l >> 8) as u8, (l >> 16) as u8, (l >> 24) as u8 ]);
+        msg[..message.len()].copy_from_slice(message);
+    }
+    let data: Vec<&[u8]> = padded.chunks(capacity).collect();
+    let parity = if count > threshold {
+        erasure::encode_parity(&data, count) ?  // BadLength
+    } else { Vec::new() };
+
+    let mut fragments = Vec::with_capacity(count);
+    let shards = data.iter().map(|d| *d).chain(parity.iter().map(|p| &p[..]));
+    for (index,shard) in shards.enumerate() {
+        let h = FragmentHeader {
+            id, index: index as u16, threshold: threshold as u16, count: count as u16
+        };
+        let mut f = Vec::with_capacity(FRAGMENT_HEADER_LENGTH + capacity);
+        f.extend_from_slice(& h.to_bytes());
+        f.extend_from_slice(shard);
+        fragments.push(f.into_boxed_slice());
+    }
+    Ok((id, fragments))
+}
+
+
+/// Fragments received so far for one message.
+struct Partial {
+    /// Time at which we received the first fragment
+    started: SystemTime,
+
+    /// Fragment data indexed by fragment number
+    fragments: Vec<Option<Box<[u8]>>>,
+
+    /// Number of `Some` entries in `fragments`
+    received: usize,
+
+    /// Number of fragments required to reconstruct the message
+    threshold: usize,
+}
+
+/// Default bound on the length of messages we reassemble.
+pub const DEFAULT_MAX_MESSAGE_LENGTH : usize = 1 << 20;
+
+/// Default bound on the number of incomplete messages we hold.
+pub const DEFAULT_MAX_PENDING : usize = 64;
+
+/// Default bound on the total fragment count of all incomplete
+/// messages we hold.
+pub const DEFAULT_MAX_FRAGMENTS : usize = 8192;
+
+/// Default bound on the number of completed messages we remember
+/// for duplicate suppression.
+pub const DEFAULT_MAX_COMPLETED : usize = 4096;
+
+/// Reassembles fragmented messages from arriving payloads.
+///
+/// We suppress duplicate fragments, including fragments of messages
+/// we already completed, and abandon incomplete messages after
+/// `timeout`.  As fragments may arrive from anyone, we bound the
+/// memory held by incomplete messages and by completed messages,
+/// evicting the oldest ones first.
+pub struct Reassembler {
+    /// Time after receiving the first fragment after which we
+    /// abandon an incomplete message.  We also remember completed
+    /// messages for this long for duplicate suppression.
+    pub timeout: Duration,
+
+    /// Longest message we reassemble.  We reject fragments claiming
+    /// more fragments than `fragment_message` produces for this length.
+    pub max_message_length: usize,
+
+    /// Maximum number of incomplete messages held.
+    pub max_pending: usize,
+
+    /// Maximum sum of the fragment counts of incomplete messages held.
+    pub max_fragments: usize,
+
+    /// Maximum number of completed messages remembered.
+    pub max_completed: usize,
+
+    partials: HashMap<MessageId,Partial,HasherState>,
+
+    /// Sum of the fragment counts of all `partials`.
+    allocated: usize,
+
+    /// Completed messages along with their completion time.
+    completed: HashMap<MessageId,SystemTime,HasherState>,
+}
+
+impl Reassembler {
+    pub fn new(hs: HasherState, timeout: Duration) -> Reassembler {
+        Reassembler {
+            timeout,
+            max_message_length: DEFAULT_MAX_MESSAGE_LENGTH,
+            max_pending: DEFAULT_MAX_PENDING,
+            max_fragments: DEFAULT_MAX_FRAGMENTS,
+            max_completed: DEFAULT_MAX_COMPLETED,
+            partials: HashMap::with_hasher(hs),
+            allocated: 0,
+            completed: HashMap::with_hasher(hs),
+        }
+    }
+
+    /// Number of incomplete messages held.
+    pub fn pending(&self) -> usize { self.partials.len() }
+
+    /// Process one arriving fragment payload, returning the whole
+    /// message if this fragment completes it.
+    pub fn insert<K: Clock+?Sized>(&mut self, clock: &K, payload: &[u8])
+      -> SphinxResult<Option<Vec<u8>>> {
+        let now = clock.now();
+        let (h, data) = FragmentHeader::parse(payload) ?;  // BadLength, BadPacket
+        if self.completed.contains_key(&h.id) { return Ok(None); }
+        if ! self.partials.contains_key(&h.id) {
+            self.admit(&h, data.len()) ?;  // BadLength, BadPacket
+        }
+
+        let complete = {
+            let p = self.partials.entry(h.id).or_insert_with( || Partial {
+                started: now,
+                fragments: vec![None; h.count as usize],
+                received: 0,
+                threshold: h.threshold as usize,
+            } );
+            if p.fragments.len() != h.count as usize || p.threshold != h.threshold as usize {
+                return Err( SphinxError::BadPacket("Fragment count disagrees with earlier fragments", h.count as u64) );
+            }
+            let slot = &mut p.fragments[h.index as usize];
+            if slot.is_some() { return Ok(None); }  // Duplicate
+            *slot = Some(data.to_vec().into_boxed_slice());
+            p.received += 1;
+            p.received == p.threshold
+        };
+        if ! complete { return Ok(None); }
+
+        let p = self.partials.remove(&h.id).expect("Partial message vanished!");
+        self.allocated -= p.fragments.len();
+        let data = erasure::reconstruct(p.threshold, &p.fragments) ?;  // BadLength, BadPacket
+        let mut message = Vec::with_capacity(data.iter().map(|d| d.len()).sum());
+        for d in data.iter() { message.extend_from_slice(d); }
+        if message.len() < MESSAGE_LENGTH_LENGTH {
+            return Err( SphinxError::BadLength("Reassembled message lacks its length", message.len()) );
+        }
+        let l = {
+            let l = array_ref![message,0,MESSAGE_LENGTH_LENGTH];
+            (l[0] as usize) | (l[1] as usize) << 8 | (l[2] as usize) << 16 | (l[3] as usize) << 24
+        };
+        if l > message.len() - MESSAGE_LENGTH_LENGTH {
+            return Err( SphinxError::BadLength("Reassembled message length exceeds fragments", l) );
+        }
+        message.drain(..MESSAGE_LENGTH_LENGTH);
+        message.truncate(l);
+        // We remember messages only once they reconstruct, so that
+        // retransmissions may repair a failed reconstruction.
+        self.complete(h.id, now);
+        Ok(Some(message))
+    }
+
+    /// Remember a completed message, forgetting the oldest completed
+    /// messages to make room.
+    fn complete(&mut self, id: MessageId, now: SystemTime) {
+        if self.max_completed == 0 { return; }
+        while self.completed.len() >= self.max_completed {
+            let oldest = *self.completed.iter()
+                .min_by_key( |&(_,t)| *t )
+                .expect("Completed bound exceeded without completed messages!").0;
+            self.completed.remove(&oldest);
+        }
+        self.completed.insert(id, now);
+    }
+
+    /// Check a fragment of a message we have not seen before against
+    /// our bounds, evicting the oldest incomplete messages to make room.
+    fn admit(&mut self, h: &FragmentHeader, capacity: usize) -> SphinxResult<()> {
+        if capacity == 0 {
+            return Err( SphinxError::BadLength("Fragment carries no data", capacity) );
+        }
+        let max_threshold = (MESSAGE_LENGTH_LENGTH + self.max_message_length + capacity - 1) / capacity;
+        if h.threshold as usize > max_threshold {
+            return Err( SphinxError::BadPacket("Fragment threshold exceeds maximum message length", h.threshold as u64) );
+        }
+        let count = h.count as usize;
+        if count > self.max_fragments || self.max_pending == 0 {
+            return Err( SphinxError::BadPacket("Fragment count exceeds reassembly bounds", h.count as u64) );
+        }
+        while self.partials.len() >= self.max_pending || self.allocated + count > self.max_fragments {
+            let oldest = *self.partials.iter()
+                .min_by_key( |&(_,p)| p.started )
+                .expect("Reassembly bounds exceeded without partial messages!").0;
+            let p = self.partials.remove(&oldest).unwrap();
+            self.allocated -= p.fragments.len();
+        }
+        self.allocated += count;
+        Ok(())
+    }
+
+    /// Abandon incomplete messages and forget completed messages
+    /// older than `timeout`.  Returns the number of incomplete
+    /// messages abandoned.
+    pub fn expire<K: Clock+?Sized>(&mut self, clock: &K) -> usize {
+        let now = clock.now();
+        let timeout = self.timeout;
+        let old = |t: &SystemTime| now.duration_since(*t).map(|d| d > timeout).unwrap_or(false);
+        let before = self.partials.len();
+        let mut freed = 0;
+        self.partials.retain( |_,p| {
+            if ! old(&p.started) { return true; }
+            freed += p.fragments.len();
+            false
+        } );
+        self.allocated -= freed;
+        self.completed.retain( |_,t| ! old(t) );
+        before - self.partials.len()
+    }
+
+    /// Feed all packets waiting in an `ArrivingStore` through
+    /// reassembly, returning any completed messages.  We drop
+    /// malformed fragments because they may arrive from anyone.
+    pub fn drain_arrivals<K: Clock+?Sized>(&mut self, clock: &K, arrivals: &ArrivingStore)
+      -> Vec<Vec<u8>> {
+        let packets = {
+            let mut arrivals = arrivals.write().unwrap();  // PoisonError ???
+            ::std::mem::replace(&mut *arrivals, Vec::new())
+        };
+        packets.iter().filter_map( |a| self.insert(clock, &a.body).unwrap_or(None) ).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{OsRng, Rng};
+    use std::time::UNIX_EPOCH;
+    use keys::time::MockClock;
+    use super::*;
+
+    #[test]
+    fn fragments_reassemble() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let clock = MockClock::new(UNIX_EPOCH);
+        let mut message = vec![0u8; 5000];
+        r.fill_bytes(&mut message);
+
+        let (_,mut fragments) = fragment_message(&mut r, &message, 1024, 0.0).unwrap();
+        assert_eq!(fragments.len(), (5004 + fragment_capacity(1024) - 1) / fragment_capacity(1024));
+        fragments.reverse();
+
+        let mut ra = Reassembler::new(HasherState::new(), Duration::from_secs(600));
+        let last = fragments.pop().unwrap();
+        for f in fragments.iter() {
+            assert_eq!(ra.insert(&clock, f).unwrap(), None);
+            assert_eq!(ra.insert(&clock, f).unwrap(), None);  // Duplicate
+        }
+        assert_eq!(ra.insert(&clock, &last).unwrap(), Some(message));
+        assert_eq!(ra.insert(&clock, &last).unwrap(), None);  // Duplicate after completion
+        assert_eq!(ra.pending(), 0);
+    }
+
+    #[test]
+    fn fragments_expire() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let clock = MockClock::new(UNIX_EPOCH);
+        let (_,fragments) = fragment_message(&mut r, &[7u8; 3000], 1024, 0.0).unwrap();
+
+        let mut ra = Reassembler::new(HasherState::new(), Duration::from_secs(600));
+        assert_eq!(ra.insert(&clock, &fragments[0]).unwrap(), None);
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(ra.expire(&clock), 0);
+        clock.advance(Duration::from_secs(301));
+        assert_eq!(ra.expire(&clock), 1);
+        for f in fragments[1..].iter() {
+            assert_eq!(ra.insert(&clock, f).unwrap(), None);
+        }
+        assert_eq!(ra.pending(), 1);
+    }
+
+    #[test]
+    fn fragments_survive_drops() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let clock = MockClock::new(UNIX_EPOCH);
+        let mut message = vec![0u8; 20000];
+        r.fill_bytes(&mut message);
+
+        for &redundancy in [0.25, 0.5, 1.0].iter() {
+            let (_,mut fragments) = fragment_message(&mut r, &message, 2048, redundancy).unwrap();
+            let h = FragmentHeader::parse(&fragments[0]).unwrap().0;
+            let (k,n) = (h.threshold as usize, h.count as usize);
+            assert_eq!(n, k + (redundancy * k as f64).ceil() as usize);
+
+            // Simulate a lossy path dropping n-k random packets.
+            for _ in 0..(n-k) {
+                let i = r.gen_range(0,fragments.len());
+                fragments.remove(i);
+            }
+            let mut ra = Reassembler::new(HasherState::new(), Duration::from_secs(600));
+            let last = fragments.pop().unwrap();
+            for f in fragments.iter() {
+                assert_eq!(ra.insert(&clock, f).unwrap(), None);
+            }
+            assert_eq!(ra.insert(&clock, &last).unwrap(), Some(message.clone()));
+
+            // One more drop leaves the message incomplete.
+            let mut ra = Reassembler::new(HasherState::new(), Duration::from_secs(600));
+            for f in fragments.iter() {
+                assert_eq!(ra.insert(&clock, f).unwrap(), None);
+            }
+            assert_eq!(ra.pending(), 1);
+        }
+    }
+
+    #[test]
+    fn redundancy_bounded() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        for &redundancy in [-1.0, ::std::f64::INFINITY, ::std::f64::NAN, 1e300].iter() {
+            assert!( fragment_message(&mut r, &[0u8; 3000], 1024, redundancy).is_err() );
+        }
+        let (_,f) = fragment_message(&mut r, &[0u8; 10], 1024, 255.0).unwrap();
+        assert_eq!(f.len(), erasure::MAX_SHARDS);
+        assert!( fragment_message(&mut r, &[0u8; 3000], 1024, 255.0).is_err() );
+    }
+
+    #[test]
+    fn partials_bounded() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let clock = MockClock::new(UNIX_EPOCH);
+        let mut ra = Reassembler::new(HasherState::new(), Duration::from_secs(600));
+        ra.max_pending = 3;
+        ra.max_fragments = 8;
+
+        // A forged count of 0xFFFF never allocates.
+        let (_,fragments) = fragment_message(&mut r, &[1u8; 3000], 1024, 0.0).unwrap();
+        let mut forged = fragments[0].to_vec();
+        let mut h = FragmentHeader::parse(&forged).unwrap().0;
+        h.threshold = 0xFFFF;  h.count = 0xFFFF;
+        forged[..FRAGMENT_HEADER_LENGTH].copy_from_slice(&h.to_bytes());
+        assert!( ra.insert(&clock, &forged).is_err() );
+        ra.max_message_length = 1 << 30;
+        assert!( ra.insert(&clock, &forged).is_err() );
+        assert_eq!(ra.pending(), 0);
+
+        // Messages beyond max_pending evict the oldest.
+        let messages: Vec<_> = (0..4u8).map( |i| {
+            clock.advance(Duration::from_secs(1));
+            let (_,f) = fragment_message(&mut r, &[i; 1500], 1024, 0.0).unwrap();
+            assert_eq!(f.len(), 2);
+            assert_eq!(ra.insert(&clock, &f[0]).unwrap(), None);
+            f
+        } ).collect();
+        assert_eq!(ra.pending(), 3);
+        assert_eq!(ra.insert(&clock, &messages[0][1]).unwrap(), None);
+        assert_eq!(ra.insert(&clock, &messages[3][1]).unwrap(), Some(vec![3u8; 1500]));
+
+        // Large messages evict to stay within max_fragments.
+        let (_,f) = fragment_message(&mut r, &[9u8; 5000], 1024, 0.0).unwrap();
+        assert!(f.len() > 4 && f.len() <= 8);
+        let (last,f) = f.split_last().unwrap();
+        for g in f.iter() { assert_eq!(ra.insert(&clock, g).unwrap(), None); }
+        assert_eq!(ra.pending(), 2);
+        assert_eq!(ra.insert(&clock, last).unwrap(), Some(vec![9u8; 5000]));
+        assert_eq!(ra.insert(&clock, &messages[2][1]).unwrap(), None);  // Evicted
+    }
+
+    #[test]
+    fn completed_bounded() {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let clock = MockClock::new(UNIX_EPOCH);
+        let mut ra = Reassembler::new(HasherState::new(), Duration::from_secs(600));
+        ra.max_completed = 2;
+
+        // Completed messages beyond max_completed forget the oldest.
+        let messages: Vec<_> = (0..3u8).map( |i| {
+            clock.advance(Duration::from_secs(1));
+            let (_,f) = fragment_message(&mut r, &[i; 100], 1024, 0.0).unwrap();
+            assert_eq!(f.len(), 1);
+            assert_eq!(ra.insert(&clock, &f[0]).unwrap(), Some(vec![i; 100]));
+            f
+        } ).collect();
+        assert_eq!(ra.completed.len(), 2);
+        assert_eq!(ra.insert(&clock, &messages[2][0]).unwrap(), None);  // Duplicate
+        assert_eq!(ra.insert(&clock, &messages[0][0]).unwrap(), Some(vec![0u8; 100]));
+
+        // A message that fails to reconstruct is not marked completed,
+        // so a retransmission still reassembles.
+        let (_,f) = fragment_message(&mut r, &[5u8; 100], 1024, 0.0).unwrap();
+        let mut bad = f[0].to_vec();
+        for b in bad[FRAGMENT_HEADER_LENGTH..FRAGMENT_HEADER_LENGTH+MESSAGE_LENGTH_LENGTH].iter_mut() { *b = 0xFF; }
+        assert!( ra.insert(&clock, &bad).is_err() );
+        assert_eq!(ra.insert(&clock, &f[0]).unwrap(), Some(vec![5u8; 100]));
+    }
+}
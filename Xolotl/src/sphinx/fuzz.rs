@@ -0,0 +1,131 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Entry points for the fuzz targets in `fuzz/fuzz_targets`
+//!
+//! We build these only with the `fuzzing` feature.  Each accepts
+//! arbitrary bytes and must never panic, except that we panic upon
+//! any `InternalError` because untrusted input should only ever be
+//! rejected with some other `SphinxError`.
+
+use std::cmp::min;
+use std::sync::Arc;
+use std::time::{Duration,UNIX_EPOCH};
+
+use ::state::HasherState;
+use keys::{RoutingName,RoutingSecret};
+use keys::time::{MockClock,ValidityPeriod};
+use ratchet::{BranchId,TwigId,BRANCH_ID_LENGTH,TWIG_ID_LENGTH};
+use curve::{Point,Scalar};
+use super::commands::Command;
+use super::layout::{Params,ImplParams,HeaderMuts,PreHeader,SURB_PREFIX_LENGTH,read_n_trim_surb_log};
+use super::node::{Router,RatchetState};
+use super::params::ChatParams;
+use super::stream::SphinxKey;
+use super::error::*;
+use super::*;
+
+
+/// Fail if untrusted input caused an `InternalError`.
+fn check<T>(r: &SphinxResult<T>) {
+    if let Err(SphinxError::InternalError(s)) = *r {
+        panic!("Untrusted input caused an internal error: {}", s);
+    }
+}
+
+pub fn command_parse(data: &[u8]) {
+    let r = Command::parse(data);
+    check(&r);
+    if let Ok((_,eaten)) = r { assert!(eaten <= data.len()); }
+}
+
+pub fn header_sliced(data: &[u8]) {
+    let mut h = data.to_vec();
+    let r = HeaderMuts::<ChatParams>::new_sliced(&mut h);
+    check(&r);
+    assert_eq!(r.is_ok(), data.len() == ChatParams::header_length());
+}
+
+pub fn decode_surb(data: &[u8]) {
+    let r = PreHeader::decode_surb(data);
+    check(&r);
+    match r {
+        Ok(surb) => assert_eq!(&surb.encode_surb()[..], data),
+        Err(_) => assert!(data.len() < SURB_PREFIX_LENGTH),
+    }
+}
+
+pub fn surb_log(data: &[u8]) {
+    let mut surb_log = data;
+    let mut n = 0;
+    while let Ok(_) = read_n_trim_surb_log(&mut surb_log) { n += 1; }
+    assert_eq!(n, data.len() / PACKET_NAME_LENGTH);
+}
+
+pub fn ratchet_ids(data: &[u8]) {
+    if data.len() < TWIG_ID_LENGTH { return; }
+    let b = array_ref![data,0,TWIG_ID_LENGTH];
+    assert_eq!(&TwigId::from_bytes(b).to_bytes(), b);
+    let b = array_ref![data,0,BRANCH_ID_LENGTH];
+    assert_eq!(&BranchId::from_bytes(b).to_bytes(), b);
+}
+
+
+fn routing_secret() -> RoutingSecret {
+    RoutingSecret {
+        name: RoutingName([7u8; 16]),
+        secret: Scalar::make(&[9u8; 64]),
+        validity: ValidityPeriod(0..2000),
+    }
+}
+
+/// Router holding only `routing_secret()` with an empty ratchet.
+fn router() -> Router<ChatParams> {
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(1000)));
+    let ratchet = Arc::new(RatchetState::new(HasherState::new()));
+    let mut router = Router::new(clock, ratchet, true);
+    router.add_routing_secret(routing_secret());
+    router
+}
+
+/// Feed arbitrary bytes through `Router::process` as a header
+/// followed by a body.
+pub fn router_process(data: &[u8]) {
+    let (header,body) = data.split_at(min(data.len(), ChatParams::header_length()));
+    check(& router().process(header.into(), body.into()));
+}
+
+/// Feed a header built from arbitrary bytes through `Router::process`,
+/// but with a correct route, alpha, and gamma, so that fuzzing reaches
+/// command processing.
+///
+/// We take the alpha's secret scalar from the first 64 bytes, the
+/// body length from the next byte, and fill beta, the SURB log, and
+/// the body from the remaining bytes, leaving them zero if exhausted.
+pub fn router_process_structured(data: &[u8]) {
+    if data.len() < 65 { return; }
+    let (seed,data) = data.split_at(64);
+    let (b,mut data) = data.split_at(1);
+    let i = b[0] as usize % ChatParams::BODY_LENGTHS.len();
+    let mut fill = |x: &mut [u8]| {
+        let l = min(x.len(), data.len());
+        x[..l].copy_from_slice(&data[..l]);
+        data = &data[l..];
+    };
+
+    let secret = routing_secret();
+    let mut header = ChatParams::boxed_zeroed_header();
+    {
+        let mut refs = HeaderMuts::<ChatParams>::new_sliced(&mut header).unwrap();
+        fill(&mut *refs.beta);
+        fill(&mut *refs.surb_log);
+        *refs.route = secret.name.0;
+        let alpha = Point::from_secret(&Scalar::make(array_ref![seed,0,64]));
+        *refs.alpha = alpha.compress();
+        let ss = alpha.key_exchange(&secret.secret);
+        let hop = SphinxKey::<ChatParams>::new_kdf(&ss, &secret.name).header_cipher().unwrap();
+        *refs.gamma = refs.create_gamma(&hop).unwrap().0;
+    }
+    let mut body = ChatParams::boxed_zeroed_body(i);
+    fill(&mut body);
+    check(& router().process(header, body));
+}
@@ -8,15 +8,40 @@ use std::borrow::{Borrow,BorrowMut};
 use std::marker::PhantomData;
 
 use keys::{RoutingName,RoutingNameBytes,ROUTING_NAME_LENGTH};
-use keys::time::{ValidityPeriod};
+use keys::time::{ValidityPeriod,ValidityPolicy};
 use curve::{AlphaBytes,ALPHA_LENGTH};
 use super::stream::{Gamma,GammaBytes,GAMMA_LENGTH,HeaderCipher};
 use super::commands::{Command,CommandGamma,CommandData,CommandNode,MAX_SURB_BETA_LENGTH};
+use super::mailbox::SlotName;
+use super::extension::Extensions;
 use super::error::*;
 use super::slice::*;
 use super::*; // {PacketName,PACKET_NAME_LENGTH};
 
 
+/// Commands preceding the main command of a sub-hop, which modify
+/// how the node handles the main command.
+#[derive(Debug, Default, Clone)]
+pub struct Modifiers {
+    /// SURB for any error report, from `Command::Report`.
+    pub report: Option<PreHeader>,
+
+    /// Slot for parking or releasing packets, from `Command::DropOff`.
+    pub drop_off: Option<SlotName>,
+}
+
+impl Modifiers {
+    /// Combine modifiers from two sub-hops on the same node,
+    /// preferring our own.
+    pub fn or(self, other: Modifiers) -> Modifiers {
+        Modifiers {
+            report: self.report.or(other.report),
+            drop_off: self.drop_off.or(other.drop_off),
+        }
+    }
+}
+
+
 /// We use `usize` for indexing, like all Rust programs, but we may
 /// specify a smaller type for user specified indexes.
 pub type Length = usize;
@@ -50,6 +75,7 @@ pub trait Params: Sized+Clone+Copy {
     const MAX_BETA_TAIL_LENGTH: Length;
 
     /// Maximum length of the SURB.  At most half of `BETA_LENGTH - 48`.
+    /// See `params_lengths_valid` for all constraints.
     ///
     /// Alpha and Gamma are encoded into the "bottom" of beta, and
     /// hence do not contribute here.  This is unlikely to change.
@@ -65,6 +91,10 @@ pub trait Params: Sized+Clone+Copy {
     /// Approved message body lengths
     const BODY_LENGTHS: &'static [Length];
 
+    /// Policy for coarsening the validity periods of headers and
+    /// SURBs we build, so that they do not reveal their routing keys.
+    const VALIDITY_POLICY: ValidityPolicy;
+
     /// Rate paramater lambda for the exponential distribution of
     /// from which we sample the senders' sugested delays in 
     /// `Stream::delay`.
@@ -73,7 +103,7 @@ pub trait Params: Sized+Clone+Copy {
     /// Sphinx header length
     #[inline(always)]
     fn header_length() -> usize {
-        ALPHA_LENGTH + GAMMA_LENGTH
+        ROUTING_NAME_LENGTH + ALPHA_LENGTH + GAMMA_LENGTH
         + Self::BETA_LENGTH as usize
         + Self::SURB_LOG_LENGTH as usize
     }
@@ -109,11 +139,48 @@ impl<P> Params for ParamsEtc<P> where P: Params, PhantomData<P>: 'static  {
 }
 */
 
+/// Check that a combination of `Params` lengths is sound, meaning
+/// - beta's tail for a single sub-hop is shorter than beta,
+/// - SURBs are not so long that they degrade sender security,
+/// - SURBs fit into the encoding of `Command::CrossOver`, and
+/// - the SURB log holds a whole number of `PacketName`s.
+///
+/// We make this a `const fn` so that `params_assert_valid!` can reject
+/// invalid `Params` at compile time, but `ImplParams::check_lengths`
+/// repeats these tests at runtime with more informative errors.
+pub const fn params_lengths_valid(
+    beta_length: Length,
+    max_beta_tail_length: Length,
+    max_surb_beta_length: Length,
+    surb_log_length: Length
+  ) -> bool {
+    (max_beta_tail_length < beta_length)
+    & (2*max_surb_beta_length + ALPHA_LENGTH + GAMMA_LENGTH <= beta_length)
+    & (max_surb_beta_length <= MAX_SURB_BETA_LENGTH)
+    & (surb_log_length % PACKET_NAME_LENGTH == 0)
+}
+
+/// Reject a concrete `Params` type at compile time if its lengths
+/// violate `params_lengths_valid`, by producing an array length
+/// mismatch.
+macro_rules! params_assert_valid { ($p:ty) => {
+    impl $p {
+        #[allow(dead_code)]
+        const LENGTHS_VALID: [(); 1] = [(); ::sphinx::layout::params_lengths_valid(
+            <$p as Params>::BETA_LENGTH,
+            <$p as Params>::MAX_BETA_TAIL_LENGTH,
+            <$p as Params>::MAX_SURB_BETA_LENGTH,
+            <$p as Params>::SURB_LOG_LENGTH
+        ) as usize];
+    }
+} }
+
 /// Just a helper trait to provide inherent methods on types
 /// satisfying `Params`.
 pub trait ImplParams: Params {
     fn boxed_zeroed_header() -> Box<[u8]>;
     fn boxed_zeroed_body(i: usize) -> Box<[u8]>;
+    fn check_lengths() -> SphinxResult<()>;
     fn check_body_length(body_length: usize) -> SphinxResult<()>;
 }
 
@@ -130,6 +197,31 @@ impl<P: Params> ImplParams for P {
         vec![0u8; P::BODY_LENGTHS[i]].into_boxed_slice()
     }
 
+    /// Returns an error describing the first violation of
+    /// `params_lengths_valid` by our paramaters.
+    fn check_lengths() -> SphinxResult<()> {
+        if P::MAX_BETA_TAIL_LENGTH >= P::BETA_LENGTH {
+            return Err( SphinxError::BadLength("Maximum beta tail exceeds beta",
+                P::MAX_BETA_TAIL_LENGTH) );
+        }
+        // Prevent configurations that support long SURB attacks.
+        if 2*P::MAX_SURB_BETA_LENGTH + ALPHA_LENGTH + GAMMA_LENGTH > P::BETA_LENGTH {
+            return Err( SphinxError::BadLength("Maximum SURB is so long that it degrades sender security",
+                P::MAX_SURB_BETA_LENGTH) );
+        }
+        if P::MAX_SURB_BETA_LENGTH > MAX_SURB_BETA_LENGTH as Length {
+            return Err( SphinxError::BadLength("Maximum SURB length exceeds encoding",
+                P::MAX_SURB_BETA_LENGTH) );
+        }
+        if P::SURB_LOG_LENGTH % PACKET_NAME_LENGTH != 0 {
+            return Err( SphinxError::BadLength("SURB log does not hold whole packet names",
+                P::SURB_LOG_LENGTH) );
+        }
+        debug_assert!( params_lengths_valid(P::BETA_LENGTH, P::MAX_BETA_TAIL_LENGTH,
+            P::MAX_SURB_BETA_LENGTH, P::SURB_LOG_LENGTH) );
+        Ok(())
+    }
+
     /// Returns an error if the body length is not approved by the paramaters.
     fn check_body_length(body_length: usize) -> SphinxResult<()> {
         // Just for debugging convenience we check all lengths
@@ -215,15 +307,7 @@ impl<'a,P> HeaderMuts<'a,P> where P: Params {
     ///
     pub fn new_sliced<'s>(mut header: &'s mut [u8]) -> SphinxResult<HeaderMuts<'s,P>>
     {
-        // Prevent configurations that support long SURB attacks.
-        if 2*P::MAX_SURB_BETA_LENGTH > P::BETA_LENGTH - ALPHA_LENGTH + GAMMA_LENGTH {
-            return Err( SphinxError::BadLength("Maximum SURB is so long that it degrades sender security",
-                P::MAX_SURB_BETA_LENGTH) );
-        }
-        if P::MAX_SURB_BETA_LENGTH > MAX_SURB_BETA_LENGTH as Length {
-            return Err( SphinxError::BadLength("Maximum SURB length exceeds encoding",
-                P::MAX_SURB_BETA_LENGTH) );
-        }
+        P::check_lengths() ?;  // BadLength
 
         let orig_len = header.len();
         if orig_len < P::header_length() {
@@ -273,23 +357,89 @@ impl<'a,P> HeaderMuts<'a,P> where P: Params {
     /// Decrypt beta, read a command from an initial segment of beta,
     /// shift beta forward by the command's length, and pad the tail
     /// of beta.
-    pub fn peal_beta(&mut self, hop: &mut HeaderCipher<P>) -> SphinxResult<CommandNode> {
-        hop.xor_beta(self.beta,0,0) ?;  // InternalError
-
-        let (command, eaten) = Command::parse(self.beta) ?;  // BadPacket: Unknown Command
-        if eaten > P::MAX_BETA_TAIL_LENGTH as usize {
-            return Err( SphinxError::InternalError("Ate too much Beta!") );
-        }
+    ///
+    /// If beta begins with `Command::Report` or `Command::DropOff`
+    /// then we also read the following command and return them as
+    /// `Modifiers`, so that all these commands share this sub-hop's
+    /// tail.
+    ///
+    /// We decrypt only as much of beta as the command requires.
+    /// Deliveries and arrivals never forward beta, so they decrypt
+    /// only the commands themselves, leaving the remainder of beta
+    /// garbage.  Cross overs decrypt only their SURB's beta, leaving
+    /// the remainder of beta zeroed.  Only commands that forward the
+    /// packet pay for decrypting all of beta and padding its tail.
+    pub fn peal_beta(&mut self, hop: &mut HeaderCipher<P>)
+      -> SphinxResult<(CommandNode,Modifiers)> {
+        self.peal_beta_extended(hop,None)
+    }
 
-        // We could reduce our calls to ChaCha by partially processing
-        // commands here, like zeroing beta's during cross over, or 
-        // ignoring beta entirely for delivery commands. 
+    /// Decrypt beta and read a command, like `peal_beta`, but also
+    /// recognize commands registered in `extensions`.  Extension
+    /// commands never forward beta, so we treat them like deliveries.
+    pub fn peal_beta_extended(&mut self, hop: &mut HeaderCipher<P>, extensions: Option<&Extensions>)
+      -> SphinxResult<(CommandNode,Modifiers)> {
         let length = self.beta.len();
         debug_assert_eq!(length, P::BETA_LENGTH as usize);
+        let head = P::MAX_BETA_TAIL_LENGTH as usize;
+        hop.xor_beta_part(&mut self.beta[..head],0) ?;  // InternalError
+
+        // Any command extending beyond `head` gets rejected below,
+        // so we never act upon bytes we have not decrypted.
+        let (mut command, mut eaten) = Command::parse_extended(self.beta,extensions) ?;  // BadPacket: Unknown Command
+        let mut modifiers = Modifiers::default();
+        loop {
+            match command {
+                Command::Report { route, alpha, gamma, surb_beta } => {
+                    if modifiers.report.is_some() {
+                        return Err( SphinxError::BadPacket("Tried two report commands.",0) );
+                    }
+                    // Embedded SURBs carry no validity period, so we
+                    // use an empty one, which firing the SURB ignores.
+                    modifiers.report = Some( PreHeader {
+                        validity: ValidityPeriod(0..0),
+                        route, alpha, gamma, beta: surb_beta,
+                    } );
+                },
+                Command::DropOff { slot } => {
+                    if modifiers.drop_off.is_some() {
+                        return Err( SphinxError::BadPacket("Tried two drop off commands.",0) );
+                    }
+                    modifiers.drop_off = Some(slot);
+                },
+                _ => break,
+            }
+            let (c,e) = Command::parse_extended(&self.beta[eaten..],extensions) ?;  // BadPacket: Unknown Command
+            command = c;
+            eaten += e;
+        }
+        if eaten > head {
+            return Err( SphinxError::BadPacket("Ate too much Beta!",eaten as u64) );
+        }
+
+        match command {
+            Command::Deliver { .. } | Command::ArrivalDirect { } | Command::ArrivalSURB { }
+              | Command::Delete { .. } | Command::Extension { .. } => return Ok((command,modifiers)),
+            Command::CrossOver { surb_beta: surb_beta_length, .. } => {
+                if surb_beta_length > P::MAX_SURB_BETA_LENGTH {
+                    return Err( SphinxError::BadPacket("Long SURB attack dropped.",surb_beta_length as u64) );
+                }
+                let end = eaten + surb_beta_length;
+                if end > head {
+                    hop.xor_beta_part(&mut self.beta[head..end],head) ?;  // InternalError
+                }
+                for i in eaten..end { self.beta[i-eaten] = self.beta[i];  }
+                for i in self.beta[surb_beta_length..].iter_mut() { *i = 0; }
+                return Ok((command,modifiers));
+            },
+            _ => { },
+        }
+
+        hop.xor_beta_part(&mut self.beta[head..],head) ?;  // InternalError
         // let beta = &mut refs.beta[..length];
         for i in eaten..length { self.beta[i-eaten] = self.beta[i];  }
         hop.set_beta_tail(&mut self.beta[length-eaten..length]) ?;  // InternalError
-        Ok(command)
+        Ok((command,modifiers))
     }
 }
 
@@ -352,6 +502,7 @@ impl<'a, P: Params> IntoIterator for HeaderMuts<'a,P> {
 /// endianness conversion, except that our `T` and `U` are `[u8]`
 /// and `PreHeader` with `Box<[u8]>` replaced by `[u8]`, which are
 /// unsized, so this being careful.
+#[derive(Clone, Debug)]
 pub struct PreHeader {
     pub validity: ValidityPeriod,
     pub route: RoutingName,
@@ -361,12 +512,13 @@ pub struct PreHeader {
     // pub keys: ...,
 }
 
+/// Length of an encoded SURB excluding its `beta`.
+pub const SURB_PREFIX_LENGTH: usize = 16 + ROUTING_NAME_LENGTH + ALPHA_LENGTH + GAMMA_LENGTH;
+
 impl PreHeader {
     /// Encode a SURB for storage or transmission
-    pub fn encode_surb(self) -> Box<[u8]> {
-        let mut v = Vec::with_capacity(
-            16 + ROUTING_NAME_LENGTH + ALPHA_LENGTH + GAMMA_LENGTH + self.beta.len()
-        );
+    pub fn encode_surb(&self) -> Box<[u8]> {
+        let mut v = Vec::with_capacity(SURB_PREFIX_LENGTH + self.beta.len());
         v.extend_from_slice( & self.validity.to_bytes() );
         v.extend_from_slice( &self.route.0 );
         v.extend_from_slice( &self.alpha );
@@ -376,14 +528,36 @@ impl PreHeader {
     }
 
     /// Encode a SURB from storage or transmission.
-    pub fn decode_surb(mut surb: &[u8]) -> PreHeader {
-        PreHeader {
-            route: RoutingName(*reserve_fixed!(&mut surb, ROUTING_NAME_LENGTH)),
-            validity: ValidityPeriod::from_bytes(reserve_fixed!(&mut surb, 16)),
-            alpha: *reserve_fixed!(&mut surb, ALPHA_LENGTH),
-            gamma: Gamma(*reserve_fixed!(&mut surb, GAMMA_LENGTH)),
+    ///
+    /// Returns a `BadLength` error if `surb` is shorter than `SURB_PREFIX_LENGTH`.
+    pub fn decode_surb(mut surb: &[u8]) -> SphinxResult<PreHeader> {
+        Ok( PreHeader {
+            validity: ValidityPeriod::from_bytes(try_reserve_fixed!(&mut surb, 16)),
+            route: RoutingName(*try_reserve_fixed!(&mut surb, ROUTING_NAME_LENGTH)),
+            alpha: *try_reserve_fixed!(&mut surb, ALPHA_LENGTH),
+            gamma: Gamma(*try_reserve_fixed!(&mut surb, GAMMA_LENGTH)),
             beta: surb.to_owned().into_boxed_slice(),
-        }
+        } )
+    }
+}
+
+/// We serialize `PreHeader`s using their SURB encoding.
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for PreHeader {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok,S::Error>
+      where S: ::serde::Serializer {
+        serializer.serialize_bytes(& self.encode_surb())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for PreHeader {
+    fn deserialize<D>(deserializer: D) -> Result<PreHeader,D::Error>
+      where D: ::serde::Deserializer<'de> {
+        let v = deserializer.deserialize_bytes(::macros::BytesVisitor("PreHeader")) ?;
+        PreHeader::decode_surb(&v).map_err( |_|
+            ::serde::de::Error::invalid_length(v.len(), &"at least SURB_PREFIX_LENGTH")
+        )
     }
 }
 
@@ -420,8 +594,207 @@ pub fn encode_header<P: Params,R: Rng>(rng: &mut Rng, preheader: PreHeader)
 /// assumed by `HeaderRef`.
 ///
 /// TODO: Is it used??
-pub fn read_n_trim_surb_log(surb_log: &mut &[u8]) -> PacketName {
-    PacketName(*reserve_fixed!(surb_log,PACKET_NAME_LENGTH))
+pub fn read_n_trim_surb_log(surb_log: &mut &[u8]) -> SphinxResult<PacketName> {
+    Ok( PacketName(*try_reserve_fixed!(surb_log,PACKET_NAME_LENGTH)) )
 }
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{OsRng, Rng};
+    use test::Bencher;
+    use super::super::commands::PreCommand;
+    use super::super::stream::ChaChaKnN;
+    use super::super::params::ChatParams;
+    use super::super::mailbox::MailboxName;
+    use super::*;
+
+    /// Encrypted beta beginning with `command` followed by random bytes.
+    fn encrypted_beta(hop: &mut HeaderCipher<ChatParams>, command: PreCommand<Gamma>) -> Vec<u8> {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        let mut beta = vec![0u8; ChatParams::BETA_LENGTH];
+        r.fill_bytes(&mut beta);
+        let l = command.command_length();
+        command.write_command(&mut beta[..l]);
+        hop.xor_beta(&mut beta,0,0).unwrap();
+        beta
+    }
+
+    fn hop() -> HeaderCipher<ChatParams> {
+        let mut r = OsRng::new().expect("failed to create an OS RNG");
+        ChaChaKnN { key: r.gen(), nonce: r.gen() }.header_cipher::<ChatParams>().unwrap()
+    }
+
+    fn transmit() -> PreCommand<Gamma> {
+        Command::Transmit { route: RoutingName([1u8; ROUTING_NAME_LENGTH]), gamma: Gamma([2u8; GAMMA_LENGTH]) }
+    }
+
+    fn deliver() -> PreCommand<Gamma> {
+        Command::Deliver { mailbox: MailboxName([3u8; 16]) }
+    }
+
+    #[test]
+    fn lazy_peal_beta_matches_full() {
+        let mut hop = hop();
+        let beta = encrypted_beta(&mut hop, transmit());
+        let eaten = transmit().command_length();
+
+        // Decrypt all of beta, shift, and pad the tail by hand.
+        let mut expected = beta.clone();
+        hop.xor_beta(&mut expected,0,0).unwrap();
+        let length = expected.len();
+        for i in eaten..length { expected[i-eaten] = expected[i]; }
+        hop.set_beta_tail(&mut expected[length-eaten..]).unwrap();
+
+        let mut h = ChatParams::boxed_zeroed_header();
+        let mut refs = HeaderMuts::<ChatParams>::new_sliced(&mut h).unwrap();
+        refs.beta.copy_from_slice(&beta);
+        match refs.peal_beta(&mut hop).unwrap() {
+            (Command::Transmit { route, gamma }, m) => {
+                assert!(m.report.is_none() && m.drop_off.is_none());
+                assert_eq!(route.0, [1u8; ROUTING_NAME_LENGTH]);
+                assert_eq!(gamma.0, [2u8; GAMMA_LENGTH]);
+            },
+            c => panic!("Wrong command {:?}", c),
+        }
+        assert_eq!(&refs.beta[..], &expected[..]);
+
+        let beta = encrypted_beta(&mut hop, deliver());
+        refs.beta.copy_from_slice(&beta);
+        match refs.peal_beta(&mut hop).unwrap() {
+            (Command::Deliver { mailbox }, _) => assert_eq!(mailbox.0, [3u8; 16]),
+            c => panic!("Wrong command {:?}", c),
+        }
+    }
+
+    /// Encrypted beta beginning with `commands`.
+    fn encrypted_commands(hop: &mut HeaderCipher<ChatParams>, commands: &[PreCommand<Gamma>]) -> Vec<u8> {
+        let mut beta = vec![0u8; ChatParams::BETA_LENGTH];
+        let mut o = 0;
+        for c in commands.iter() {
+            let l = c.command_length();
+            c.write_command(&mut beta[o..o+l]);
+            o += l;
+        }
+        hop.xor_beta(&mut beta,0,0).unwrap();
+        beta
+    }
+
+    #[test]
+    fn drop_off_modifies_transmit() {
+        let mut hop = hop();
+        let slot = SlotName([4u8; 16]);
+        let mut h = ChatParams::boxed_zeroed_header();
+        let mut refs = HeaderMuts::<ChatParams>::new_sliced(&mut h).unwrap();
+
+        let beta = encrypted_commands(&mut hop, &[Command::DropOff { slot }, transmit()]);
+        refs.beta.copy_from_slice(&beta);
+        match refs.peal_beta(&mut hop).unwrap() {
+            (Command::Transmit { .. }, m) => assert_eq!(m.drop_off, Some(slot)),
+            c => panic!("Wrong command {:?}", c),
+        }
+
+        let beta = encrypted_commands(&mut hop, &[Command::DropOff { slot }, Command::DropOff { slot }, transmit()]);
+        refs.beta.copy_from_slice(&beta);
+        assert!( refs.peal_beta(&mut hop).is_err() );
+    }
+
+    #[test]
+    fn short_input_rejected() {
+        let mut h = ChatParams::boxed_zeroed_header();
+        assert!( HeaderMuts::<ChatParams>::new_sliced(&mut h).is_ok() );
+        assert!( HeaderMuts::<ChatParams>::new_sliced(&mut h[1..]).is_err() );
+
+        let mut beta = vec![0u8; 64];
+        let l = transmit().write_command(&mut beta);
+        assert!( Command::parse(&beta[..l]).is_ok() );
+        assert!( Command::parse(&beta[..l-1]).is_err() );
+        assert!( Command::parse(&[]).is_err() );
+
+        assert!( PreHeader::decode_surb(&[0u8; SURB_PREFIX_LENGTH]).is_ok() );
+        assert!( PreHeader::decode_surb(&[0u8; SURB_PREFIX_LENGTH-1]).is_err() );
+        let mut surb_log = &[0u8; PACKET_NAME_LENGTH+1][..];
+        assert!( read_n_trim_surb_log(&mut surb_log).is_ok() );
+        assert!( read_n_trim_surb_log(&mut surb_log).is_err() );
+    }
+
+    fn bench_peal_beta(b: &mut Bencher, command: PreCommand<Gamma>) {
+        let mut hop = hop();
+        let beta = encrypted_beta(&mut hop, command);
+        let mut h = ChatParams::boxed_zeroed_header();
+        let mut refs = HeaderMuts::<ChatParams>::new_sliced(&mut h).unwrap();
+        b.iter( || {
+            refs.beta.copy_from_slice(&beta);
+            refs.peal_beta(&mut hop).unwrap().1
+        } );
+    }
+
+    /// Baseline for `bench_peal_beta` that decrypts all of beta,
+    /// shifts it, and pads its tail regardless of the command, like
+    /// `lazy_peal_beta_matches_full` does by hand.
+    fn bench_full_beta(b: &mut Bencher, command: PreCommand<Gamma>) {
+        let mut hop = hop();
+        let beta = encrypted_beta(&mut hop, command);
+        let mut h = ChatParams::boxed_zeroed_header();
+        let mut refs = HeaderMuts::<ChatParams>::new_sliced(&mut h).unwrap();
+        b.iter( || {
+            refs.beta.copy_from_slice(&beta);
+            hop.xor_beta(&mut refs.beta[..],0,0).unwrap();
+            let (command,eaten) = Command::parse(&refs.beta[..]).unwrap();
+            let length = refs.beta.len();
+            for i in eaten..length { refs.beta[i-eaten] = refs.beta[i]; }
+            hop.set_beta_tail(&mut refs.beta[length-eaten..]).unwrap();
+            command
+        } );
+    }
+
+    #[bench]
+    fn bench_peal_beta_transmit(b: &mut Bencher) {
+        bench_peal_beta(b, transmit());
+    }
+
+    #[bench]
+    fn bench_full_beta_transmit(b: &mut Bencher) {
+        bench_full_beta(b, transmit());
+    }
+
+    #[bench]
+    fn bench_peal_beta_deliver(b: &mut Bencher) {
+        bench_peal_beta(b, deliver());
+    }
+
+    #[bench]
+    fn bench_full_beta_deliver(b: &mut Bencher) {
+        bench_full_beta(b, deliver());
+    }
+
+    #[test]
+    fn header_length_includes_route() {
+        let l = ROUTING_NAME_LENGTH + ALPHA_LENGTH + GAMMA_LENGTH
+          + ChatParams::BETA_LENGTH as usize + ChatParams::SURB_LOG_LENGTH as usize;
+        assert_eq!( ChatParams::header_length(), l );
+        let mut h = ChatParams::boxed_zeroed_header();
+        assert!( HeaderMuts::<ChatParams>::new_sliced(&mut h).is_ok() );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn preheader_serde_round_trip() {
+        use serde_json::{to_string,from_str};
+        let mut rng = OsRng::new().unwrap();
+        let mut surb = vec![0u8; SURB_PREFIX_LENGTH + 64];
+        rng.fill_bytes(&mut surb);
+        let p = PreHeader::decode_surb(&surb).unwrap();
+
+        let q: PreHeader = from_str(&to_string(&p).unwrap()).unwrap();
+        assert_eq!(&q.encode_surb()[..], &surb[..]);
+        assert_eq!(q.validity.0, p.validity.0);
+        assert_eq!(q.route, p.route);
+        assert_eq!(&q.beta[..], &p.beta[..]);
+
+        let short = to_string(&surb[..SURB_PREFIX_LENGTH-1].to_vec()).unwrap();
+        assert!( from_str::<PreHeader>(&short).is_err() );
+    }
+}
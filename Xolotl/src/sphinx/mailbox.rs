@@ -6,6 +6,7 @@
 use std::collections::HashMap;
 use std::hash::Hash; // Hasher
 use std::sync::{RwLock}; // RwLockReadGuard, RwLockWriteGuard
+use std::sync::atomic::{AtomicUsize,Ordering};
 
 use super::error::*;
 use super::*;
@@ -14,6 +15,9 @@ use ::state::HasherState;
 
 
 pub struct ArivingPacket {
+    /// Packet name of our final hop, which identifies the SURB used
+    /// if this packet arrived via one of our SURBs.
+    pub packet_name: PacketName,
     pub metadata: Vec<surbs::Metadata>,
     pub body: Box<[u8]>
 }
@@ -76,12 +80,59 @@ impl<K,PM> PacketMapMap<K,PM>
       -> SphinxResult<()> 
     {
         if let Some(pm) = self.enqueue_familiar(&k,packet_name,packet) ? {
+            use std::collections::hash_map::Entry;
             let mut queues = self.1.write().unwrap(); // PoisonError ???
-            queues.insert(k, pm );  // Ignore return because get(k) just failed
+            // Another thread may have created this queue since `get(k)` failed.
+            match queues.entry(k) {
+                Entry::Vacant(e) => { e.insert(pm); },
+                Entry::Occupied(e) => {
+                    let mut packets = e.get().packets().write().unwrap();  // PoisonError ???
+                    for (n,p) in pm.packets().write().unwrap().drain() {
+                        if packets.insert(n,p).is_some() {
+                            return Err( SphinxError::InternalError("Packet name collision detected!") );
+                        }
+                    }
+                },
+            }
         }
         Ok(())
     }
 
+    /// Remove and return all packets satisfying `f` from every queue.
+    pub fn dequeue_matching<F>(&self, mut f: F) -> Vec<(PacketName,PM::Packet)>
+      where F: FnMut(&PM::Packet) -> bool {
+        let queues = self.1.read().unwrap(); // PoisonError ???
+        let mut r = Vec::new();
+        for pm in queues.values() {
+            let mut packets = pm.packets().write().unwrap();  // PoisonError ???
+            let names: Vec<PacketName> = packets.iter()
+              .filter(|&(_,p)| f(p)).map(|(n,_)| *n).collect();
+            for n in names {
+                let p = packets.remove(&n).unwrap();
+                r.push((n,p));
+            }
+        }
+        r
+    }
+
+    /// Remove and return the packet named `packet_name` queued under `k`.
+    pub fn remove(&self, k: &K, packet_name: &PacketName) -> Option<PM::Packet> {
+        let queues = self.1.read().unwrap(); // PoisonError ???
+        let queue = if let Some(q) = queues.get(k) { q } else { return None; };
+        let mut packets = queue.packets().write().unwrap();  // PoisonError ???
+        packets.remove(packet_name)
+    }
+
+    /// Remove and return all packets queued under `k`.
+    pub fn dequeue(&self, k: &K) -> Vec<(PacketName,PM::Packet)> {
+        let pm = {
+            let mut queues = self.1.write().unwrap(); // PoisonError ???
+            if let Some(pm) = queues.remove(k) { pm } else { return Vec::new(); }
+        };
+        let mut packets = pm.packets().write().unwrap(); // Owned here
+        packets.drain().collect()
+    }
+
 }
 
 
@@ -124,6 +175,63 @@ impl PacketMapy for Mailbox {
 pub type MailboxStore = PacketMapMap<MailboxName,Mailbox>;
 
 
+pub const MAILBOX_KEY_LENGTH : usize = 32;
+pub const DELETE_TAG_LENGTH : usize = 16;
+
+/// Secret key with which a mailbox's owner authenticates requests
+/// like `Command::Delete`, which the owner registers with the node
+/// hosting the mailbox.
+#[derive(Clone)]
+pub struct MailboxKey(pub [u8; MAILBOX_KEY_LENGTH]);
+
+/// Tag authenticating the deletion of one packet from a mailbox.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeleteTag(pub [u8; DELETE_TAG_LENGTH]);
+
+impl MailboxKey {
+    /// Authenticate deleting `packet_name` from `mailbox`.
+    pub fn delete_tag(&self, mailbox: &MailboxName, packet_name: &PacketName) -> DeleteTag {
+        use crypto::digest::Digest;
+        use crypto::sha3::Sha3;
+
+        let r = &mut [0u8; 32];
+        let mut sha = Sha3::sha3_256();
+        sha.input(&self.0);
+        sha.input_str( "Delete" );
+        sha.input(&mailbox.0);
+        sha.input(&packet_name.0);
+        sha.result(r);
+        sha.reset();
+        DeleteTag(*array_ref![r,0,DELETE_TAG_LENGTH])
+    }
+}
+
+/// Mailbox keys registered by mailbox owners.
+pub type MailboxKeyStore = RwMap<MailboxName,MailboxKey>;
+
+impl MailboxStore {
+    /// Remove `packet_name` from `mailbox` if `tag` authenticates
+    /// the deletion using the key registered in `keys`, returning
+    /// whether the packet existed.
+    ///
+    /// Returns a `BadPacket` error if nobody registered a key for
+    /// `mailbox` or `tag` fails to authenticate.
+    pub fn delete(&self, keys: &MailboxKeyStore, mailbox: &MailboxName,
+                  packet_name: &PacketName, tag: &DeleteTag) -> SphinxResult<bool> {
+        let expected = {
+            let keys = keys.read().unwrap();  // PoisonError ???
+            let key = keys.get(mailbox)
+              .ok_or( SphinxError::BadPacket("Deletion from mailbox without a key.",0) ) ?;
+            key.delete_tag(mailbox, packet_name)
+        };
+        if ! ::consistenttime::ct_u8_slice_eq(&expected.0, &tag.0) {
+            return Err( SphinxError::BadPacket("Unauthenticated mailbox deletion.",0) );
+        }
+        Ok( self.remove(mailbox, packet_name).is_some() )
+    }
+}
+
+
 pub struct OutgoingPacket {
     pub route: ::keys::RoutingName,
     pub time: ::std::time::SystemTime,
@@ -148,5 +256,159 @@ impl PacketMapy for Outgoing {
 // TODO Replace RoutingName with longer term key's name here.
 pub type OutgoingStore = PacketMapMap<::keys::RoutingName,Outgoing>;
 
+pub const SLOT_NAME_LENGTH : usize = 16;
+pub type SlotNameBytes = [u8; SLOT_NAME_LENGTH];
+
+/// Opaque identifier for a slot in which a node parks packets
+/// for later forwarding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SlotName(pub SlotNameBytes);
+
+/// Packets parked by `Command::DropOff`, whose `time` gives when
+/// the node's holding time expires.
+///
+/// We limit how many packets we park in any one slot and in total,
+/// so that senders cannot exhaust our memory by parking packets
+/// nobody releases.  We check these limits before parking, so
+/// concurrent callers may exceed them slightly.
+pub struct DropOffStore {
+    slots: PacketMapMap<SlotName,Outgoing>,
+    depth: AtomicUsize,
+    max_per_slot: usize,
+    max_total: usize,
+}
+
+impl DropOffStore {
+    /// Create a store parking at most `max_per_slot` packets in
+    /// any one slot and `max_total` packets overall.
+    pub fn new(hs: HasherState, max_per_slot: usize, max_total: usize) -> DropOffStore {
+        DropOffStore {
+            slots: PacketMapMap::new(hs),
+            depth: AtomicUsize::new(0),
+            max_per_slot, max_total,
+        }
+    }
+
+    /// Change our limits, without releasing any packets already parked.
+    pub fn set_limits(&mut self, max_per_slot: usize, max_total: usize) {
+        self.max_per_slot = max_per_slot;
+        self.max_total = max_total;
+    }
+
+    /// Number of packets currently parked in all slots.
+    pub fn depth(&self) -> usize { self.depth.load(Ordering::Relaxed) }
 
+    /// Number of packets currently parked in `slot`.
+    fn slot_depth(&self, slot: &SlotName) -> usize {
+        let queues = self.slots.1.read().unwrap(); // PoisonError ???
+        queues.get(slot).map_or(0, |q| q.packets().read().unwrap().len())  // PoisonError ???
+    }
 
+    /// Park `packet` in `slot`, or return `Backpressure` if either
+    /// `slot` or the whole store is full.
+    pub fn enqueue(&self, slot: SlotName, packet_name: PacketName, packet: OutgoingPacket)
+      -> SphinxResult<()> {
+        if self.depth() >= self.max_total {
+            return Err( SphinxError::Backpressure("Drop off store full") );
+        }
+        if self.slot_depth(&slot) >= self.max_per_slot {
+            return Err( SphinxError::Backpressure("Drop off slot full") );
+        }
+        self.slots.enqueue(slot, packet_name, packet) ?;  // InternalError
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Remove and return all packets parked in `slot`.
+    pub fn dequeue(&self, slot: &SlotName) -> Vec<(PacketName,OutgoingPacket)> {
+        let packets = self.slots.dequeue(slot);
+        self.depth.fetch_sub(packets.len(), Ordering::Relaxed);
+        packets
+    }
+
+    /// Remove and return all packets satisfying `f` from every slot.
+    pub fn dequeue_matching<F>(&self, f: F) -> Vec<(PacketName,OutgoingPacket)>
+      where F: FnMut(&OutgoingPacket) -> bool {
+        let packets = self.slots.dequeue_matching(f);
+        self.depth.fetch_sub(packets.len(), Ordering::Relaxed);
+        packets
+    }
+}
+
+/// `OutgoingStore` split into shards by next hop to reduce lock
+/// contention, which counts queued packets for backpressure.
+pub struct ShardedOutgoingStore {
+    shards: Vec<OutgoingStore>,
+    depth: AtomicUsize,
+}
+
+impl ShardedOutgoingStore {
+    /// Create a store with `shards` shards, with at least one shard
+    /// and at most 256.
+    pub fn new(hs: HasherState, shards: usize) -> ShardedOutgoingStore {
+        let shards = ::std::cmp::min(::std::cmp::max(shards,1), 0x100);
+        ShardedOutgoingStore {
+            shards: (0..shards).map(|_| OutgoingStore::new(hs)).collect(),
+            depth: AtomicUsize::new(0),
+        }
+    }
+
+    /// We select shards using the routing name's first byte because
+    /// routing names are hashes of routing keys.
+    fn shard(&self, route: &::keys::RoutingName) -> &OutgoingStore {
+        &self.shards[ route.0[0] as usize % self.shards.len() ]
+    }
+
+    /// Number of packets currently queued for all next hops.
+    pub fn depth(&self) -> usize { self.depth.load(Ordering::Relaxed) }
+
+    pub fn enqueue(&self, route: ::keys::RoutingName, packet_name: PacketName, packet: OutgoingPacket)
+      -> SphinxResult<()> {
+        self.shard(&route).enqueue(route, packet_name, packet) ?;
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Remove and return all packets queued for next hop `route`.
+    pub fn dequeue(&self, route: &::keys::RoutingName) -> Vec<(PacketName,OutgoingPacket)> {
+        let packets = self.shard(route).dequeue(route);
+        self.depth.fetch_sub(packets.len(), Ordering::Relaxed);
+        packets
+    }
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unauthenticated_deletes_rejected() {
+        let hs = HasherState::new();
+        let store = MailboxStore::new(hs);
+        let keys: MailboxKeyStore = RwLock::new(HashMap::with_hasher(hs));
+        let mailbox = MailboxName([1u8; MAILBOX_NAME_LENGTH]);
+        let name = PacketName([2u8; PACKET_NAME_LENGTH]);
+        let packet = || MailboxPacket { surb_log: Box::new([]), body: Box::new([3u8; 8]) };
+        store.enqueue(mailbox, name, packet()).unwrap();
+
+        let key = MailboxKey([4u8; MAILBOX_KEY_LENGTH]);
+        let tag = key.delete_tag(&mailbox, &name);
+        assert!( store.delete(&keys, &mailbox, &name, &tag).is_err() );  // No key registered
+        keys.write().unwrap().insert(mailbox, key.clone());
+
+        let forged = MailboxKey([5u8; MAILBOX_KEY_LENGTH]).delete_tag(&mailbox, &name);
+        assert!( store.delete(&keys, &mailbox, &name, &forged).is_err() );
+        let other = key.delete_tag(&mailbox, &PacketName([6u8; PACKET_NAME_LENGTH]));
+        assert!( store.delete(&keys, &mailbox, &name, &other).is_err() );
+        assert_eq!( store.dequeue(&mailbox).len(), 1 );
+
+        store.enqueue(mailbox, name, packet()).unwrap();
+        assert_eq!( store.delete(&keys, &mailbox, &name, &tag).unwrap(), true );
+        assert_eq!( store.delete(&keys, &mailbox, &name, &tag).unwrap(), false );
+        assert!( store.dequeue(&mailbox).is_empty() );
+    }
+}
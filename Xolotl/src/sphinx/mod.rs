@@ -16,8 +16,22 @@ pub mod error;
 mod slice;
 
 mod commands;
+#[macro_use]
 mod layout;
 mod surbs;
+mod ack;
+mod erasure;
+mod fragment;
+mod report;
+mod extension;
+mod pipeline;
+pub mod params;
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+
+#[cfg(test)]
+mod testing;
 
 
 pub use self::layout::Params;
@@ -6,18 +6,27 @@
 
 use std::collections::HashMap;
 use std::borrow::{BorrowMut}; // Borrow
-use std::sync::{Arc}; // RwLock
+use std::sync::{Arc,RwLock};
 use std::marker::PhantomData;
+use std::time::Duration;
 
 
 // pub ed25519_dalek::ed25519;
 
 pub use ratchet::{TwigId,TWIG_ID_LENGTH,Transaction,AdvanceNode};
 pub use ratchet::State as RatchetState;
+use ratchet::{GcPolicy,GcReport,bootstrap_branch,bootstrap_branch_id};
+use ratchet::error::RatchetError;
+
+use keys::time::{Clock,ValidityResult};
+use ::state::HasherState;
+use keys::error::KeysError;
+use keys::kem::{Kem,KemSecret};
 
 use super::commands::{Command};
 use super::layout::{Params,ImplParams,HeaderMuts};
 use super::mailbox::*;
+use super::extension::{Extension,Extensions,ExtensionAction};
 // use super::slice::*;
 use super::error::*;
 use super::*;
@@ -41,6 +50,17 @@ pub enum Action {
         time: ::std::time::SystemTime,
     },
 
+    /// Park this message for forwarding to another hop once released,
+    /// either by another packet or by our holding time expiring.
+    DropOff {
+        /// Slot in which we park the message
+        slot: SlotName,
+        /// Next hop
+        route: ::keys::RoutingName,
+        /// Time at which our holding time expires
+        time: ::std::time::SystemTime,
+    },
+
     /// Arrival of a message for some local application.
     ///
     /// There are situations where we could know the sender because
@@ -50,87 +70,345 @@ pub enum Action {
     Arrival {
         metadata: Vec<surbs::Metadata>,
     },
+
+    /// Send a new packet in reply, like a deletion confirmation,
+    /// discarding this packet.
+    Reply {
+        packet: OutgoingPacket,
+    },
+
+    /// Discard this packet without further action.
+    Discard,
 }
 
+/// Packet public key and blinding factor of a forwarded packet,
+/// whose blinded alpha we compute only after `do_crypto`, so that
+/// `process_batch` may blind many together.
+type Blinding = Option<(::curve::Point,::curve::Scalar)>;
+
 
 struct RoutingSecretData {
     // routing_public: ::keys::RoutingPublic,
     routing_secret: ::keys::RoutingSecret,
     replayer: replay::ReplayFilterStore,
+    /// KEM and secret key for hybrid sub-hops, if we advertise one.
+    kem: Option<(Arc<Kem>,KemSecret)>,
 }
 
-struct Router<P: Params> {
+pub struct Router<P: Params> {
     params: PhantomData<P>,
 
     secrets: HashMap<::keys::RoutingName,RoutingSecretData>,
 
-    outgoing: OutgoingStore,
+    outgoing: ShardedOutgoingStore,
     mailboxes: MailboxStore,
+    mailbox_keys: MailboxKeyStore,
     arrivals: ArrivingStore,
+    drop_offs: DropOffStore,
 
     surbs: Arc<surbs::SURBStore<P>>,
     ratchet: Arc<RatchetState>,
+
+    /// Source of time for routing key expiry and packet scheduling.
+    clock: Arc<Clock+Send+Sync>,
+
+    /// Honor `Command::Report` by reporting dropped packets.
+    report_errors: bool,
+
+    /// Fire acknowledgement SURBs attached to arriving payloads.
+    fire_acks: bool,
+
+    /// Maximum time we park packets for `Command::DropOff`.
+    drop_off_hold: Duration,
+
+    /// Handlers for extension commands.
+    extensions: Extensions,
+
+    /// Maximum number of consecutive ratchet sub-hops we process.
+    max_ratchet_subhops: usize,
 }
 
 
+/// Number of shards for our replay filters and outgoing queues.
+const ROUTER_SHARDS: usize = 16;
+
+/// Default maximum time in seconds we park packets for `Command::DropOff`.
+pub const DROP_OFF_HOLD_SECS: u64 = 24*60*60;
+
+/// Default maximum number of packets we park in one drop off slot.
+pub const DROP_OFF_SLOT_CAPACITY: usize = 64;
+
+/// Default maximum number of packets we park in all drop off slots.
+pub const DROP_OFF_CAPACITY: usize = 0x10000;
+
+/// Default maximum number of consecutive ratchet sub-hops.
+pub const MAX_RATCHET_SUBHOPS: usize = 1;
+
 impl<P: Params> Router<P> {
-    /// 
+    /// Create a router without any routing keys.
+    pub fn new(clock: Arc<Clock+Send+Sync>, ratchet: Arc<RatchetState>, report_errors: bool) -> Router<P> {
+        let hs = HasherState::new();
+        Router {
+            params: PhantomData,
+            secrets: HashMap::new(),
+            outgoing: ShardedOutgoingStore::new(hs, ROUTER_SHARDS),
+            mailboxes: MailboxStore::new(hs),
+            mailbox_keys: RwLock::new(HashMap::with_hasher(hs)),
+            arrivals: ArrivingStore::default(),
+            drop_offs: DropOffStore::new(hs, DROP_OFF_SLOT_CAPACITY, DROP_OFF_CAPACITY),
+            drop_off_hold: Duration::from_secs(DROP_OFF_HOLD_SECS),
+            fire_acks: false,
+            extensions: Extensions::new(),
+            max_ratchet_subhops: MAX_RATCHET_SUBHOPS,
+            surbs: Arc::new(surbs::SURBStore::new(hs)),
+            ratchet, clock, report_errors,
+        }
+    }
+
+    /// Accept packets using the routing key `routing_secret`.
+    pub fn add_routing_secret(&mut self, routing_secret: ::keys::RoutingSecret) {
+        self.secrets.insert(routing_secret.name, RoutingSecretData {
+            routing_secret,
+            replayer: replay::ReplayFilterStore::new(HasherState::new(), ROUTER_SHARDS),
+            kem: None,
+        } );
+    }
+
+    /// Accept hybrid sub-hops for the routing key named `route`,
+    /// which must advertise the public key for `secret`.
+    pub fn add_kem_secret(&mut self, route: &::keys::RoutingName, kem: Arc<Kem>, secret: KemSecret)
+      -> SphinxResult<()> {
+        let secrets = self.secrets.get_mut(route)
+          .ok_or( SphinxError::InternalError("Unknown routing key name.") ) ?;
+        secrets.kem = Some((kem,secret));
+        Ok(())
+    }
+
+    /// Authorize `Command::Delete` for `mailbox` with tags made by `key`.
+    pub fn register_mailbox(&self, mailbox: MailboxName, key: MailboxKey) {
+        let mut keys = self.mailbox_keys.write().unwrap();  // PoisonError ???
+        keys.insert(mailbox, key);
+    }
+
+    /// Handle the extension command `opcode` using `extension`.
     ///
-    /// TODO: Check validity
-    fn secrets(&self, route: &::keys::RoutingName) -> SphinxResult<&RoutingSecretData> {
-        self.secrets.get(route)
-          .ok_or( SphinxError::BadPacket("Unknown routing key name.",0) )
+    /// Returns an `InternalError` if `opcode` is not reserved for
+    /// extensions or we already handle it.
+    pub fn register_extension(&mut self, opcode: u8, extension: Arc<Extension>) -> SphinxResult<()> {
+        self.extensions.register(opcode, extension)
+    }
+
+    /// Set the maximum number of consecutive ratchet sub-hops we
+    /// process for one packet.
+    pub fn set_max_ratchet_subhops(&mut self, max: usize) {
+        self.max_ratchet_subhops = max;
+    }
+
+    /// Treat every payload arriving for us as produced by
+    /// `ack::attach_ack`, firing any attached acknowledgement SURB
+    /// and removing the prefix before storing the payload.
+    pub fn set_fire_acks(&mut self, fire_acks: bool) {
+        self.fire_acks = fire_acks;
+    }
+
+    /// Set the maximum time we park packets for `Command::DropOff`.
+    pub fn set_drop_off_hold(&mut self, hold: Duration) {
+        self.drop_off_hold = hold;
+    }
+
+    /// Set the maximum numbers of packets we park for `Command::DropOff`
+    /// in any one slot and in all slots, beyond which we drop packets
+    /// with `Backpressure`.
+    pub fn set_drop_off_limits(&mut self, max_per_slot: usize, max_total: usize) {
+        self.drop_offs.set_limits(max_per_slot, max_total);
+    }
+
+    /// Queue every packet parked in `slot` for transmission now,
+    /// returning how many we released.
+    fn release_slot(&self, slot: &SlotName) -> SphinxResult<usize> {
+        let now = self.clock.now();
+        let packets = self.drop_offs.dequeue(slot);
+        let n = packets.len();
+        for (packet_name,mut p) in packets {
+            p.time = now;
+            self.outgoing.enqueue(p.route, packet_name, p) ?;  // InternalError
+        }
+        Ok(n)
+    }
+
+    /// Queue for transmission every parked packet whose holding time
+    /// expired, returning how many we released.  Call periodically.
+    pub fn release_drop_offs(&self) -> SphinxResult<usize> {
+        let now = self.clock.now();
+        let packets = self.drop_offs.dequeue_matching(|p| p.time <= now);
+        let n = packets.len();
+        for (packet_name,p) in packets {
+            self.outgoing.enqueue(p.route, packet_name, p) ?;  // InternalError
+        }
+        Ok(n)
+    }
+
+    /// Reclaim ratchet storage according to `policy`, returning
+    /// what we reclaimed.  Call periodically.
+    pub fn collect_ratchet_garbage(&self, policy: &GcPolicy) -> SphinxResult<GcReport> {
+        Ok( self.ratchet.collect_garbage(self.clock.now(), policy) ? )  // RatchetError
+    }
+
+    /// Packets that arrived for us, as drained by `AckTracker::drain_acks`
+    /// or `Reassembler::drain_arrivals`.
+    pub fn arrivals(&self) -> &ArrivingStore { &self.arrivals }
+
+    /// SURBs we created that return to us.
+    pub fn surbs(&self) -> &surbs::SURBStore<P> { &self.surbs }
+
+    /// Number of packets awaiting transmission to other nodes.
+    pub fn outgoing_depth(&self) -> usize { self.outgoing.depth() }
+
+    /// Remove and return all packets awaiting transmission to `route`.
+    pub fn take_outgoing(&self, route: &::keys::RoutingName) -> Vec<(PacketName,OutgoingPacket)> {
+        self.outgoing.dequeue(route)
+    }
+
+    /// Find the secret data for the routing key named `route`, along
+    /// with an error if our clock says the routing key is not
+    /// currently valid.
+    ///
+    /// We return the secret data for invalid keys we still hold only
+    /// so that we may report errors.
+    fn secrets(&self, route: &::keys::RoutingName)
+      -> SphinxResult<(&RoutingSecretData,SphinxResult<()>)> {
+        let secrets = self.secrets.get(route)
+          .ok_or( SphinxError::BadPacket("Unknown routing key name.",0) ) ?;
+        let valid = match secrets.routing_secret.validity.valid(&*self.clock) {
+            ValidityResult::Valid(_) => Ok(()),
+            ValidityResult::Pending(_) => Err( SphinxError::KeysError(
+                KeysError::Routing(*route,"Routing key not yet valid.") ) ),
+            ValidityResult::Expired(_) => Err( SphinxError::KeysError(
+                KeysError::Routing(*route,"Routing key expired.") ) ),
+        };
+        Ok((secrets,valid))
+    }
+
+    /// Queue a report that we dropped the packet `packet` due to `e`
+    /// along the SURB `requested` by `Command::Report`, which we pair
+    /// with the `ErrorPacketId` of the sub-hop requesting the report,
+    /// if we report errors and `e` merits reporting.
+    ///
+    /// We must never fail here because we already have an error to
+    /// return, so we ignore any failure to send the report.  We send
+    /// nothing if our `ErrorPacketId`s are empty because nobody could
+    /// correlate our report.
+    ///
+    /// Callers must authenticate beta and pass the replay filter
+    /// before calling us, so that each packet yields at most one
+    /// report, which its sender requested.
+    fn report(&self, packet: &PacketName, requested: Option<(ErrorPacketId,layout::PreHeader)>, e: &SphinxError) {
+        if ! self.report_errors || ERROR_PACKET_ID_LENGTH == 0 { return; }
+        let (id,surb) = if let Some(r) = requested { r } else { return; };
+        let code = if let Some(c) = report::ErrorCode::from_error(e) { c } else { return; };
+        let r = report::ErrorReport { id, code };
+        let mut rng = if let Ok(rng) = ::rand::OsRng::new() { rng } else { return; };
+        if let Ok(reply) = ack::fire_surb::<P,_,_>(&mut rng, &*self.clock, surb, &r.to_bytes()) {
+            let _ = self.outgoing.enqueue(reply.route, *packet, reply);
+        }
     }
 
     /// Invokes ratchet and cross over functionality itself, but
     /// must return an `Action` for functionality that requires
-    /// ownership of the header and/or body.
-    fn do_crypto(&self, mut refs: HeaderMuts<P>, body: &mut [u8])
-      -> SphinxResult<(PacketName,Action)> {
+    /// ownership of the header and/or body, along with any blinding
+    /// of alpha, which our caller must apply using `set_alpha`.
+    fn do_crypto(&self, refs: HeaderMuts<P>, body: &mut [u8])
+      -> SphinxResult<(PacketName,Action,Blinding)> {
         // Try SURB unwinding based on alpha contents
         // .. self.surbs.try_unwind_surbs_on_arivial(hop.packet_name(), refs.surb_log, body); ..
         // But what about authentication?
 
-        let secrets = self.secrets(&::keys::RoutingName(*refs.route)) ?;
+        let (secrets,valid) = self.secrets(&::keys::RoutingName(*refs.route)) ?;  // BadPacket
 
         // Compute shared secret from the Diffie-Helman key exchange.
         let alpha = ::curve::Point::decompress(refs.alpha) ?;  // BadAlpha
         let ss = alpha.key_exchange(&secrets.routing_secret.secret);
 
+        self.do_crypto_keyed(refs, body, secrets, valid, alpha, ss)
+    }
+
+    /// Remainder of `do_crypto` after the key exchange, which
+    /// `process_batch` calls with key exchanges done in bulk.
+    fn do_crypto_keyed(&self, mut refs: HeaderMuts<P>, body: &mut [u8],
+                       secrets: &RoutingSecretData, valid: SphinxResult<()>,
+                       alpha: ::curve::Point, ss: SphinxSecret)
+      -> SphinxResult<(PacketName,Action,Blinding)> {
         // Initalize the stream cipher
-        let mut key = stream::SphinxKey::<P>::new_kdf(&ss, &secrets.routing_secret.name);
+        let key = stream::SphinxKey::<P>::new_kdf(&ss, &secrets.routing_secret.name);
         let mut hop = key.header_cipher() ?;  // InternalError: ChaCha stream exceeded
 
-        // Abort if our MAC gamma fails to verify
+        // Abort if our MAC gamma fails to verify.  We never report
+        // this because nothing authenticates beta, so anyone could
+        // corrupt gamma to make us act upon beta.
         refs.verify_gamma(&hop) ?;  // InvalidMac
 
         // Abort if the packet is a reply
         hop.replay_check(&secrets.replayer) ?; // Replay
 
         // Onion decrypt beta to extract first command.
-        let mut command = refs.peal_beta(&mut hop) ?;  // InternalError, BadPacket: Unknown Command
-
-        // Process `Command::Ratchet` before decrypting the surb log or body.
-        if let Command::Ratchet { twig, gamma } = command {
-            let TwigId(branch_id, twig_idx) = twig;
-            let mut advance = AdvanceNode::new(&self.ratchet, &branch_id) ?;  // RatchetError
-            let ss = SphinxSecret(key.chacha.key);
-            key.chacha.key = advance.clicks(&ss, twig_idx) ?;  // RatchetError
-            hop = key.header_cipher() ?;  // InternalError: ChaCha stream exceeded
-            *refs.gamma = gamma.0;
-            if let Err(e) = refs.verify_gamma(&hop) {
-                advance.abandon().unwrap();  // RatchetError ??
-                return Err(e);  // InvalidMac
+        let (command, mut modifiers) = refs.peal_beta_extended(&mut hop, Some(&self.extensions)) ?;  // InternalError, BadPacket: Unknown Command
+
+        // We report any later failure along the SURB requested by the
+        // latest sub-hop requesting a report, including our routing
+        // key not being currently valid.
+        let packet = *hop.packet_name();
+        let mut requested = modifiers.report.take()
+          .map( |s| (hop.replay_code().error_packet_id(), s) );
+        let r = match valid {
+            Ok(()) => self.do_commands(refs, body, secrets, alpha, key, hop, command, modifiers, &mut requested),
+            Err(e) => Err(e),  // KeysError
+        };
+        if let Err(ref e) = r { self.report(&packet, requested, e); }
+        r
+    }
+
+    /// Remainder of `do_crypto_keyed` after decrypting the first
+    /// command, which records in `requested` any error report
+    /// requested by later sub-hops.
+    fn do_commands(&self, mut refs: HeaderMuts<P>, body: &mut [u8],
+                   secrets: &RoutingSecretData, alpha: ::curve::Point,
+                   mut key: stream::SphinxKey<P>, mut hop: stream::HeaderCipher<P>,
+                   mut command: commands::CommandNode, mut modifiers: layout::Modifiers,
+                   requested: &mut Option<(ErrorPacketId,layout::PreHeader)>)
+      -> SphinxResult<(PacketName,Action,Blinding)> {
+        // Process `Command::Hybrid` and then `Command::Ratchet` before
+        // decrypting the surb log or body.
+        let hybrid = if let Command::Hybrid { ref ciphertext, gamma } = command {
+            Some( self.do_hybrid(&mut refs, &mut key, secrets, ciphertext, gamma) ? )
+        } else { None };
+        if let Some(h) = hybrid {
+            hop = h;
+            let (c,mut m) = refs.peal_beta_extended(&mut hop, Some(&self.extensions)) ?;  // InternalError, BadPacket: Unknown Command
+            if let Some(s) = m.report.take() { *requested = Some((hop.replay_code().error_packet_id(), s)); }
+            command = c;
+            modifiers = m.or(modifiers);
+            if let Command::Hybrid { .. } = command {
+                return Err( SphinxError::BadPacket("Tried two hybrid subhops.",0) );
             }
-            advance.confirm() ?;  // RatchetError
-            command = refs.peal_beta(&mut hop) ?;  // InternalError, BadPacket: Unknown Command
-            // We do not permit multiple ratchet sub-hops because
+        }
+        let mut ratchets = 0;
+        while let Command::Ratchet { twig, gamma } = command {
+            // We permit only one ratchet sub-hop by default because
             // spending too much of `beta` on one node might harm real
-            // world anonymity.  We bake this assumption in elsewhere
-            // too, like maybe `sphinx::client::Scaffold::add_cipher`.
-            if let Command::Ratchet { .. } = command {
-                return Err( SphinxError::BadPacket("Tried two ratchet subhops.",0) );
+            // world anonymity.
+            ratchets += 1;
+            if ratchets > self.max_ratchet_subhops {
+                return Err( SphinxError::BadPacket("Tried too many ratchet subhops.",0) );
             }
+            hop = self.do_ratchet(&mut refs, &mut key, twig, gamma) ?;  // RatchetError, InvalidMac
+            let (c,mut m) = refs.peal_beta_extended(&mut hop, Some(&self.extensions)) ?;  // InternalError, BadPacket: Unknown Command
+            if let Some(s) = m.report.take() { *requested = Some((hop.replay_code().error_packet_id(), s)); }
+            command = c;
+            modifiers = m.or(modifiers);
+        }
+        // Hybrid sub-hops must directly follow the Sphinx sub-hop.
+        if let Command::Hybrid { .. } = command {
+            return Err( SphinxError::BadPacket("Hybrid subhop after ratchet subhop.",0) );
         }
 
         // No need to constant time here.  Should just pass the bool really.
@@ -138,42 +416,57 @@ impl<P: Params> Router<P> {
             refs.surb_log.iter().fold(0u8, |x,y| { x | *y })
         );
 
-        // Short circut decrypting the body, SURB and SURB log if
-        // we're unwinding an arriving SURB anyways.
+        // A drop off preceding a delivery or arrival releases packets
+        // parked in its slot, while one preceding `Transmit` parks
+        // this packet below.
+        if let Some(slot) = modifiers.drop_off {
+            match command {
+                Command::Transmit { .. } => { },
+                Command::Deliver { .. } | Command::ArrivalDirect { } | Command::ArrivalSURB { } => {
+                    self.release_slot(&slot) ?;  // InternalError
+                },
+                _ => return Err( SphinxError::BadPacket("Drop off cannot modify this command.",0) ),
+            }
+        }
+
+        // SURB unwinding reapplies the keys of every hop of the SURB,
+        // including our own, so we must first remove our own layer
+        // from the body and SURB log, exactly like any other hop.
         // TODO: Should we better authenticate that SURB were created by us?
         if let Command::ArrivalSURB { } = command {
-            // hop.xor_surb_log(refs.surb_log) ?;
-            // hop.body_cipher().decrypt(body) ?;  // InternalError 
-            return self.surbs.unwind_surbs_on_arivial(hop.packet_name(), refs.surb_log, body);
+            hop.xor_surb_log(refs.surb_log) ?;
+            hop.body_cipher().decrypt(body) ?;  // InternalError 
+            return self.surbs.unwind_surbs_on_arivial(hop.packet_name(), refs.surb_log, body)
+              .map( |(packet,action)| (packet,action,None) );
         }
 
         // Decrypt body
         hop.body_cipher().decrypt(body) ?;  // InternalError 
 
-        Ok(( *hop.packet_name(), match command {
+        let mut blinding = None;
+        let action = match command {
             Command::ArrivalSURB { } => unreachable!(),
             Command::Ratchet {..} => unreachable!(),
+            Command::Hybrid {..} => unreachable!(),
+            // peal_beta never returns Report or DropOff
+            Command::Report {..} | Command::DropOff {..} => unreachable!(),
 
             // We cross over to running a SURB embedded in beta by
             // moving the SURB into postion, zeroing the tail, and
-            // recursing. 
-            Command::CrossOver { surb_beta: surb_beta_length, route, alpha, gamma } => {
+            // recursing.  We must zero the tail of beta beyond the
+            // SURB's beta so that our SURB's gammas cover values known
+            // by its creator, which `peal_beta` already did.
+            Command::CrossOver { surb_beta: _, route, alpha, gamma } => {
                 if already_crossed_over {
                     return Err( SphinxError::BadPacket("Tried two crossover subhops.",0) );
                 }
-                if surb_beta_length > P::MAX_SURB_BETA_LENGTH {
-                    return Err( SphinxError::BadPacket("Long SURB attack dropped.",surb_beta_length as u64) );
-                }
                 // Put SURB in control of packet.
                 *refs.route = route.0;
                 *refs.alpha = alpha;
                 *refs.gamma = gamma.0;
-                // We must zero the tail of beta beyond surb_beta_length so
-                // that our SURB's gammas cover values known by its creator.
                 // We might improve SURB unwinding by zeroing the SURB log 
-                // field too.  These two fields are both safe to zero now
-                // because they will immediately be encrypted.
-                for i in refs.beta[surb_beta_length..].iter_mut() { *i = 0; }
+                // field too.  It is safe to zero now because it will
+                // immediately be encrypted.
                 for i in refs.surb_log.iter_mut() { *i = 0; }
                 // Process the local SURB hop.
                 return self.do_crypto(refs,body);
@@ -182,12 +475,10 @@ impl<P: Params> Router<P> {
             // We cross over to running a SURB embedded in beta by
             // moving the SURB into postion, zeroing the tail, and
             // recursing. 
-            Command::Contact { } => {
-                unimplemented!()
-            },
-            Command::Greeting { } => {
-                unimplemented!()
-            },
+            Command::Contact { } =>
+                return Err( SphinxError::BadPacket("Unimplemented contact command.",0x60) ),
+            Command::Greeting { } =>
+                return Err( SphinxError::BadPacket("Unimplemented greeting command.",0x61) ),
 
             // We mutate all `refs.*` in place, along with body, so
             // `Transmit` merely drops this mutable borrow of the 
@@ -210,9 +501,12 @@ impl<P: Params> Router<P> {
                 // Prepare packet for next hop as usual in Sphinx.
                 *refs.route = route.0;
                 *refs.gamma = gamma.0;
-                *refs.alpha = alpha.blind(& hop.blinding()).compress();
-                let time = ::std::time::SystemTime::now() + hop.delay();
-                Action::Transmit { route, time }
+                blinding = Some((alpha, hop.blinding()));
+                let time = hop.time(&*self.clock);
+                if let Some(slot) = modifiers.drop_off {
+                    let time = self.clock.now() + self.drop_off_hold;
+                    Action::DropOff { slot, route, time }
+                } else { Action::Transmit { route, time } }
             },
 
             // We box the SURB log because we must store it for pickup
@@ -223,7 +517,93 @@ impl<P: Params> Router<P> {
 
             Command::ArrivalDirect { } =>
                 Action::Arrival { metadata: vec![] },
-        } ))
+
+            // We delete only after finding the confirmation SURB, so
+            // that deletions always get confirmed.  Our confirmation
+            // names the packet and says if it existed.
+            Command::Delete { mailbox, packet_name, tag } => {
+                let payload = body::unframe_body(body) ?;  // TaggingAttack
+                let surb = match ack::detach_ack(payload) ? {  // BadLength, BadPacket
+                    (Some(surb),_) => surb,
+                    (None,_) => return Err( SphinxError::BadPacket("Delete lacks confirmation SURB.",0) ),
+                };
+                let deleted = self.mailboxes.delete(&self.mailbox_keys, &mailbox, &packet_name, &tag) ?;  // BadPacket
+                let mut confirmation = [0u8; PACKET_NAME_LENGTH+1];
+                confirmation[..PACKET_NAME_LENGTH].copy_from_slice(&packet_name.0);
+                confirmation[PACKET_NAME_LENGTH] = deleted as u8;
+                let mut rng = ::rand::OsRng::new()
+                  .map_err( |_| SphinxError::InternalError("Failed to create an OS RNG") ) ?;
+                let packet = ack::fire_surb::<P,_,_>(&mut rng, &*self.clock, surb, &confirmation) ?;  // BadLength, InternalError
+                Action::Reply { packet }
+            },
+
+            // Registration ensures we know the extension.
+            Command::Extension { opcode, data } => {
+                let e = self.extensions.get(opcode)
+                  .ok_or( SphinxError::InternalError("Unregistered extension command parsed.") ) ?;
+                match e.handle(hop.packet_name(), &data, body) ? {
+                    ExtensionAction::Discard => Action::Discard,
+                    ExtensionAction::Deliver { mailbox } =>
+                        Action::Deliver { mailbox, surb_log: refs.surb_log.to_vec().into_boxed_slice() },
+                    ExtensionAction::Reply { packet } => Action::Reply { packet },
+                }
+            },
+        };
+        Ok(( *hop.packet_name(), action, blinding ))
+    }
+
+    /// Decapsulate the KEM shared secret for a `Command::Hybrid` and
+    /// return the `HeaderCipher` for the hybrid sub-hop, verifying
+    /// its gamma.
+    fn do_hybrid(&self, refs: &mut HeaderMuts<P>, key: &mut stream::SphinxKey<P>,
+                 secrets: &RoutingSecretData, ciphertext: &[u8], gamma: stream::Gamma)
+      -> SphinxResult<stream::HeaderCipher<P>> {
+        let &(ref kem, ref kem_secret) = secrets.kem.as_ref()
+          .ok_or( SphinxError::BadPacket("Hybrid subhop without KEM key.",0) ) ?;
+        let kem_ss = kem.decapsulate(kem_secret, ciphertext) ?;  // KeysError
+        let ss = SphinxSecret(key.chacha.key);
+        *key = stream::SphinxKey::<P>::new_kdf_hybrid(&ss, &kem_ss, &secrets.routing_secret.name);
+        let hop = key.header_cipher() ?;  // InternalError: ChaCha stream exceeded
+        *refs.gamma = gamma.0;
+        refs.verify_gamma(&hop) ?;  // InvalidMac
+        Ok(hop)
+    }
+
+    /// Advance our ratchet for a `Command::Ratchet` and return the
+    /// `HeaderCipher` for the ratchet sub-hop, verifying its gamma.
+    fn do_ratchet(&self, refs: &mut HeaderMuts<P>, key: &mut stream::SphinxKey<P>,
+                  twig: TwigId, gamma: stream::Gamma)
+      -> SphinxResult<stream::HeaderCipher<P>> {
+        let TwigId(branch_id, twig_idx) = twig;
+        let ss = SphinxSecret(key.chacha.key);
+        let mut advance = match AdvanceNode::new(&self.ratchet, &branch_id) {
+            Ok(a) => a,
+            // Bootstrap the branch if the client derived it from our key.
+            // We create it before checking gamma, but garbage collection
+            // reclaims any branches that nobody uses.
+            Err(RatchetError::MissingParent(_)) if branch_id == bootstrap_branch_id(&ss) => {
+                bootstrap_branch(&self.ratchet, &ss) ?;  // RatchetError
+                AdvanceNode::new(&self.ratchet, &branch_id) ?  // RatchetError
+            },
+            Err(e) => return Err( e.into() ),  // RatchetError
+        };
+        key.chacha.key = advance.clicks(&ss, twig_idx) ?;  // RatchetError
+        let hop = key.header_cipher() ?;  // InternalError: ChaCha stream exceeded
+        *refs.gamma = gamma.0;
+        if let Err(e) = refs.verify_gamma(&hop) {
+            advance.abandon().unwrap();  // RatchetError ??
+            return Err(e);  // InvalidMac
+        }
+        advance.confirm() ?;  // RatchetError
+        self.ratchet.touch(&branch_id, self.clock.now()) ?;  // RatchetError
+        Ok(hop)
+    }
+
+    /// Write the blinded alpha for the next hop into `header`.
+    fn set_alpha(header: &mut [u8], alpha: &::curve::AlphaBytes) -> SphinxResult<()> {
+        let refs = HeaderMuts::<P>::new_sliced(header) ?;  // BadLength
+        *refs.alpha = *alpha;
+        Ok(())
     }
 
     /// Process an incoming Sphinx packet.
@@ -234,23 +614,352 @@ impl<P: Params> Router<P> {
         // assert lengths ...
 
         P::check_body_length(body.len()) ?; // BadLength
-        let (packet, action) = {
+        let (packet, action, blinding) = {
             let refs = HeaderMuts::<P>::new_sliced(header.borrow_mut()) ?;  // BadLength
             self.do_crypto(refs,body.borrow_mut()) ? 
         };
+        if let Some((alpha,blinding)) = blinding {
+            Self::set_alpha(header.borrow_mut(), &alpha.blind(&blinding).compress()) ?;  // BadLength
+        }
+        self.dispatch(packet, action, header, body)
+    }
+
+    /// Process many incoming Sphinx packets, doing all their key
+    /// exchanges together using `curve::Point::key_exchange_batch`,
+    /// and blinding the alphas of all packets we transmit together
+    /// using `curve::Point::blind_batch`.
+    ///
+    /// Results and queued packets are bit-identical to calling
+    /// `process` on each packet in turn, but we queue transmitted
+    /// packets only after processing the whole batch.  We dispatch
+    /// all other packets immediately, so that later packets in the
+    /// batch may release packets parked by earlier ones.
+    pub fn process_batch(&self, packets: Vec<(Box<[u8]>,Box<[u8]>)>)
+      -> Vec<SphinxResult<()>>
+    {
+        // Look up routing keys and decompress alphas.
+        let mut staged = Vec::with_capacity(packets.len());
+        for (mut header, body) in packets.into_iter() {
+            let s = P::check_body_length(body.len()).and_then( |()| {  // BadLength
+                let refs = HeaderMuts::<P>::new_sliced(header.borrow_mut()) ?;  // BadLength
+                let (secrets,valid) = self.secrets(&::keys::RoutingName(*refs.route)) ?;  // BadPacket
+                let alpha = ::curve::Point::decompress(refs.alpha) ?;  // BadAlpha
+                Ok((secrets,valid,alpha))
+            } );
+            staged.push((header,body,s));
+        }
+
+        // Do all key exchanges with one field inversion.
+        let shared = {
+            let pairs: Vec<(&::curve::Point,&::curve::Scalar)> = staged.iter()
+                .filter_map( |&(_,_,ref s)| s.as_ref().ok() )
+                .map( |&(secrets,_,ref alpha)| (alpha, &secrets.routing_secret.secret) )
+                .collect();
+            ::curve::Point::key_exchange_batch(&pairs)
+        };
+
+        let mut shared = shared.into_iter();
+        let mut results = Vec::with_capacity(staged.len());
+        let mut deferred = Vec::new();
+        for (mut header, mut body, s) in staged.into_iter() {
+            let r = s.and_then( |(secrets,valid,alpha)| {
+                let ss = shared.next().expect("One shared secret per staged packet");
+                let refs = HeaderMuts::<P>::new_sliced(header.borrow_mut()) ?;  // BadLength
+                self.do_crypto_keyed(refs, body.borrow_mut(), secrets, valid, alpha, ss)
+            } );
+            let r = match r {
+                Err(e) => Err(e),
+                Ok((packet, action, None)) => self.dispatch(packet, action, header, body),
+                Ok((packet, action, Some(blinding))) => {
+                    if let Action::Transmit { .. } = action {
+                        deferred.push((results.len(), packet, action, blinding, header, body));
+                        Ok(())
+                    } else {
+                        let (alpha,blinding) = blinding;
+                        match Self::set_alpha(header.borrow_mut(), &alpha.blind(&blinding).compress()) {
+                            Ok(()) => self.dispatch(packet, action, header, body),
+                            Err(e) => Err(e),  // BadLength
+                        }
+                    }
+                },
+            };
+            results.push(r);
+        }
+
+        // Blind the alphas of all transmitted packets with one field
+        // inversion, and only then queue them.
+        let alphas = {
+            let pairs: Vec<(&::curve::Point,&::curve::Scalar)> = deferred.iter()
+                .map( |&(_,_,_,(ref alpha,ref blinding),_,_)| (alpha,blinding) )
+                .collect();
+            ::curve::Point::blind_batch(&pairs)
+        };
+        for ((i,packet,action,_,mut header,body),alpha) in deferred.into_iter().zip(alphas) {
+            results[i] = match Self::set_alpha(header.borrow_mut(), &alpha) {
+                Ok(()) => self.dispatch(packet, action, header, body),
+                Err(e) => Err(e),  // BadLength
+            };
+        }
+        results
+    }
+
+    /// Queue or deliver a processed packet according to its `Action`.
+    fn dispatch(&self, packet: PacketName, action: Action, header: Box<[u8]>, body: Box<[u8]>)
+      -> SphinxResult<()>
+    {
         match action {
             Action::Transmit { route, time } =>
                 self.outgoing.enqueue(route, packet, OutgoingPacket { route, time, header, body } ),
             Action::Deliver { mailbox, surb_log } =>
                 self.mailboxes.enqueue(mailbox, packet, MailboxPacket { surb_log, body } ),
+            Action::DropOff { slot, route, time } =>
+                self.drop_offs.enqueue(slot, packet, OutgoingPacket { route, time, header, body } ),
             Action::Arrival { metadata } => {
+                // We authenticate the body only after the final Lioness layer.
+                let mut payload = body::unframe_body(&body) ?;  // TaggingAttack
+                if self.fire_acks {
+                    let (surb,rest) = ack::detach_ack(payload) ?;  // BadLength, BadPacket
+                    if let Some(surb) = surb { self.fire_ack(packet, surb) ?; }
+                    payload = rest;
+                }
+                let body = payload.to_vec().into_boxed_slice();
                 let mut arrivals = self.arrivals.write().unwrap(); // PoisonError ???
-                arrivals.push( ArivingPacket { metadata, body } );
+                arrivals.push( ArivingPacket { packet_name: packet, metadata, body } );
                 Ok(())
             },
+            Action::Reply { packet: reply } =>
+                self.outgoing.enqueue(reply.route, packet, reply),
+            Action::Discard => Ok(()),
         }
     }
 
+    /// Queue the acknowledgement for the arriving packet `packet`
+    /// using its acknowledgement SURB `surb`.
+    fn fire_ack(&self, packet: PacketName, surb: layout::PreHeader) -> SphinxResult<()> {
+        let mut rng = ::rand::OsRng::new()
+          .map_err( |_| SphinxError::InternalError("Failed to create an OS RNG") ) ?;
+        let ack = ack::fire_ack::<P,_,_>(&mut rng, &*self.clock, surb) ?;  // BadLength, InternalError
+        self.outgoing.enqueue(ack.route, packet, ack)
+    }
+
 }
 
 
+#[cfg(test)]
+mod tests {
+    use rand::OsRng;
+    use test::Bencher;
+    use super::super::client::ClientRatchetState;
+    use super::super::commands::Instruction;
+    use super::super::params::ChatParams;
+    use super::super::testing::{TestNet,TestKem};
+    use keys::kem::{KemPublic,KemSharedSecret};
+    use keys::error::KeysResult;
+    use super::*;
+
+    /// Encode a packet for node 1 following `instructions`.
+    fn packet(net: &TestNet<ChatParams>, instructions: Vec<Instruction>) -> (Box<[u8]>,Box<[u8]>) {
+        let ratchets = ClientRatchetState::new();
+        let world = net.world(&ratchets);
+        let mut rng = OsRng::new().expect("failed to create an OS RNG");
+        let h = net.build(&world, false, 1, instructions).unwrap();
+        (h.encode_header(&mut rng).unwrap(), h.seal_body(1, b"Hello").unwrap())
+    }
+
+    /// Encode `n` packets that node 1 transmits to node 0, followed
+    /// by one arriving at node 1.
+    fn packets(net: &TestNet<ChatParams>, n: usize) -> Vec<(Box<[u8]>,Box<[u8]>)> {
+        let mut ps: Vec<_> = (0..n).map( |_| packet(net, vec![
+            Instruction::Transmit { route: net.names[0] },
+            Instruction::ArrivalDirect { },
+        ]) ).collect();
+        ps.push( packet(net, vec![ Instruction::ArrivalDirect { } ]) );
+        ps
+    }
+
+    /// Encode a packet that node 1 parks in `slot` for node 0.
+    fn parked(net: &TestNet<ChatParams>, slot: SlotName) -> (Box<[u8]>,Box<[u8]>) {
+        packet(net, vec![
+            Instruction::DropOff { slot },
+            Instruction::Transmit { route: net.names[0] },
+            Instruction::ArrivalDirect { },
+        ])
+    }
+
+    fn outgoing(router: &Router<ChatParams>, route: &::keys::RoutingName)
+      -> Vec<(PacketName,::keys::RoutingName,::std::time::SystemTime,Box<[u8]>,Box<[u8]>)> {
+        let mut o: Vec<_> = router.take_outgoing(route).into_iter()
+          .map( |(n,p)| (n, p.route, p.time, p.header, p.body) ).collect();
+        o.sort_by_key( |x| (x.0).0 );
+        o
+    }
+
+    #[test]
+    fn process_batch_matches_process() {
+        let net = TestNet::<ChatParams>::new(2);
+        let mut ps = packets(&net, 6);
+        // We add a replay and a packet whose alpha we corrupted.
+        let replay = ps[2].clone();
+        ps.push(replay);
+        let mut bad = ps[0].clone();
+        HeaderMuts::<ChatParams>::new_sliced(&mut bad.0).unwrap().alpha[0] ^= 1;
+        ps.insert(3, bad);
+
+        let sequential = net.twin(1);
+        let batched = net.twin(1);
+        let r0: Vec<bool> = ps.iter().cloned().map( |(h,b)| sequential.process(h,b).is_ok() ).collect();
+        let r1: Vec<bool> = batched.process_batch(ps).iter().map( |r| r.is_ok() ).collect();
+        assert_eq!(r0, r1);
+        assert_eq!(r0.iter().filter( |x| **x ).count(), 7);
+
+        let o = outgoing(&sequential, &net.names[0]);
+        assert_eq!(o.len(), 6);
+        assert!( o == outgoing(&batched, &net.names[0]) );
+        let a0 = sequential.arrivals().read().unwrap();
+        let a1 = batched.arrivals().read().unwrap();
+        assert_eq!(a0.len(), 1);
+        assert_eq!(a1.len(), 1);
+        assert_eq!(a0[0].body, a1[0].body);
+    }
+
+    #[test]
+    fn drop_offs_park_and_release() {
+        let net = TestNet::<ChatParams>::new(2);
+        let mut router = net.twin(1);
+        router.set_drop_off_hold(Duration::from_secs(60));
+        let slot = SlotName([7u8; SLOT_NAME_LENGTH]);
+
+        // A later packet carrying our slot releases parked packets.
+        let (h,b) = parked(&net, slot);
+        router.process(h,b).unwrap();
+        assert!( router.take_outgoing(&net.names[0]).is_empty() );
+        let (h,b) = packet(&net, vec![ Instruction::DropOff { slot }, Instruction::ArrivalDirect { } ]);
+        router.process(h,b).unwrap();
+        assert_eq!( router.take_outgoing(&net.names[0]).len(), 1 );
+        assert_eq!( router.arrivals().read().unwrap().len(), 1 );
+
+        // Our holding time expiring also releases parked packets.
+        let (h,b) = parked(&net, slot);
+        router.process(h,b).unwrap();
+        assert_eq!( router.release_drop_offs().unwrap(), 0 );
+        net.clock.advance(Duration::from_secs(61));
+        assert_eq!( router.release_drop_offs().unwrap(), 1 );
+        assert_eq!( router.take_outgoing(&net.names[0]).len(), 1 );
+        assert_eq!( router.release_drop_offs().unwrap(), 0 );
+    }
+
+    #[test]
+    fn drop_offs_refuse_when_full() {
+        let net = TestNet::<ChatParams>::new(2);
+        let mut router = net.twin(1);
+        router.set_drop_off_limits(1, 2);
+        let slots: Vec<_> = (0..3u8).map( |i| SlotName([i; SLOT_NAME_LENGTH]) ).collect();
+        let backpressure = |r: SphinxResult<()>| match r {
+            Err(SphinxError::Backpressure(_)) => { },
+            r => panic!("Expected backpressure, not {:?}", r),
+        };
+
+        let (h,b) = parked(&net, slots[0]);
+        router.process(h,b).unwrap();
+        let (h,b) = parked(&net, slots[0]);
+        backpressure( router.process(h,b) );
+        let (h,b) = parked(&net, slots[1]);
+        router.process(h,b).unwrap();
+        let (h,b) = parked(&net, slots[2]);
+        backpressure( router.process(h,b) );
+
+        // Releasing a slot frees space.
+        let (h,b) = packet(&net, vec![ Instruction::DropOff { slot: slots[0] }, Instruction::ArrivalDirect { } ]);
+        router.process(h,b).unwrap();
+        let (h,b) = parked(&net, slots[2]);
+        router.process(h,b).unwrap();
+        assert_eq!( router.take_outgoing(&net.names[0]).len(), 1 );
+    }
+
+    /// `TestKem` whose ciphertexts get corrupted before we send them.
+    struct CorruptKem;
+
+    impl Kem for CorruptKem {
+        fn encapsulate(&self, public: &KemPublic, seed: &[u8; 32])
+          -> KeysResult<(Box<[u8]>,KemSharedSecret)> {
+            let (mut ciphertext, ss) = TestKem(32).encapsulate(public, seed) ?;
+            ciphertext[0] ^= 1;
+            Ok(( ciphertext, ss ))
+        }
+
+        fn decapsulate(&self, secret: &KemSecret, ciphertext: &[u8])
+          -> KeysResult<KemSharedSecret> {
+            TestKem(32).decapsulate(secret, ciphertext)
+        }
+    }
+
+    #[test]
+    fn hybrid_round_trip() {
+        let net = TestNet::<ChatParams>::new_hybrid(2, TestKem(32));
+        let router = net.twin(1);
+        let ratchets = ClientRatchetState::new();
+        let mut rng = OsRng::new().expect("failed to create an OS RNG");
+        let instructions = || vec![ Instruction::Hybrid { }, Instruction::ArrivalDirect { } ];
+
+        let h = net.build(&net.world(&ratchets), false, 1, instructions()).unwrap();
+        router.process(h.encode_header(&mut rng).unwrap(), h.seal_body(1, b"Hello").unwrap()).unwrap();
+        assert_eq!( router.arrivals().read().unwrap().len(), 1 );
+
+        // The Sphinx sub-hop's gamma covers the wrong ciphertext, so
+        // only the hybrid sub-hop's gamma fails.
+        let corrupt = CorruptKem;
+        let world = net.world(&ratchets).with_kem(&corrupt);
+        let h = net.build(&world, false, 1, instructions()).unwrap();
+        match router.process(h.encode_header(&mut rng).unwrap(), h.seal_body(1, b"Hello").unwrap()) {
+            Err(SphinxError::InvalidMac(_)) => { },
+            r => panic!("Expected InvalidMac, not {:?}", r),
+        }
+        assert_eq!( router.arrivals().read().unwrap().len(), 1 );
+    }
+
+    #[test]
+    fn hybrid_fits_ml_kem_ciphertexts() {
+        use keys::kem::ML_KEM_768_CIPHERTEXT_LENGTH;
+        use super::super::params::HybridChatParams;
+        let kem = TestKem(ML_KEM_768_CIPHERTEXT_LENGTH);
+        let ratchets = ClientRatchetState::new();
+        let instructions = |route| vec![
+            Instruction::Hybrid { },
+            Instruction::Transmit { route },
+            Instruction::ArrivalDirect { },
+        ];
+
+        // Our usual tails cannot hold the ciphertext.
+        let net = TestNet::<ChatParams>::new_hybrid(2, kem);
+        assert!( net.build(&net.world(&ratchets), false, 1, instructions(net.names[0])).is_err() );
+
+        let net = TestNet::<HybridChatParams>::new_hybrid(2, kem);
+        let h = net.build(&net.world(&ratchets), false, 1, instructions(net.names[0])).unwrap();
+        net.send(1, &h, b"Hello").unwrap();
+        assert_eq!( net.relay(1,0).unwrap(), 1 );
+        assert_eq!( net.routers[0].arrivals().read().unwrap().len(), 1 );
+    }
+
+    // We process each batch with a fresh router, so that we measure
+    // processing, not replay detection, in both benchmarks.
+
+    #[bench]
+    fn bench_process_single(b: &mut Bencher) {
+        let net = TestNet::<ChatParams>::new(2);
+        let ps = packets(&net, 64);
+        b.iter( || {
+            let router = net.twin(1);
+            for (h,bd) in ps.iter().cloned() { router.process(h,bd).unwrap(); }
+        } );
+    }
+
+    #[bench]
+    fn bench_process_batch(b: &mut Bencher) {
+        let net = TestNet::<ChatParams>::new(2);
+        let ps = packets(&net, 64);
+        b.iter( || {
+            let router = net.twin(1);
+            for r in router.process_batch(ps.clone()) { r.unwrap(); }
+        } );
+    }
+}
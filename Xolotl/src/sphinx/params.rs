@@ -0,0 +1,151 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Sphinx packet format paramater presets
+//!
+//! We validate every preset at compile time with `params_assert_valid!`.
+
+use keys::time::ValidityPolicy;
+use super::layout::{Params,Length};
+use super::surbs::ProtocolId;
+
+
+/// Paramaters for interactive messaging with small bodies and
+/// short delays.
+#[derive(Debug, Clone, Copy)]
+pub struct ChatParams;
+
+impl Params for ChatParams {
+    const PROTOCOL_ID: ProtocolId = ProtocolId(1);
+    const PROTOCOL_NAME: &'static str = "Xolotl Sphinx Chat v0";
+    const BETA_LENGTH: Length = 1024;
+    const MAX_BETA_TAIL_LENGTH: Length = 64;
+    const MAX_SURB_BETA_LENGTH: Length = 448;
+    const SURB_LOG_LENGTH: Length = 128;
+    const SURB_BETA_LENGTHS: &'static [Length] = &[448];
+    const BODY_LENGTHS: &'static [Length] = &[0, 2048];
+    const VALIDITY_POLICY: ValidityPolicy
+      = ValidityPolicy::Coarsen { granularity: 10*60, fuzz: 6 };
+    const DELAY_LAMBDA: f64 = 0.1;
+}
+
+params_assert_valid!(ChatParams);
+
+
+/// Paramaters for bulk transfers with large bodies and long delays.
+#[derive(Debug, Clone, Copy)]
+pub struct FileParams;
+
+impl Params for FileParams {
+    const PROTOCOL_ID: ProtocolId = ProtocolId(2);
+    const PROTOCOL_NAME: &'static str = "Xolotl Sphinx File v0";
+    const BETA_LENGTH: Length = 1024;
+    const MAX_BETA_TAIL_LENGTH: Length = 64;
+    const MAX_SURB_BETA_LENGTH: Length = 448;
+    const SURB_LOG_LENGTH: Length = 256;
+    const SURB_BETA_LENGTHS: &'static [Length] = &[448];
+    const BODY_LENGTHS: &'static [Length] = &[0, 32*1024, 256*1024];
+    const VALIDITY_POLICY: ValidityPolicy
+      = ValidityPolicy::Coarsen { granularity: 60*60, fuzz: 4 };
+    const DELAY_LAMBDA: f64 = 1.0/600.0;
+}
+
+params_assert_valid!(FileParams);
+
+
+/// `ChatParams` with a beta tail long enough for `Instruction::Report`
+/// with a SURB of up to five hops, along with the command reported.
+///
+/// We lengthen beta too because every sub-hop requesting a report
+/// consumes much of it.
+#[derive(Debug, Clone, Copy)]
+pub struct ReportingChatParams;
+
+impl Params for ReportingChatParams {
+    const PROTOCOL_ID: ProtocolId = ProtocolId(3);
+    const PROTOCOL_NAME: &'static str = "Xolotl Sphinx Reporting Chat v0";
+    const BETA_LENGTH: Length = 1536;
+    const MAX_BETA_TAIL_LENGTH: Length = 256;
+    const MAX_SURB_BETA_LENGTH: Length = 448;
+    const SURB_LOG_LENGTH: Length = 128;
+    const SURB_BETA_LENGTHS: &'static [Length] = &[448];
+    const BODY_LENGTHS: &'static [Length] = &[0, 2048];
+    const VALIDITY_POLICY: ValidityPolicy
+      = ValidityPolicy::Coarsen { granularity: 10*60, fuzz: 6 };
+    const DELAY_LAMBDA: f64 = 0.1;
+}
+
+params_assert_valid!(ReportingChatParams);
+
+
+/// `ChatParams` with a beta tail long enough for `Instruction::Hybrid`
+/// with ML-KEM-768 ciphertexts, for first contact.
+///
+/// Any `keys::kem::Kem` whose ciphertexts fit
+/// `MAX_BETA_TAIL_LENGTH - 19` bytes works, but we ship none, see
+/// `keys::kem`.  A hybrid sub-hop consumes over half of beta, so
+/// clients should use it only for the first hop of a first contact.
+#[derive(Debug, Clone, Copy)]
+pub struct HybridChatParams;
+
+impl Params for HybridChatParams {
+    const PROTOCOL_ID: ProtocolId = ProtocolId(4);
+    const PROTOCOL_NAME: &'static str = "Xolotl Sphinx Hybrid Chat v0";
+    const BETA_LENGTH: Length = 2048;
+    const MAX_BETA_TAIL_LENGTH: Length = 1152;
+    const MAX_SURB_BETA_LENGTH: Length = 448;
+    const SURB_LOG_LENGTH: Length = 128;
+    const SURB_BETA_LENGTHS: &'static [Length] = &[448];
+    const BODY_LENGTHS: &'static [Length] = &[0, 2048];
+    const VALIDITY_POLICY: ValidityPolicy
+      = ValidityPolicy::Coarsen { granularity: 10*60, fuzz: 6 };
+    const DELAY_LAMBDA: f64 = 0.1;
+}
+
+params_assert_valid!(HybridChatParams);
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::{RoutingName,ROUTING_NAME_LENGTH};
+    use keys::kem::ML_KEM_768_CIPHERTEXT_LENGTH;
+    use super::super::commands::Command;
+    use super::super::layout::ImplParams;
+    use super::super::stream::{Gamma,GAMMA_LENGTH};
+
+    #[test]
+    fn presets_valid() {
+        ChatParams::check_lengths().unwrap();
+        ChatParams::check_body_length(2048).unwrap();
+        assert!( ChatParams::check_body_length(1000).is_err() );
+        assert!( ChatParams::max_hops_capacity() >= 16 );
+
+        FileParams::check_lengths().unwrap();
+        FileParams::check_body_length(256*1024).unwrap();
+        assert!( FileParams::max_hops_capacity() >= 16 );
+
+        ReportingChatParams::check_lengths().unwrap();
+        assert!( ReportingChatParams::max_hops_capacity() >= 16 );
+        HybridChatParams::check_lengths().unwrap();
+        assert!( HybridChatParams::max_hops_capacity() >= 16 );
+    }
+
+    #[test]
+    fn presets_fit_long_commands() {
+        let route = RoutingName([0u8; ROUTING_NAME_LENGTH]);
+        let gamma = Gamma([0u8; GAMMA_LENGTH]);
+        let transmit = Command::Transmit::<Gamma,usize> { route, gamma }.command_length();
+
+        // A report whose SURB transmits four times before arriving,
+        // followed by the transmit command it reports upon.
+        let surb_beta = vec![0u8; 4*transmit + 1].into_boxed_slice();
+        let report = Command::Report::<Gamma,usize> { route, alpha: [0u8; 32], gamma, surb_beta }.command_length();
+        assert!( report + transmit <= ReportingChatParams::MAX_BETA_TAIL_LENGTH );
+        assert!( report + transmit > ChatParams::MAX_BETA_TAIL_LENGTH );
+
+        let ciphertext = vec![0u8; ML_KEM_768_CIPHERTEXT_LENGTH].into_boxed_slice();
+        let hybrid = Command::Hybrid::<Gamma,usize> { ciphertext, gamma }.command_length();
+        assert!( hybrid <= HybridChatParams::MAX_BETA_TAIL_LENGTH );
+        assert!( hybrid > ChatParams::MAX_BETA_TAIL_LENGTH );
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Multi-threaded packet processing for mix nodes
+//!
+//! We feed incoming packets through a bounded queue to a pool of
+//! worker threads, each of which runs `Router::process`.  We refuse
+//! new packets, instead of queuing them, whenever either the input
+//! queue fills or too many packets await transmission, so that
+//! overload propagates back to the nodes sending to us.
+
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::sync::mpsc::{sync_channel,SyncSender,Receiver,TrySendError};
+use std::thread::{self,JoinHandle};
+
+use super::node::Router;
+use super::layout::Params;
+use super::error::*;
+
+
+/// Incoming header and body awaiting processing.
+type Packet = (Box<[u8]>,Box<[u8]>);
+
+/// Configuration for a `Pipeline`.
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    /// Number of worker threads running `Router::process`.
+    pub workers: usize,
+
+    /// Maximum number of packets awaiting processing.
+    pub queue_depth: usize,
+
+    /// Maximum number of processed packets awaiting transmission
+    /// before we refuse new packets.
+    pub max_outgoing: usize,
+}
+
+/// Counters describing pipeline activity.
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    /// Packets processed successfully.
+    pub processed: AtomicUsize,
+
+    /// Packets dropped by `Router::process` returning an error.
+    pub dropped: AtomicUsize,
+
+    /// Packets refused due to backpressure.
+    pub refused: AtomicUsize,
+}
+
+/// Pool of worker threads processing packets for one `Router`.
+pub struct Pipeline<P: Params> {
+    router: Arc<Router<P>>,
+    config: PipelineConfig,
+    input: Option<SyncSender<Packet>>,
+    workers: Vec<JoinHandle<()>>,
+    pub stats: Arc<PipelineStats>,
+}
+
+impl<P> Pipeline<P> where P: Params+Send+Sync+'static {
+    /// Spawn `config.workers` worker threads processing packets
+    /// for `router`.
+    pub fn start(router: Arc<Router<P>>, config: PipelineConfig) -> Pipeline<P> {
+        let (input, output) = sync_channel::<Packet>(config.queue_depth);
+        let output = Arc::new(Mutex::new(output));
+        let stats = Arc::new(PipelineStats::default());
+        let workers = (0..::std::cmp::max(config.workers,1)).map( |_| {
+            let router = router.clone();
+            let output = output.clone();
+            let stats = stats.clone();
+            thread::spawn( move || work(&router, &output, &stats) )
+        } ).collect();
+        Pipeline { router, config, input: Some(input), workers, stats }
+    }
+
+    fn check_outgoing(&self) -> SphinxResult<()> {
+        if self.router.outgoing_depth() >= self.config.max_outgoing {
+            self.stats.refused.fetch_add(1, Ordering::Relaxed);
+            return Err( SphinxError::Backpressure("Outgoing queues full") );
+        }
+        Ok(())
+    }
+
+    fn input(&self) -> SphinxResult<&SyncSender<Packet>> {
+        self.input.as_ref().ok_or( SphinxError::InternalError("Pipeline shut down") )
+    }
+
+    /// Queue a packet for processing, or refuse it if either our
+    /// input queue or outgoing queues are full.
+    pub fn submit(&self, header: Box<[u8]>, body: Box<[u8]>) -> SphinxResult<()> {
+        self.check_outgoing() ?;  // Backpressure
+        match self.input()?.try_send((header,body)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.stats.refused.fetch_add(1, Ordering::Relaxed);
+                Err( SphinxError::Backpressure("Input queue full") )
+            },
+            Err(TrySendError::Disconnected(_)) =>
+                Err( SphinxError::InternalError("Pipeline workers exited") ),
+        }
+    }
+
+    /// Queue a packet for processing, blocking while our input queue
+    /// is full, but refuse it if our outgoing queues are full.
+    pub fn submit_blocking(&self, header: Box<[u8]>, body: Box<[u8]>) -> SphinxResult<()> {
+        self.check_outgoing() ?;  // Backpressure
+        self.input()?.send((header,body))
+          .map_err( |_| SphinxError::InternalError("Pipeline workers exited") )
+    }
+}
+
+impl<P: Params> Pipeline<P> {
+    /// Stop accepting packets, finish processing queued packets,
+    /// and wait for all worker threads to exit.
+    pub fn shutdown(&mut self) {
+        self.input = None;
+        for w in self.workers.drain(..) {
+            let _ = w.join();  // Worker panics already poisoned their locks.
+        }
+    }
+}
+
+impl<P: Params> Drop for Pipeline<P> {
+    fn drop(&mut self) { self.shutdown(); }
+}
+
+/// Worker thread main loop, which exits once `Pipeline` drops its
+/// `SyncSender` and the queue empties.
+fn work<P: Params>(router: &Router<P>, output: &Mutex<Receiver<Packet>>, stats: &PipelineStats) {
+    loop {
+        // We hold the lock only while waiting for one packet.
+        let packet = {
+            let output = output.lock().unwrap_or_else(|x| x.into_inner());
+            output.recv()
+        };
+        let (header,body) = if let Ok(p) = packet { p } else { return; };
+        match router.process(header,body) {
+            Ok(()) => stats.processed.fetch_add(1, Ordering::Relaxed),
+            Err(_) => stats.dropped.fetch_add(1, Ordering::Relaxed),
+              // TODO: Log errors
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use rand::OsRng;
+    use super::super::client::ClientRatchetState;
+    use super::super::commands::Instruction;
+    use super::super::params::ChatParams;
+    use super::super::testing::TestNet;
+    use super::*;
+
+    /// Encode `n` packets arriving at node `first`, or transmitted
+    /// by node `first` to node `next`.
+    fn packets(net: &TestNet<ChatParams>, n: usize, first: usize, next: Option<usize>) -> Vec<Packet> {
+        let ratchets = ClientRatchetState::new();
+        let world = net.world(&ratchets);
+        let mut rng = OsRng::new().expect("failed to create an OS RNG");
+        (0..n).map( |_| {
+            let mut instructions = Vec::new();
+            if let Some(i) = next { instructions.push(Instruction::Transmit { route: net.names[i] }); }
+            instructions.push(Instruction::ArrivalDirect { });
+            let h = net.build(&world, false, first, instructions).unwrap();
+            (h.encode_header(&mut rng).unwrap(), h.seal_body(1, b"Hello").unwrap())
+        } ).collect()
+    }
+
+    /// Wait until workers finished processing `n` packets.
+    fn wait(stats: &PipelineStats, n: usize) {
+        for _ in 0..1000 {
+            let done = stats.processed.load(Ordering::Relaxed) + stats.dropped.load(Ordering::Relaxed);
+            if done >= n { return; }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Pipeline stalled");
+    }
+
+    #[test]
+    fn pipeline_processes_and_shuts_down() {
+        let mut net = TestNet::<ChatParams>::new(2);
+        let (header,body) = packets(&net, 1, 1, Some(0)).pop().unwrap();
+        let router = Arc::new(net.routers.remove(1));
+        let config = PipelineConfig { workers: 2, queue_depth: 4, max_outgoing: 16 };
+        let mut pipeline = Pipeline::start(router.clone(), config);
+
+        // Workers drop the replay, whichever they process second.
+        pipeline.submit(header.clone(), body.clone()).unwrap();
+        pipeline.submit(header.clone(), body.clone()).unwrap();
+
+        // Shutting down finishes all queued packets before joining.
+        pipeline.shutdown();
+        assert_eq!(pipeline.stats.processed.load(Ordering::Relaxed), 1);
+        assert_eq!(pipeline.stats.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(router.take_outgoing(&net.names[0]).len(), 1);
+        match pipeline.submit(header, body) {
+            Err(SphinxError::InternalError(_)) => { },
+            r => panic!("Submitted after shutdown: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn pipeline_refuses_when_outgoing_full() {
+        let mut net = TestNet::<ChatParams>::new(2);
+        let mut ps = packets(&net, 2, 1, Some(0));
+        let router = Arc::new(net.routers.remove(1));
+        let config = PipelineConfig { workers: 1, queue_depth: 4, max_outgoing: 1 };
+        let pipeline = Pipeline::start(router.clone(), config);
+
+        let (header,body) = ps.pop().unwrap();
+        pipeline.submit_blocking(header,body).unwrap();
+        wait(&pipeline.stats, 1);
+        assert_eq!(router.outgoing_depth(), 1);
+
+        let (header,body) = ps.pop().unwrap();
+        match pipeline.submit(header.clone(), body.clone()) {
+            Err(SphinxError::Backpressure(_)) => { },
+            r => panic!("Expected backpressure: {:?}", r),
+        }
+        assert!( pipeline.submit_blocking(header.clone(), body.clone()).is_err() );
+        assert_eq!(pipeline.stats.refused.load(Ordering::Relaxed), 2);
+
+        // Transmitting our queued packet relieves the backpressure.
+        assert_eq!(router.take_outgoing(&net.names[0]).len(), 1);
+        pipeline.submit(header,body).unwrap();
+        wait(&pipeline.stats, 2);
+        assert_eq!(pipeline.stats.processed.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn pipeline_refuses_when_input_full() {
+        let mut net = TestNet::<ChatParams>::new(1);
+        let ps = packets(&net, 3, 0, None);
+        let router = Arc::new(net.routers.pop().unwrap());
+        let config = PipelineConfig { workers: 1, queue_depth: 1, max_outgoing: 16 };
+        let mut pipeline = Pipeline::start(router.clone(), config);
+
+        let mut accepted = 0;
+        {
+            // Our only worker blocks storing its first arrival, so our
+            // queue accepts at most one more packet.
+            let _arrivals = router.arrivals().write().unwrap();
+            for (header,body) in ps {
+                match pipeline.submit(header,body) {
+                    Ok(()) => accepted += 1,
+                    Err(SphinxError::Backpressure(_)) => { },
+                    Err(e) => panic!("Unexpected error {:?}", e),
+                }
+            }
+        }
+        assert!( accepted >= 1 && accepted <= 2 );
+        assert_eq!(pipeline.stats.refused.load(Ordering::Relaxed), 3 - accepted);
+
+        pipeline.shutdown();
+        assert_eq!(pipeline.stats.processed.load(Ordering::Relaxed), accepted);
+        assert_eq!(router.arrivals().read().unwrap().len(), accepted);
+    }
+}
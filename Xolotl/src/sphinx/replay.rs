@@ -86,5 +86,62 @@ impl<'l,R> ReplayChecker for &'l RwLock<R> where for <'r> &'r mut R: ReplayCheck
 
 // pub type trait ReplayFilter = Filter<Key = ReplayCode>;
 
-pub type ReplayFilterStore = RwLock<HashSetFilter<ReplayCode>>;
+/// Replay filter split into independently locked shards to reduce
+/// lock contention when many threads process packets.
+///
+/// We select the shard using the replay code's first byte, which
+/// requires no hashing because replay codes are pseudo-random.
+pub struct ShardedReplayFilter<R> {
+    shards: Vec<RwLock<R>>,
+}
+
+impl<R> ShardedReplayFilter<R> where R: Filter<Key=ReplayCode> {
+    /// Create a filter with `shards` shards, with at least one shard
+    /// and at most 256.
+    pub fn new(hs: HasherState, shards: usize) -> ShardedReplayFilter<R> {
+        let shards = ::std::cmp::min(::std::cmp::max(shards,1), 0x100);
+        ShardedReplayFilter {
+            shards: (0..shards).map(|_| RwLock::new(R::new(hs))).collect(),
+        }
+    }
+
+    fn shard(&self, replay_code: &ReplayCode) -> &RwLock<R> {
+        &self.shards[ replay_code.0[0] as usize % self.shards.len() ]
+    }
+}
+
+impl<'l,R> ReplayChecker for &'l ShardedReplayFilter<R>
+  where R: Filter<Key=ReplayCode>, for <'r> &'r mut R: ReplayChecker {
+    /// Replay detection logic layer for `ShardedReplayFilter`
+    fn replay_check(self, replay_code: &ReplayCode) -> Result<(),SphinxError> {
+        self.shard(replay_code).replay_check(replay_code)
+    }
+}
+
+pub type ReplayFilterStore = ShardedReplayFilter<HashSetFilter<ReplayCode>>;
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn sharded_replays_detected() {
+        let filter = Arc::new( ReplayFilterStore::new(HasherState::new(), 8) );
+        let threads: Vec<_> = (0..4u8).map( |t| {
+            let filter = filter.clone();
+            thread::spawn( move || {
+                for i in 0..64u8 {
+                    let mut rc = ReplayCode::default();
+                    rc.0[0] = i;  rc.0[1] = t;
+                    assert!( (&*filter).replay_check(&rc).is_ok() );
+                    assert!( (&*filter).replay_check(&rc).is_err() );
+                }
+            } )
+        } ).collect();
+        for t in threads { t.join().unwrap(); }
+    }
+}
 
@@ -0,0 +1,249 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Error reports for dropped packets
+//!
+//! A sender may request, using `Instruction::Report`, that a hop
+//! report dropping its packet along a SURB embedded in beta.  Any
+//! such report identifies the packet only by the hop's `ErrorPacketId`,
+//! which the sender learns when building the header, but nobody else
+//! learns.
+//!
+//! `ErrorPacketId`s are empty unless built with the `error_reports`
+//! feature, so without it nodes send no reports because nobody could
+//! correlate them.
+//!
+//! Nodes report only failures after gamma authenticates beta and the
+//! replay filter admits the packet, so nobody can forge or replay
+//! report requests, and each packet yields at most one report.
+
+use std::collections::HashMap;
+
+use ::state::HasherState;
+use super::mailbox::ArrivingStore;
+use super::ack;
+use super::error::*;
+
+
+/// Reason a hop dropped a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The routing key exists but is not currently valid.
+    KeyNotValid = 1,
+
+    /// The ratchet could not advance, probably due to an unknown branch.
+    Ratchet = 2,
+
+    /// Any other failure after decrypting beta.
+    BadPacket = 3,
+}
+
+impl ErrorCode {
+    /// Classify an error for reporting.
+    ///
+    /// We never report replays because doing so helps attackers
+    /// and honest senders never replay packets.  We never report
+    /// MAC failures either, not even for ratchet sub-hops, because
+    /// anyone may corrupt a packet in flight.
+    pub fn from_error(e: &SphinxError) -> Option<ErrorCode> {
+        use super::error::SphinxError::*;
+        match *e {
+            InvalidMac(_) => None,
+            KeysError(_) => Some(ErrorCode::KeyNotValid),
+            RatchetError(_) => Some(ErrorCode::Ratchet),
+            Replay(_) => None,
+            _ => Some(ErrorCode::BadPacket),
+        }
+    }
+
+    pub fn from_byte(b: u8) -> Option<ErrorCode> {
+        match b {
+            1 => Some(ErrorCode::KeyNotValid),
+            2 => Some(ErrorCode::Ratchet),
+            3 => Some(ErrorCode::BadPacket),
+            _ => None,
+        }
+    }
+}
+
+
+pub const ERROR_REPORT_LENGTH : usize = ERROR_PACKET_ID_LENGTH + 1;
+
+/// Compact report that a hop dropped the packet identified by `id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub id: ErrorPacketId,
+    pub code: ErrorCode,
+}
+
+impl ErrorReport {
+    pub fn to_bytes(&self) -> [u8; ERROR_REPORT_LENGTH] {
+        let mut r = [0u8; ERROR_REPORT_LENGTH];
+        r[..ERROR_PACKET_ID_LENGTH].copy_from_slice(&self.id.to_bytes());
+        r[ERROR_PACKET_ID_LENGTH] = self.code as u8;
+        r
+    }
+
+    pub fn from_bytes(b: &[u8]) -> SphinxResult<ErrorReport> {
+        if b.len() != ERROR_REPORT_LENGTH {
+            return Err( SphinxError::BadLength("Error report has wrong length", b.len()) );
+        }
+        let mut id = [0u8; ERROR_PACKET_ID_LENGTH];
+        id.copy_from_slice(&b[..ERROR_PACKET_ID_LENGTH]);
+        let code = b[ERROR_PACKET_ID_LENGTH];
+        let code = ErrorCode::from_byte(code)
+          .ok_or( SphinxError::BadPacket("Unknown error report code", code as u64) ) ?;
+        Ok( ErrorReport { id: ErrorPacketId::from_bytes(&id), code } )
+    }
+}
+
+
+/// Maps error reports back to the sends that requested them.
+///
+/// Register every send along with the ids returned by
+/// `NewHeader::report_ids`, and forget sends once acknowledged.
+pub struct ErrorCorrelator<T> {
+    sends: HashMap<ErrorPacketId,T,HasherState>,
+}
+
+impl<T: Clone> ErrorCorrelator<T> {
+    pub fn new(hs: HasherState) -> ErrorCorrelator<T> {
+        ErrorCorrelator { sends: HashMap::with_hasher(hs) }
+    }
+
+    /// Number of ids awaiting possible reports.
+    pub fn pending(&self) -> usize { self.sends.len() }
+
+    /// Record that reports for any of `ids` concern `send`.
+    pub fn register(&mut self, ids: &[ErrorPacketId], send: T) {
+        for i in ids.iter() { self.sends.insert(*i, send.clone()); }
+    }
+
+    /// Stop expecting reports for `ids`.
+    pub fn forget(&mut self, ids: &[ErrorPacketId]) {
+        for i in ids.iter() { self.sends.remove(i); }
+    }
+
+    /// Find the send concerned by a report, if any.  We keep the
+    /// send's other ids because other hops cannot report the same
+    /// packet once one hop dropped it, but a retransmission may
+    /// share `send`.
+    pub fn correlate(&mut self, report: &ErrorReport) -> Option<(T,ErrorCode)> {
+        self.sends.remove(&report.id).map(|t| (t,report.code))
+    }
+
+    /// Parse and correlate a raw report.
+    pub fn correlate_bytes(&mut self, report: &[u8]) -> SphinxResult<Option<(T,ErrorCode)>> {
+        let report = ErrorReport::from_bytes(report) ?;  // BadLength, BadPacket
+        Ok( self.correlate(&report) )
+    }
+
+    /// Remove error reports concerning our sends from `arrivals`,
+    /// leaving all other arriving packets in place, and return the
+    /// sends they concern.
+    ///
+    /// Reports arrive with the empty acknowledgement prefix added by
+    /// `ack::fire_surb`, unless `Router::set_fire_acks` removed it.
+    pub fn drain_reports(&mut self, arrivals: &ArrivingStore) -> Vec<(T,ErrorCode)> {
+        let mut arrivals = arrivals.write().unwrap();  // PoisonError ???
+        let mut reports = Vec::new();
+        let mut i = 0;
+        while i < arrivals.len() {
+            let r = {
+                let body = &arrivals[i].body[..];
+                let body = match ack::detach_ack(body) {
+                    Ok((None,rest)) if rest.len() == ERROR_REPORT_LENGTH => rest,
+                    _ => body,
+                };
+                self.correlate_bytes(body).ok().and_then(|r| r)
+            };
+            if let Some(r) = r {
+                arrivals.swap_remove(i);
+                reports.push(r);
+            } else { i += 1; }
+        }
+        reports
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::OsRng;
+    use super::super::client::ClientRatchetState;
+    use super::super::commands::Instruction;
+    use super::super::layout::{HeaderMuts,PreHeader};
+    use super::super::params::ReportingChatParams;
+    use super::super::testing::TestNet;
+    use super::*;
+
+    #[test]
+    fn error_reports_correlate() {
+        let mut c = ErrorCorrelator::<u32>::new(HasherState::new());
+        let ids = [ErrorPacketId([1u8; 16]), ErrorPacketId([2u8; 16])];
+        c.register(&ids, 7);
+        c.register(&[ErrorPacketId([3u8; 16])], 8);
+
+        let r = ErrorReport { id: ErrorPacketId([2u8; 16]), code: ErrorCode::Ratchet };
+        let b = r.to_bytes();
+        assert_eq!(ErrorReport::from_bytes(&b).unwrap(), r);
+        assert_eq!(c.correlate_bytes(&b).unwrap(), Some((7,ErrorCode::Ratchet)));
+        assert_eq!(c.correlate_bytes(&b).unwrap(), None);
+        assert!( c.correlate_bytes(&b[1..]).is_err() );
+
+        c.forget(&ids);
+        assert_eq!(c.pending(), 1);
+    }
+
+    #[test]
+    fn reports_return_along_surbs() {
+        let net = TestNet::<ReportingChatParams>::new(3);
+        let ratchets = ClientRatchetState::new();
+        let world = net.world(&ratchets);
+
+        // Our report SURBs arrive directly at us on node 0.
+        let surb = || -> PreHeader {
+            let s = net.build(&world, true, 0, vec![ Instruction::ArrivalSURB { } ]).unwrap();
+            let (surb,keys) = s.into_surb().unwrap();
+            net.routers[0].surbs().insert_arrival(keys).unwrap();
+            surb
+        };
+        // We request reports from both node 1 and node 2, but node 2
+        // knows no SURB for our arrival.
+        let instructions = || vec![
+            Instruction::Report { surb: surb() },
+            Instruction::Transmit { route: net.names[2] },
+            Instruction::Report { surb: surb() },
+            Instruction::ArrivalSURB { },
+        ];
+        let mut correlator = ErrorCorrelator::new(HasherState::new());
+
+        // Node 2 drops our packet after decrypting beta, but reports
+        // only once when we replay the packet.
+        let h = net.build(&world, false, 1, instructions()).unwrap();
+        assert_eq!(h.report_ids().len(), 2);
+        correlator.register(h.report_ids(), 7u32);
+        net.send(1, &h, b"Hello").unwrap();
+        let forwarded = net.routers[1].take_outgoing(&net.names[2]);
+        assert_eq!(forwarded.len(), 1);
+        for _ in 0..2 {
+            let p = &forwarded[0].1;
+            assert!( net.routers[2].process(p.header.clone(), p.body.clone()).is_err() );
+        }
+        assert_eq!(net.relay(2,0).unwrap(), 1);
+        assert_eq!(correlator.drain_reports(net.routers[0].arrivals()), vec![(7,ErrorCode::BadPacket)]);
+
+        // Node 1 drops our packet because gamma fails to verify, which
+        // nobody may learn, because anyone could corrupt gamma.
+        let h = net.build(&world, false, 1, instructions()).unwrap();
+        correlator.register(h.report_ids(), 8u32);
+        let mut rng = OsRng::new().expect("failed to create an OS RNG");
+        let body = h.seal_body(1, b"Hello").unwrap();
+        let mut header = h.encode_header(&mut rng).unwrap();
+        HeaderMuts::<ReportingChatParams>::new_sliced(&mut header).unwrap().gamma[0] ^= 1;
+        assert!( net.routers[1].process(header, body).is_err() );
+        assert_eq!(net.relay(1,2).unwrap(), 0);
+        assert_eq!(net.relay(1,0).unwrap(), 0);
+        assert!( correlator.drain_reports(net.routers[0].arrivals()).is_empty() );
+        assert!( net.routers[0].arrivals().read().unwrap().is_empty() );
+    }
+}
@@ -6,6 +6,8 @@
 
 use std::iter::{Iterator,IntoIterator,TrustedLen};  // ExactSizeIterator
 
+use super::error::*;
+
 
 /*
 pub fn set_slice<T>(s: &mut [T], z: T) {
@@ -29,6 +31,22 @@ macro_rules! reserve_fixed { ($heap:expr, $len:expr) => {
     array_ref![reserve($heap,$len),0,$len]
 } }
 
+/// A version of `reserve` that returns a `BadLength` error, instead
+/// of panicking, if `heap` is shorter than `len`.  Use this when
+/// parsing untrusted input.
+pub fn try_reserve<'heap, T>(heap: &mut &'heap [T], len: usize) -> SphinxResult<&'heap [T]> {
+    if heap.len() < len {
+        return Err( SphinxError::BadLength("Input too short to parse", heap.len()) );
+    }
+    Ok( reserve(heap,len) )
+}
+
+/// A version of `try_reserve` for fixed length arrays, which
+/// returns any error from the enclosing function.
+macro_rules! try_reserve_fixed { ($heap:expr, $len:expr) => {
+    array_ref![try_reserve($heap,$len)?,0,$len]
+} }
+
 /// Returns an initial segment of a `mut &mut [T]` replacing the inner
 /// `&mut [T]` with the remainder.  In effect, this executes the command
 /// `(return,heap) = heap.split_at_mut(len)` without annoying the borrow
@@ -140,6 +140,33 @@ impl<P: Params> SphinxKey<P> {
         }
     }
 
+    /// Derive the key material for a hybrid sub-hop by mixing the
+    /// shared secret `kem_ss` from a post-quantum KEM into the key
+    /// material `ss` of the preceding sub-hop, so that breaking the
+    /// key exchange behind `ss` alone reveals nothing.
+    pub fn new_kdf_hybrid(ss: &SphinxSecret, kem_ss: &::keys::kem::KemSharedSecret, rn: &::keys::RoutingName)
+      -> SphinxKey<P> {
+        use crypto::digest::Digest;
+        use crypto::sha3::Sha3;
+
+        let r = &mut [0u8; 32+16];  // ClearOnDrop
+        let mut sha = Sha3::shake_256();
+        sha.input(&ss.0);
+        sha.input_str( "Hybrid" );
+        sha.input(kem_ss);
+        sha.input(&rn.0);
+        sha.input_str( P::PROTOCOL_NAME );
+        sha.input(&ss.0);
+        sha.result(r);
+        sha.reset();
+
+        let (nonce,_,key) = array_refs![r,12,4,32];
+        SphinxKey {
+            params: PhantomData,
+            chacha: ChaChaKnN { nonce: *nonce, key: *key },
+        }
+    }
+
     /// Initalize our IETF ChaCha20 stream cipher by invoking 
     /// `ChaChaKnN::header_cipher` with our paramaters `P: Params`.
     pub fn header_cipher(&self) -> SphinxResult<HeaderCipher<P>> {
@@ -245,7 +272,14 @@ impl<P: Params> HeaderCipher<P> {
     ///
     /// Does not verify the lengths of Beta or the SURB.
     pub fn create_gamma(&self, beta: &[u8]) -> SphinxResult<Gamma> {
-        if beta.len() != P::BETA_LENGTH as usize {
+        self.create_gamma_parts(beta,&[])
+    }
+
+    /// Compute the poly1305 MAC `Gamma` of beta supplied as a head
+    /// followed by a tail, as when building headers where the tail
+    /// comes from previous hops.
+    pub fn create_gamma_parts(&self, head: &[u8], tail: &[u8]) -> SphinxResult<Gamma> {
+        if head.len() + tail.len() != P::BETA_LENGTH as usize {
             return Err( SphinxError::InternalError("Beta has the incorrect length for MAC!") );
         }
 
@@ -255,7 +289,8 @@ impl<P: Params> HeaderCipher<P> {
 
         let mut poly = Poly1305::new(&self.gamma_key.0);
         // let mut poly = ClearOnDrop::new(&mut poly);
-        poly.input(beta);
+        poly.input(head);
+        poly.input(tail);
         poly.raw_result(&mut gamma_out.0);
         poly.reset();
         Ok(gamma_out)
@@ -315,6 +350,12 @@ impl<P: Params> HeaderCipher<P> {
         &self.packet_name
     }
 
+    /// Returns our replay code, which also identifies the packet in
+    /// error reports.
+    pub fn replay_code(&self) -> &ReplayCode {
+        &self.replay_code
+    }
+
     pub fn xor_beta(&mut self, beta: &mut [u8], offset: usize, tail: usize)
       -> SphinxResult<()> {
         let len = P::BETA_LENGTH as usize - offset;
@@ -332,6 +373,23 @@ impl<P: Params> HeaderCipher<P> {
         Ok(())
     }
 
+    /// Encrypt only the first `head.len()` bytes of beta, as when
+    /// building headers where the remainder comes from previous hops.
+    pub fn xor_beta_head(&mut self, head: &mut [u8]) -> SphinxResult<()> {
+        self.xor_beta_part(head,0)
+    }
+
+    /// Encrypt only the bytes of beta starting at `offset`, as when
+    /// a command requires only part of beta.
+    pub fn xor_beta_part(&mut self, part: &mut [u8], offset: usize) -> SphinxResult<()> {
+        if offset + part.len() > P::BETA_LENGTH as usize {
+            return Err( SphinxError::InternalError("Beta too long to encrypt!") );
+        }
+        self.stream.seek_to((self.chunks.beta.start + offset) as u64).unwrap();
+        self.stream.xor_read(part).unwrap();
+        Ok(())
+    }
+
     pub fn set_beta_tail(&mut self, beta_tail: &mut [u8]) -> SphinxResult<()> {
         if beta_tail.len() > P::MAX_BETA_TAIL_LENGTH as usize {
             return Err( SphinxError::InternalError("Beta's tail is too long!") );
@@ -352,6 +410,9 @@ impl<P: Params> HeaderCipher<P> {
     }
 
     /// Sender's sugested delay for this packet.
+    ///
+    /// Add this to the time given by a `keys::time::Clock`, not
+    /// `SystemTime::now()`, when scheduling packets.
     pub fn delay(&mut self) -> ::std::time::Duration {
         use rand::{ChaChaRng, SeedableRng}; // Rng, Rand
         let mut rng = {
@@ -377,10 +438,10 @@ impl<P: Params> HeaderCipher<P> {
         // )
     }
 
-    // /// Approximate time when mix node should forward this packet
-    // pub fn time(&mut self) -> ::std::time::SystemTime {
-    //     ::std::time::SystemTime::now() + self.delay()
-    // }
+    /// Approximate time when mix node should forward this packet
+    pub fn time<K: ::keys::time::Clock+?Sized>(&mut self, clock: &K) -> ::std::time::SystemTime {
+        clock.now() + self.delay()
+    }
 }
 
 
@@ -4,9 +4,9 @@
 //!
 //! ...
 
-// use std::collections::HashMap;
+use std::collections::HashMap;
 // use std::hash::Hash; // Hasher
-// use std::sync::{RwLock}; // RwLockReadGuard, RwLockWriteGuard
+use std::sync::{RwLock}; // RwLockReadGuard, RwLockWriteGuard
 use std::iter::Iterator;
 use std::marker::PhantomData;
 
@@ -18,7 +18,7 @@ use super::error::*;
 use super::slice::*;
 use super::*;
 
-// use ::state::{HasherState};
+use ::state::{HasherState};
 
 
 pub const MAX_SURB_METADATA : usize = 8;
@@ -42,7 +42,8 @@ struct ArrivalSURB  {
 }
 
 // TODO: Make protocol name reference params
-pub struct ProtocolId(u16);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProtocolId(pub u16);
 
 pub struct DeliverySURB {
     pub protocol: ProtocolId,
@@ -61,9 +62,38 @@ pub struct SURBStore<P: Params> {
 
 
 impl<P: Params> SURBStore<P> {
-    // pub new() -> SURBStore {
-    //     ;
-    // }
+    pub fn new(hs: HasherState) -> SURBStore<P> {
+        SURBStore {
+            params: PhantomData,
+            arrivals: RwLock::new(HashMap::with_hasher(hs)),
+            deliverys: RwLock::new(HashMap::with_hasher(hs)),
+        }
+    }
+
+    /// Archive a SURB that returns directly to us, without passing
+    /// through a mailbox, and return the packet name with which it
+    /// arrives.
+    ///
+    /// We identify the arrival by the packet name of its final hop,
+    /// which `Router::do_crypto` computes when it finds the
+    /// `Command::ArrivalSURB`.
+    pub fn insert_arrival(&self, surb: DeliverySURB) -> SphinxResult<PacketName> {
+        let packet_name = {
+            let key = surb.hops.last()
+              .ok_or( SphinxError::InternalError("SURB has no hops.") ) ?;
+            let mut hop = key.chacha.header_cipher::<P>() ?;
+              // InternalError: ChaCha stream exceeded
+            *hop.packet_name()
+        };
+        let mut arrivals = self.arrivals.write().unwrap();  // PoisonError ??
+        let mut deliverys = self.deliverys.write().unwrap();  // PoisonError ??
+        if arrivals.contains_key(&packet_name) || deliverys.contains_key(&packet_name) {
+            return Err( SphinxError::InternalError("Packet name collision detected!") );
+        }
+        arrivals.insert(packet_name, ArrivalSURB { delivery_name: packet_name });
+        deliverys.insert(packet_name, surb);
+        Ok(packet_name)
+    }
 
     /// Unwind a chain of SURBs from an arival packet name.
     /// 
@@ -102,13 +132,15 @@ impl<P: Params> SURBStore<P> {
                 if let Some(s) = deliverys.remove(&packet_name) { s } else { break; }
             };
             metadata.push(meta);
+            // TODO: Record berries from ratchet sub-hops, which
+            // `Scaffold` refuses to build into SURBs until we do.
+            // Should we write to the data base here, taking and
+            // releasing locks frequently?  Or return the list to
+            // add all at once?
+            if hops.iter().any( |key| key.berry_twig.is_some() ) {
+                return Err( SphinxError::InternalError("SURB unwinding cannot record ratchet berries") );
+            }
             for key in hops.iter().rev() {
-                if let Some(berry_twig) = key.berry_twig {
-                    unimplemented!();
-                    // TODO: Should we write to the data base here, taking
-                    // and releasing locks frequently?  Or return the list
-                    // to add all at once? 
-                }
                 // TODO: Use protocol specified in the delivery surb
                 let mut hop = key.chacha.header_cipher::<P>() ?;
                   // InternalError: ChaCha stream exceeded
@@ -0,0 +1,205 @@
+// Copyright 2016 Jeffrey Burdges.
+
+//! Fixtures for tests that run packets built by clients through
+//! `Router`s, shared by the tests of several modules.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use rand::{OsRng,Rng};
+
+use ::state::HasherState;
+use keys::{RoutingName,RoutingPublic,RoutingSecret,IssuerPublicKey,IssuerSecret,Concensus};
+use keys::kem::{Kem,KemPublic,KemSecret,KemSharedSecret};
+use keys::concensus::RoutePicker;
+use keys::time::{Clock,MockClock,ValidityPeriod};
+use keys::error::*;
+use super::client::{World,NewHeader,ClientRatchetState};
+use super::commands::Instruction;
+use super::node::{Router,RatchetState};
+use super::layout::Params;
+use super::error::*;
+
+
+/// Deterministic `Kem` whose public key hashes its secret key, whose
+/// ciphertext is the encapsulation seed padded with zeros to the
+/// given length, and whose shared secret hashes the public key with
+/// the ciphertext.  Insecure of course.
+#[derive(Debug, Clone, Copy)]
+pub struct TestKem(pub usize);
+
+impl TestKem {
+    fn hash(parts: &[&[u8]]) -> [u8; 32] {
+        use crypto::digest::Digest;
+        use crypto::sha3::Sha3;
+
+        let mut r = [0u8; 32];
+        let mut sha = Sha3::sha3_256();
+        sha.input_str( "Xolotl test KEM" );
+        for p in parts { sha.input(p); }
+        sha.result(&mut r);
+        sha.reset();
+        r
+    }
+
+    pub fn keypair<R: Rng>(&self, rng: &mut R) -> (KemPublic,KemSecret) {
+        let secret: [u8; 32] = rng.gen();
+        let public = TestKem::hash(&[&secret[..]]);
+        ( KemPublic(public.to_vec().into_boxed_slice()), KemSecret(secret.to_vec().into_boxed_slice()) )
+    }
+}
+
+impl Kem for TestKem {
+    fn encapsulate(&self, public: &KemPublic, seed: &[u8; 32])
+      -> KeysResult<(Box<[u8]>,KemSharedSecret)> {
+        let mut ciphertext = vec![0u8; ::std::cmp::max(self.0, 32)];
+        ciphertext[..32].copy_from_slice(seed);
+        let ss = TestKem::hash(&[&public.0[..], &ciphertext]);
+        Ok(( ciphertext.into_boxed_slice(), ss ))
+    }
+
+    fn decapsulate(&self, secret: &KemSecret, ciphertext: &[u8])
+      -> KeysResult<KemSharedSecret> {
+        let public = TestKem::hash(&[&secret.0[..]]);
+        Ok( TestKem::hash(&[&public[..], ciphertext]) )
+    }
+}
+
+
+/// `Concensus` holding exactly the routing keys of a `TestNet`.
+pub struct TestConcensus(HashMap<RoutingName,RoutingPublic>);
+
+impl Concensus for TestConcensus {
+    fn routing_named(&self, routing_name: &RoutingName) -> KeysResult<&RoutingPublic> {
+        self.0.get(routing_name)
+          .ok_or( KeysError::Routing(*routing_name,"No RoutingPublic for given RoutingName.") )
+    }
+
+    fn routing_by_issuer<R: Rng,K: Clock+?Sized>(&self, _rng: &mut R, _clock: &K,
+          issuer: &IssuerPublicKey, _before: SystemTime
+      ) -> KeysResult<(RoutingName,&RoutingPublic)> {
+        self.0.iter().find( |&(_,rp)| rp.issuer == *issuer )
+          .map( |(rn,rp)| (*rn,rp) )
+          .ok_or( KeysError::Issuer(*issuer,"Unknown issuer.") )
+    }
+
+    fn route_picker<'s,K: Clock+?Sized>(&'s self, _clock: &K, _before: SystemTime)
+      -> KeysResult<RoutePicker<'s,Self>> {
+        Err( KeysError::InternalError("Tests choose their routes explicitly.") )
+    }
+}
+
+
+/// Mix network of `Router`s sharing one `MockClock`, with one issuer
+/// and one routing key per node, and optionally `TestKem` keys for
+/// hybrid sub-hops.
+pub struct TestNet<P: Params> {
+    pub clock: Arc<MockClock>,
+    pub concensus: TestConcensus,
+    pub issuers: Vec<IssuerPublicKey>,
+    pub names: Vec<RoutingName>,
+    pub secrets: Vec<RoutingSecret>,
+    pub kem: Option<Arc<TestKem>>,
+    pub kem_secrets: Vec<KemSecret>,
+    pub routers: Vec<Router<P>>,
+}
+
+impl<P: Params> TestNet<P> {
+    pub fn new(n: usize) -> TestNet<P> {
+        TestNet::with_kem(n, None)
+    }
+
+    /// Create a network whose routing keys advertise `kem` keys,
+    /// which clients use for `Instruction::Hybrid` by default.
+    pub fn new_hybrid(n: usize, kem: TestKem) -> TestNet<P> {
+        TestNet::with_kem(n, Some(Arc::new(kem)))
+    }
+
+    fn with_kem(n: usize, kem: Option<Arc<TestKem>>) -> TestNet<P> {
+        let mut rng = OsRng::new().expect("failed to create an OS RNG");
+        let now = UNIX_EPOCH + Duration::from_secs(1000000);
+        let clock = Arc::new(MockClock::new(now));
+        let validity = ValidityPeriod::new(now - Duration::from_secs(3600), Duration::from_secs(2*24*3600));
+        let mut net = TestNet {
+            clock,
+            concensus: TestConcensus(HashMap::new()),
+            issuers: Vec::new(),
+            names: Vec::new(),
+            secrets: Vec::new(),
+            kem,
+            kem_secrets: Vec::new(),
+            routers: Vec::new(),
+        };
+        for _ in 0..n {
+            let issuer = IssuerSecret::new(&mut rng, validity.clone());
+            let kem_public = if let Some(ref kem) = net.kem {
+                let (p,s) = kem.keypair(&mut rng);
+                net.kem_secrets.push(s);
+                Some(p)
+            } else { None };
+            let (name,public,secret) = issuer.issue_hybrid(&mut rng, validity.clone(), kem_public);
+            net.issuers.push(issuer.public().0);
+            net.concensus.0.insert(name, public);
+            net.names.push(name);
+            net.secrets.push(secret);
+            let router = net.twin(net.secrets.len()-1);
+            net.routers.push(router);
+        }
+        net
+    }
+
+    /// Create a fresh `Router` holding the routing key of node `i`.
+    pub fn twin(&self, i: usize) -> Router<P> {
+        let ratchet = Arc::new(RatchetState::new(HasherState::new()));
+        let mut router = Router::new(self.clock.clone(), ratchet, true);
+        router.add_routing_secret(self.secrets[i].clone());
+        if let Some(ref kem) = self.kem {
+            router.add_kem_secret(&self.names[i], kem.clone(), self.kem_secrets[i].clone())
+              .expect("Failed to add KEM secret");
+        }
+        router
+    }
+
+    pub fn world<'a>(&'a self, ratchets: &'a ClientRatchetState) -> World<'a,TestConcensus,P> {
+        let world = World::new(&self.concensus, ratchets).with_clock(&*self.clock);
+        match self.kem {
+            Some(ref kem) => world.with_kem(&**kem),
+            None => world,
+        }
+    }
+
+    /// Build a header, or a SURB if `surb`, whose first hop is node
+    /// `first`, by following `instructions`.
+    pub fn build(&self, world: &World<TestConcensus,P>, surb: bool,
+                 first: usize, instructions: Vec<Instruction>)
+      -> SphinxResult<NewHeader<P>> {
+        let b = world.build_headers(OsRng::new().expect("failed to create an OS RNG"));
+        let b = if surb { b.make_surb() } else { b.long() };
+        let mut s = b.go(self.names[first]) ?;
+        {
+            let mut h = s.add();
+            for i in instructions { h.instruct(i) ?; }
+            h.approve();
+        }
+        s.done()
+    }
+
+    /// Seal `payload` for the header `h` and process the packet at
+    /// `h`'s first hop, node `first`.
+    pub fn send(&self, first: usize, h: &NewHeader<P>, payload: &[u8]) -> SphinxResult<()> {
+        let mut rng = OsRng::new().expect("failed to create an OS RNG");
+        let body = h.seal_body(1, payload) ?;  // BadLength
+        let header = h.encode_header(&mut rng) ?;  // InternalError
+        self.routers[first].process(header, body)
+    }
+
+    /// Process at node `to` every packet node `from` queued for it,
+    /// returning how many we processed.
+    pub fn relay(&self, from: usize, to: usize) -> SphinxResult<usize> {
+        let packets = self.routers[from].take_outgoing(&self.names[to]);
+        let n = packets.len();
+        for (_,p) in packets { self.routers[to].process(p.header, p.body) ?; }
+        Ok(n)
+    }
+}
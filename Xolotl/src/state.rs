@@ -82,6 +82,13 @@ pub trait Storage {
 
     fn remove<Q: ?Sized>(&mut self, value: &Q) -> Option<Self::Value>
         where Self::Key: Borrow<Q>, Q: Hash + Eq;
+
+    fn len(&self) -> usize;
+
+    /// Retain only the elements specified by the predicate,
+    /// used for garbage collection.
+    fn retain<F>(&mut self, f: F)
+        where F: FnMut(&Self::Key, &mut Self::Value) -> bool;
 }
 
 /// Rudementary `HashMap` based `Storage`
@@ -122,6 +129,15 @@ impl<K,V> Storage for HashMapStorage<K,V>
     fn remove<Q: ?Sized>(&mut self, value: &Q) -> Option<V>
         where K: Borrow<Q>, Q: Hash + Eq 
         {  self.0.remove(value) }
+
+    #[inline]
+    fn len(&self) -> usize
+        {  self.0.len()  }
+
+    #[inline]
+    fn retain<F>(&mut self, f: F)
+        where F: FnMut(&K, &mut V) -> bool
+        {  self.0.retain(f)  }
 }
 
 